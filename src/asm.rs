@@ -0,0 +1,961 @@
+//! Textual purple garden bytecode, for inspecting compiler output and writing vm tests without
+//! going through the frontend.
+//!
+//! ```text
+//! .globals
+//!     false ; g0
+//!     true ; g1
+//!     "hola" ; g2
+//! .functions
+//!     square 1
+//! .code
+//!     loadi r1, 7
+//!     call square, r1, 1
+//!     jmpf r1, L0
+//!     let x, r1
+//! L0:
+//!     ret 1
+//! square:
+//!     .line 4:2-8
+//!     mul r0, r0, r0
+//!     ret 1
+//! ```
+//!
+//! Globals are numbered in order of appearance and referred to as `g<idx>`, functions are
+//! numbered in order of appearance and their entry is the label sharing their name. Variables are
+//! written by name and hashed with [crate::cc::hash], hashes without a known name are written as
+//! `#0x<hash>`. `.line <line>:<start>-<end>` attributes the following instructions to that source
+//! span, up to the next `.line`, see [LineTable]. `;` starts a comment.
+//!
+//! String globals written with escapes are allocated on the heap of the program, strings without
+//! them borrow the source: quoted if they contain no `"`, `\` or line breaks, as raw strings
+//! `r#"..."#` otherwise, which may span multiple lines. Function names of the form `f<idx>` are
+//! taken by calls to functions without a declaration.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::{
+    bc::LineTable,
+    cc,
+    err::{PgError, Span},
+    gc::Heap,
+    op::{New, Op},
+    vm::{BigInt, BuiltinFn, Function, Value, Vm},
+};
+
+/// Builder form of a program, jump targets and function entries are op indexes
+#[derive(Debug, Default)]
+pub struct Program<'p> {
+    pub ops: Vec<Op<'p>>,
+    pub globals: Vec<Value<'p>>,
+    pub functions: Vec<Function<'p>>,
+    /// identifier names by their hash
+    pub symbols: HashMap<u64, &'p str>,
+    /// strings of globals that needed unescaping
+    pub heap: Heap<'p>,
+    /// source spans by op index
+    pub lines: LineTable,
+}
+
+impl<'p> Program<'p> {
+    /// Packs the program into a vm, which takes over its heap and symbols
+    pub fn into_vm(self) -> Vm<'p> {
+        let mut vm = Vm::new(&self.ops, self.globals, self.functions, self.lines);
+        vm.symbols = self.symbols;
+        vm.heap = self.heap;
+        vm
+    }
+}
+
+/// Renders `program` in the textual format, `builtins` names the function pointers of Op::Sys
+pub fn disassemble<'p>(program: &Program<'p>, builtins: &[(&str, BuiltinFn<'p>)]) -> String {
+    let mut labels: HashMap<usize, String> = HashMap::new();
+    for f in &program.functions {
+        labels.insert(f.pc, f.name.to_string());
+    }
+    let targets: BTreeSet<usize> = program
+        .ops
+        .iter()
+        .filter_map(|op| match op {
+            Op::Jmp { target } | Op::JmpF { target, .. } => Some(*target),
+            _ => None,
+        })
+        .collect();
+    let mut next = 0;
+    for target in targets {
+        labels.entry(target).or_insert_with(|| {
+            next += 1;
+            format!("L{}", next - 1)
+        });
+    }
+
+    let name = |hash: &u64| match program.symbols.get(hash) {
+        Some(name) => name.to_string(),
+        None => format!("#{hash:#x}"),
+    };
+
+    let lines: HashMap<usize, Span> = program.lines.iter().collect();
+
+    let mut out = String::new();
+    out.push_str(".globals\n");
+    for (i, g) in program.globals.iter().enumerate() {
+        let _ = match g {
+            Value::True => writeln!(out, "    true ; g{i}"),
+            Value::False => writeln!(out, "    false ; g{i}"),
+            Value::None => writeln!(out, "    none ; g{i}"),
+            Value::Int(int) => writeln!(out, "    {int} ; g{i}"),
+            Value::BigInt(int) => writeln!(out, "    {} ; g{i}", program.heap.get(*int)),
+            Value::Double(double) => writeln!(out, "    {double:?} ; g{i}"),
+            Value::Str(str) => writeln!(out, "    {} ; g{i}", quote(str)),
+            Value::String(str) => writeln!(out, "    {:?} ; g{i}", program.heap.get(*str)),
+            other => writeln!(out, "    ; g{i} {other:?} can not be represented"),
+        };
+    }
+
+    out.push_str(".functions\n");
+    for f in &program.functions {
+        let _ = writeln!(out, "    {} {}", f.name, f.args);
+    }
+
+    out.push_str(".code\n");
+    for (i, op) in program.ops.iter().enumerate() {
+        if let Some(label) = labels.get(&i) {
+            let _ = writeln!(out, "{label}:");
+        }
+        if let Some(span) = lines.get(&i) {
+            let _ = writeln!(out, "    .line {}:{}-{}", span.line, span.start, span.end);
+        }
+        let _ = match *op {
+            Op::Add { dst, lhs, rhs } => writeln!(out, "    add r{dst}, r{lhs}, r{rhs}"),
+            Op::Sub { dst, lhs, rhs } => writeln!(out, "    sub r{dst}, r{lhs}, r{rhs}"),
+            Op::Mul { dst, lhs, rhs } => writeln!(out, "    mul r{dst}, r{lhs}, r{rhs}"),
+            Op::Div { dst, lhs, rhs } => writeln!(out, "    div r{dst}, r{lhs}, r{rhs}"),
+            Op::Eq { dst, lhs, rhs } => writeln!(out, "    eq r{dst}, r{lhs}, r{rhs}"),
+            Op::Lt { dst, lhs, rhs } => writeln!(out, "    lt r{dst}, r{lhs}, r{rhs}"),
+            Op::Gt { dst, lhs, rhs } => writeln!(out, "    gt r{dst}, r{lhs}, r{rhs}"),
+            Op::Le { dst, lhs, rhs } => writeln!(out, "    le r{dst}, r{lhs}, r{rhs}"),
+            Op::Ge { dst, lhs, rhs } => writeln!(out, "    ge r{dst}, r{lhs}, r{rhs}"),
+            Op::Ne { dst, lhs, rhs } => writeln!(out, "    ne r{dst}, r{lhs}, r{rhs}"),
+            Op::Mod { dst, lhs, rhs } => writeln!(out, "    mod r{dst}, r{lhs}, r{rhs}"),
+            Op::Mov { dst, src } => writeln!(out, "    mov r{dst}, r{src}"),
+            Op::BitAnd { dst, lhs, rhs } => writeln!(out, "    band r{dst}, r{lhs}, r{rhs}"),
+            Op::BitOr { dst, lhs, rhs } => writeln!(out, "    bor r{dst}, r{lhs}, r{rhs}"),
+            Op::BitXor { dst, lhs, rhs } => writeln!(out, "    bxor r{dst}, r{lhs}, r{rhs}"),
+            Op::Shl { dst, lhs, rhs } => writeln!(out, "    shl r{dst}, r{lhs}, r{rhs}"),
+            Op::Shr { dst, lhs, rhs } => writeln!(out, "    shr r{dst}, r{lhs}, r{rhs}"),
+            Op::Neg { dst, src } => writeln!(out, "    neg r{dst}, r{src}"),
+            Op::BitNot { dst, src } => writeln!(out, "    bnot r{dst}, r{src}"),
+            Op::Not { dst, src } => writeln!(out, "    not r{dst}, r{src}"),
+            Op::LoadI { dst, value } => writeln!(out, "    loadi r{dst}, {value}"),
+            Op::LoadG { dst, idx } => writeln!(out, "    loadg r{dst}, g{idx}"),
+            Op::Size { dst, value } => writeln!(out, "    size r{dst}, {value}"),
+            Op::Let { hash, src } => writeln!(out, "    let {}, r{src}", name(&hash)),
+            Op::LoadV { hash, dst } => writeln!(out, "    loadv r{dst}, {}", name(&hash)),
+            Op::New {
+                dst,
+                size,
+                new_type,
+            } => writeln!(
+                out,
+                "    new r{dst}, {size}, {}",
+                match new_type {
+                    New::Object => "object",
+                    New::Array => "array",
+                }
+            ),
+            Op::Append { container, src } => writeln!(out, "    append r{container}, r{src}"),
+            Op::Len { dst, src } => writeln!(out, "    len r{dst}, r{src}"),
+            Op::Idx {
+                dst,
+                container,
+                index,
+            } => writeln!(out, "    idx r{dst}, r{container}, r{index}"),
+            Op::Set {
+                container,
+                key,
+                src,
+            } => writeln!(out, "    set r{container}, r{key}, r{src}"),
+            Op::Concat { start, len } => writeln!(out, "    concat r{start}, {len}"),
+            Op::Jmp { target } => writeln!(out, "    jmp {}", labels[&target]),
+            Op::JmpF { cond, target } => writeln!(out, "    jmpf r{cond}, {}", labels[&target]),
+            Op::Call {
+                func,
+                args_start,
+                args_len,
+            } => match program.functions.get(func as usize) {
+                Some(f) => writeln!(out, "    call {}, r{args_start}, {args_len}", f.name),
+                None => writeln!(out, "    call f{func}, r{args_start}, {args_len}"),
+            },
+            Op::Ret { times } => writeln!(out, "    ret {times}"),
+            Op::Sys {
+                ptr,
+                args_start,
+                args_len,
+            } => {
+                #[allow(unpredictable_function_pointer_comparisons)]
+                let builtin = builtins.iter().find(|(_, known)| *known == ptr);
+                match builtin {
+                    Some((name, _)) => writeln!(out, "    sys {name}, r{args_start}, {args_len}"),
+                    None => writeln!(out, "    ; sys {ptr:p}, r{args_start}, {args_len}"),
+                }
+            }
+        };
+    }
+    if let Some(label) = labels.get(&program.ops.len()) {
+        let _ = writeln!(out, "{label}:");
+    }
+
+    out
+}
+
+/// `str` in a form [assemble] reads back into a Value::Str borrowing it, raw if it can't be quoted
+/// without escapes, with as many `#` as it takes for the terminator not to occur in `str`
+fn quote(str: &str) -> String {
+    if !str.contains(['"', '\\', '\n', '\r']) {
+        return format!("\"{str}\"");
+    }
+    let hashes = (0..)
+        .map(|n| "#".repeat(n))
+        .find(|hashes| !str.contains(&format!("\"{hashes}")))
+        .expect("a long enough terminator doesn't occur in any string");
+    format!("r{hashes}\"{str}\"{hashes}")
+}
+
+/// The length of the opening delimiter and the terminator of the raw string `s` starts with
+fn raw_delimiters(s: &str) -> Option<(usize, String)> {
+    let hashes = s
+        .strip_prefix('r')?
+        .bytes()
+        .take_while(|&b| b == b'#')
+        .count();
+    if s.as_bytes().get(1 + hashes) != Some(&b'"') {
+        return None;
+    }
+    Some((2 + hashes, format!("\"{}", "#".repeat(hashes))))
+}
+
+/// `f<idx>` refers to the function at `idx`, whether it was declared or not
+fn function_index(name: &str) -> Option<u16> {
+    name.strip_prefix('f').and_then(|f| f.parse().ok())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    None,
+    Globals,
+    Functions,
+    Code,
+}
+
+/// A single whitespace or comma separated word of a line, with its position
+#[derive(Debug, Clone, Copy)]
+struct Word<'w> {
+    line: usize,
+    col: usize,
+    text: &'w str,
+}
+
+struct Assembler<'a> {
+    program: Program<'a>,
+    labels: HashMap<&'a str, usize>,
+    /// (op index, label) for every jump, resolved once all labels are known
+    fixups: Vec<(usize, Word<'a>)>,
+    /// (op index, function name) for every call
+    calls: Vec<(usize, Word<'a>)>,
+    /// the declaration of each function, for errors about its missing label
+    declarations: Vec<Word<'a>>,
+    builtins: &'a [(&'a str, BuiltinFn<'a>)],
+}
+
+impl<'a> Assembler<'a> {
+    fn err(&self, msg: impl Into<String>, word: Word) -> PgError {
+        PgError::new(msg, word.line, word.col, word.col + word.text.len())
+    }
+
+    fn reg(&self, w: Word) -> Result<u8, PgError> {
+        w.text
+            .strip_prefix('r')
+            .and_then(|r| r.parse().ok())
+            .ok_or_else(|| self.err(format!("expected a register, got `{}`", w.text), w))
+    }
+
+    fn num<T: std::str::FromStr>(&self, w: Word) -> Result<T, PgError> {
+        w.text
+            .parse()
+            .map_err(|_| self.err(format!("expected a number, got `{}`", w.text), w))
+    }
+
+    fn global(&self, w: Word) -> Result<u32, PgError> {
+        w.text
+            .strip_prefix('g')
+            .and_then(|g| g.parse().ok())
+            .ok_or_else(|| self.err(format!("expected a global, got `{}`", w.text), w))
+    }
+
+    /// `<line>:<start>-<end>`
+    fn span(&self, w: Word) -> Result<Span, PgError> {
+        w.text
+            .split_once(':')
+            .and_then(|(line, range)| {
+                let (start, end) = range.split_once('-')?;
+                Some(Span {
+                    line: line.parse().ok()?,
+                    start: start.parse().ok()?,
+                    end: end.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| {
+                self.err(
+                    format!("expected `<line>:<start>-<end>`, got `{}`", w.text),
+                    w,
+                )
+            })
+    }
+
+    fn hash(&mut self, w: Word<'a>) -> Result<u64, PgError> {
+        if let Some(hex) = w.text.strip_prefix("#0x") {
+            return u64::from_str_radix(hex, 16)
+                .map_err(|_| self.err(format!("invalid hash `{}`", w.text), w));
+        }
+        let hash = cc::hash(w.text);
+        self.program.symbols.insert(hash, w.text);
+        Ok(hash)
+    }
+
+    fn global_value(&mut self, w: Word<'a>) -> Result<Value<'a>, PgError> {
+        Ok(match w.text {
+            "true" => Value::True,
+            "false" => Value::False,
+            "none" => Value::None,
+            quoted if quoted.starts_with('"') => {
+                let inner = quoted
+                    .strip_prefix('"')
+                    .and_then(|q| q.strip_suffix('"'))
+                    .filter(|_| quoted.len() >= 2)
+                    .ok_or_else(|| self.err("unterminated string", w))?;
+                if inner.contains('\\') {
+                    let unescaped = unescape(inner).ok_or_else(|| self.err("invalid escape", w))?;
+                    Value::String(self.program.heap.alloc(unescaped))
+                } else {
+                    Value::Str(inner)
+                }
+            }
+            raw if let Some((open, close)) = raw_delimiters(raw) => Value::Str(
+                raw[open..]
+                    .strip_suffix(close.as_str())
+                    .ok_or_else(|| self.err("unterminated string", w))?,
+            ),
+            number => match number.parse::<i64>() {
+                Ok(int) => Value::Int(int),
+                Err(_) => match BigInt::parse(number) {
+                    Some(int) => Value::BigInt(self.program.heap.alloc(int)),
+                    None => Value::Double(
+                        number
+                            .parse()
+                            .map_err(|_| self.err(format!("invalid global `{number}`"), w))?,
+                    ),
+                },
+            },
+        })
+    }
+
+    fn instruction(&mut self, words: &[Word<'a>]) -> Result<Op<'a>, PgError> {
+        let mnemonic = words[0];
+        let operands = &words[1..];
+        let arity = match mnemonic.text {
+            "ret" | "jmp" => 1,
+            "mov" | "neg" | "not" | "bnot" | "loadi" | "loadg" | "size" | "let" | "loadv"
+            | "append" | "len" | "jmpf" | "concat" => 2,
+            "add" | "sub" | "mul" | "div" | "mod" | "eq" | "ne" | "lt" | "gt" | "le" | "ge"
+            | "band" | "bor" | "bxor" | "shl" | "shr" | "new" | "idx" | "set" | "call" | "sys" => 3,
+            unknown => return Err(self.err(format!("unknown instruction `{unknown}`"), mnemonic)),
+        };
+        if operands.len() != arity {
+            return Err(self.err(
+                format!(
+                    "`{}` takes {arity} operands, got {}",
+                    mnemonic.text,
+                    operands.len()
+                ),
+                mnemonic,
+            ));
+        }
+
+        let o = operands;
+        Ok(match mnemonic.text {
+            "add" | "sub" | "mul" | "div" | "mod" | "eq" | "ne" | "lt" | "gt" | "le" | "ge"
+            | "band" | "bor" | "bxor" | "shl" | "shr" => {
+                let (dst, lhs, rhs) = (self.reg(o[0])?, self.reg(o[1])?, self.reg(o[2])?);
+                match mnemonic.text {
+                    "add" => Op::Add { dst, lhs, rhs },
+                    "sub" => Op::Sub { dst, lhs, rhs },
+                    "mul" => Op::Mul { dst, lhs, rhs },
+                    "div" => Op::Div { dst, lhs, rhs },
+                    "mod" => Op::Mod { dst, lhs, rhs },
+                    "eq" => Op::Eq { dst, lhs, rhs },
+                    "ne" => Op::Ne { dst, lhs, rhs },
+                    "lt" => Op::Lt { dst, lhs, rhs },
+                    "gt" => Op::Gt { dst, lhs, rhs },
+                    "le" => Op::Le { dst, lhs, rhs },
+                    "ge" => Op::Ge { dst, lhs, rhs },
+                    "band" => Op::BitAnd { dst, lhs, rhs },
+                    "bor" => Op::BitOr { dst, lhs, rhs },
+                    "bxor" => Op::BitXor { dst, lhs, rhs },
+                    "shl" => Op::Shl { dst, lhs, rhs },
+                    _ => Op::Shr { dst, lhs, rhs },
+                }
+            }
+            "neg" | "not" | "bnot" => {
+                let (dst, src) = (self.reg(o[0])?, self.reg(o[1])?);
+                match mnemonic.text {
+                    "neg" => Op::Neg { dst, src },
+                    "not" => Op::Not { dst, src },
+                    _ => Op::BitNot { dst, src },
+                }
+            }
+            "mov" => Op::Mov {
+                dst: self.reg(o[0])?,
+                src: self.reg(o[1])?,
+            },
+            "loadi" => Op::LoadI {
+                dst: self.reg(o[0])?,
+                value: self.num(o[1])?,
+            },
+            "loadg" => Op::LoadG {
+                dst: self.reg(o[0])?,
+                idx: self.global(o[1])?,
+            },
+            "size" => Op::Size {
+                dst: self.reg(o[0])?,
+                value: self.num(o[1])?,
+            },
+            "let" => Op::Let {
+                hash: self.hash(o[0])?,
+                src: self.reg(o[1])?,
+            },
+            "loadv" => Op::LoadV {
+                dst: self.reg(o[0])?,
+                hash: self.hash(o[1])?,
+            },
+            "new" => Op::New {
+                dst: self.reg(o[0])?,
+                size: self.num(o[1])?,
+                new_type: match o[2].text {
+                    "object" => New::Object,
+                    "array" => New::Array,
+                    _ => return Err(self.err("expected `object` or `array`", o[2])),
+                },
+            },
+            "append" => Op::Append {
+                container: self.reg(o[0])?,
+                src: self.reg(o[1])?,
+            },
+            "len" => Op::Len {
+                dst: self.reg(o[0])?,
+                src: self.reg(o[1])?,
+            },
+            "idx" => Op::Idx {
+                dst: self.reg(o[0])?,
+                container: self.reg(o[1])?,
+                index: self.reg(o[2])?,
+            },
+            "set" => Op::Set {
+                container: self.reg(o[0])?,
+                key: self.reg(o[1])?,
+                src: self.reg(o[2])?,
+            },
+            "concat" => Op::Concat {
+                start: self.reg(o[0])?,
+                len: self.num(o[1])?,
+            },
+            "jmp" => {
+                self.fixups.push((self.program.ops.len(), o[0]));
+                Op::Jmp { target: 0 }
+            }
+            "jmpf" => {
+                self.fixups.push((self.program.ops.len(), o[1]));
+                Op::JmpF {
+                    cond: self.reg(o[0])?,
+                    target: 0,
+                }
+            }
+            "call" => {
+                self.calls.push((self.program.ops.len(), o[0]));
+                Op::Call {
+                    func: 0,
+                    args_start: self.reg(o[1])?,
+                    args_len: self.num(o[2])?,
+                }
+            }
+            "ret" => Op::Ret {
+                times: self.num(o[0])?,
+            },
+            _ => Op::Sys {
+                ptr: self
+                    .builtins
+                    .iter()
+                    .find(|(name, _)| *name == o[0].text)
+                    .map(|(_, ptr)| *ptr)
+                    .ok_or_else(|| self.err(format!("unknown builtin `{}`", o[0].text), o[0]))?,
+                args_start: self.reg(o[1])?,
+                args_len: self.num(o[2])?,
+            },
+        })
+    }
+
+    fn resolve(&mut self) -> Result<(), PgError> {
+        for (f, declaration) in self.program.functions.iter_mut().zip(&self.declarations) {
+            let Some(&pc) = self.labels.get(f.name) else {
+                return Err(PgError::new(
+                    format!("function `{}` has no label", f.name),
+                    declaration.line,
+                    declaration.col,
+                    declaration.col + f.name.len(),
+                ));
+            };
+            f.pc = pc;
+        }
+
+        for (idx, label) in std::mem::take(&mut self.fixups) {
+            let Some(&resolved) = self.labels.get(label.text) else {
+                return Err(self.err(format!("undefined label `{}`", label.text), label));
+            };
+            match &mut self.program.ops[idx] {
+                Op::Jmp { target } | Op::JmpF { target, .. } => *target = resolved,
+                _ => unreachable!("Assembler::resolve: fixup for a non jump"),
+            }
+        }
+
+        for (idx, name) in std::mem::take(&mut self.calls) {
+            let func = match function_index(name.text) {
+                Some(func) => func,
+                None => self
+                    .program
+                    .functions
+                    .iter()
+                    .position(|f| f.name == name.text)
+                    .ok_or_else(|| self.err(format!("undefined function `{}`", name.text), name))?
+                    as u16,
+            };
+            if let Op::Call { func: f, .. } = &mut self.program.ops[idx] {
+                *f = func;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Resolves the escapes `{:?}` produces for strings
+fn unescape(s: &str) -> Option<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            '\'' => '\'',
+            'u' => {
+                let rest = chars.as_str().strip_prefix('{')?;
+                let end = rest.find('}')?;
+                let c = char::from_u32(u32::from_str_radix(&rest[..end], 16).ok()?)?;
+                chars = rest[end + 1..].chars();
+                c
+            }
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+/// Splits `line` into words at whitespace and commas, stops at `;` outside of strings
+fn words(line_number: usize, line: &str) -> Vec<Word<'_>> {
+    let mut words = vec![];
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b' ' | b'\t' | b',' | b'\r' => i += 1,
+            b';' => break,
+            b'"' => {
+                let start = i;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i = (i + 1).min(bytes.len());
+                words.push(Word {
+                    line: line_number,
+                    col: start,
+                    text: &line[start..i],
+                });
+            }
+            _ => {
+                let start = i;
+                if let Some((open, close)) = raw_delimiters(&line[i..]) {
+                    // a raw string without its terminator continues on the next lines, see
+                    // assemble
+                    i = line[i + open..]
+                        .find(&close)
+                        .map_or(line.len(), |end| i + open + end + close.len());
+                } else {
+                    while i < bytes.len() && !matches!(bytes[i], b' ' | b'\t' | b',' | b';' | b'\r')
+                    {
+                        i += 1;
+                    }
+                }
+                words.push(Word {
+                    line: line_number,
+                    col: start,
+                    text: &line[start..i],
+                });
+            }
+        }
+    }
+    words
+}
+
+/// Parses the textual format back into its builder form, `builtins` resolves the names of Op::Sys
+/// function pointers
+pub fn assemble<'a>(
+    src: &'a str,
+    builtins: &'a [(&'a str, BuiltinFn<'a>)],
+) -> Result<Program<'a>, PgError> {
+    let mut asm = Assembler {
+        program: Program::default(),
+        labels: HashMap::new(),
+        fixups: vec![],
+        calls: vec![],
+        declarations: vec![],
+        builtins,
+    };
+
+    let mut section = Section::None;
+    // byte offset of the next line in src
+    let mut offset = 0;
+    let mut lines = src.split_inclusive('\n').enumerate();
+    while let Some((line_idx, line)) = lines.next() {
+        let line_start = offset;
+        offset += line.len();
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let words = words(line_idx + 1, line);
+        let Some(&first) = words.first() else {
+            continue;
+        };
+
+        match first.text {
+            ".globals" => section = Section::Globals,
+            ".functions" => section = Section::Functions,
+            ".code" => section = Section::Code,
+            _ => match section {
+                Section::None => return Err(asm.err("expected a section", first)),
+                Section::Globals => {
+                    let mut global = first;
+                    if let Some((open, close)) = raw_delimiters(first.text)
+                        && !first.text[open..].ends_with(&close)
+                        && let start = line_start + first.col
+                        && let Some(end) = src[start + open..].find(&close)
+                    {
+                        // the raw string spans lines, the global ends with its terminator
+                        let end = start + open + end + close.len();
+                        global.text = &src[start..end];
+                        while offset < end
+                            && let Some((_, line)) = lines.next()
+                        {
+                            offset += line.len();
+                        }
+                    }
+                    let value = asm.global_value(global)?;
+                    asm.program.globals.push(value);
+                }
+                Section::Functions => {
+                    let [name, args] = words[..] else {
+                        return Err(asm.err("expected `<name> <args>`", first));
+                    };
+                    if function_index(name.text).is_some() {
+                        return Err(asm.err(
+                            format!(
+                                "function name `{}` collides with calls by index, `f<idx>`",
+                                name.text
+                            ),
+                            name,
+                        ));
+                    }
+                    let args = asm.num(args)?;
+                    asm.declarations.push(name);
+                    asm.program.functions.push(Function {
+                        name: name.text,
+                        pc: 0,
+                        args,
+                    });
+                }
+                Section::Code => {
+                    if let Some(label) = first.text.strip_suffix(':') {
+                        if asm.labels.insert(label, asm.program.ops.len()).is_some() {
+                            return Err(asm.err(format!("duplicate label `{label}`"), first));
+                        }
+                        continue;
+                    }
+                    if first.text == ".line" {
+                        let [_, span] = words[..] else {
+                            return Err(asm.err("expected `.line <line>:<start>-<end>`", first));
+                        };
+                        let span = asm.span(span)?;
+                        asm.program.lines.push(asm.program.ops.len(), span);
+                        continue;
+                    }
+                    let op = asm.instruction(&words)?;
+                    asm.program.ops.push(op);
+                }
+            },
+        }
+    }
+
+    asm.resolve()?;
+    Ok(asm.program)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asm::{Program, assemble, disassemble},
+        err::Span,
+        op::{New, Op},
+        vm::{BuiltinFn, ErrorKind, Function, Value, Vm},
+    };
+
+    fn print<'vm>(_: &mut Vm<'vm>, _: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+        Ok(Value::False)
+    }
+
+    const SRC: &str = r#".globals
+    false ; g0
+    true ; g1
+    "hola" ; g2
+    "with \"escapes\"\n" ; g3
+    2.5 ; g4
+    -100000000000000000000 ; g5
+.functions
+    square 1
+.code
+    .line 1:0-9
+    loadi r1, 7
+    loadg r2, g3
+    .line 2:0-6
+    call square, r1, 1
+    jmpf r0, L0
+    let x, r0
+    loadv r3, x
+    new r4, 2, array
+    sys print, r1, 2
+L0:
+    ret 1
+square:
+    .line 4:2-8
+    mul r0, r0, r0
+    jmp square
+"#;
+
+    #[test]
+    fn roundtrip() {
+        let builtins: &[(&str, BuiltinFn)] = &[("print", print)];
+        let program = assemble(SRC, builtins).expect("Failed to assemble");
+        assert_eq!(program.ops.len(), 11);
+        assert_eq!(
+            program.functions,
+            vec![Function {
+                name: "square",
+                pc: 9,
+                args: 1
+            }]
+        );
+        assert_eq!(program.ops[3], Op::JmpF { cond: 0, target: 8 });
+        assert_eq!(program.ops[10], Op::Jmp { target: 9 });
+        assert_eq!(
+            program.ops[6],
+            Op::New {
+                dst: 4,
+                size: 2,
+                new_type: New::Array
+            }
+        );
+        let Value::String(escaped) = program.globals[3] else {
+            panic!("escaped strings should be allocated");
+        };
+        assert_eq!(program.heap.get(escaped), "with \"escapes\"\n");
+        let Value::BigInt(big) = program.globals[5] else {
+            panic!("ints outside of i64 range should be big ints");
+        };
+        assert_eq!(program.heap.get(big).to_string(), "-100000000000000000000");
+        assert_eq!(
+            program.lines.iter().map(|(pc, _)| pc).collect::<Vec<_>>(),
+            vec![0, 2, 9]
+        );
+
+        let text = disassemble(&program, builtins);
+        assert_eq!(text, SRC);
+        let again = assemble(&text, builtins).expect("Failed to reassemble");
+        assert_eq!(again.ops, program.ops);
+        assert_eq!(again.globals, program.globals);
+        assert_eq!(again.lines, program.lines);
+    }
+
+    #[test]
+    fn str_globals_roundtrip() {
+        let program = Program {
+            globals: vec![
+                Value::Str(r"C:\path"),
+                Value::Str(r#"say "hi""#),
+                Value::Str(r##"a "# in it"##),
+                Value::Str("two\nlines; and a \"quote\""),
+                Value::Str("plain; text"),
+                Value::Int(1),
+            ],
+            ..Default::default()
+        };
+        let text = disassemble(&program, &[]);
+        let again = assemble(&text, &[]).expect("Failed to reassemble");
+        assert_eq!(again.globals, program.globals);
+        assert!(
+            again.globals.iter().all(|g| !matches!(g, Value::String(_))),
+            "{text}"
+        );
+        assert_eq!(disassemble(&again, &[]), text);
+    }
+
+    #[test]
+    fn function_names_of_call_indexes() {
+        let program = Program {
+            ops: vec![
+                Op::Call {
+                    func: 1,
+                    args_start: 0,
+                    args_len: 0,
+                },
+                Op::Ret { times: 1 },
+            ],
+            functions: vec![
+                Function {
+                    name: "f1",
+                    pc: 1,
+                    args: 0,
+                },
+                Function {
+                    name: "g",
+                    pc: 1,
+                    args: 0,
+                },
+            ],
+            ..Default::default()
+        };
+        // `call f1` would resolve to the function at index 1, not to the one named f1
+        let text = disassemble(&program, &[]);
+        assert!(assemble(&text, &[]).is_err(), "{text}");
+
+        let program = Program {
+            functions: vec![Function {
+                name: "f1x",
+                pc: 1,
+                args: 0,
+            }],
+            ..program
+        };
+        let text = disassemble(&program, &[]);
+        let again = assemble(&text, &[]).expect("Failed to reassemble");
+        assert_eq!(again.ops, program.ops);
+        assert_eq!(again.functions, program.functions);
+        assert_eq!(disassemble(&again, &[]), text);
+    }
+
+    #[test]
+    fn lines_reach_the_vm() {
+        let program = assemble(
+            ".code
+    .line 1:0-5
+    loadi r0, 1
+    loadi r1, 0
+    .line 3:4-9
+    div r0, r0, r1
+",
+            &[],
+        )
+        .expect("Failed to assemble");
+        let vm = program.into_vm();
+        assert_eq!(
+            vm.span(2),
+            Some(Span {
+                line: 3,
+                start: 4,
+                end: 9
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_hash() {
+        let src = ".code\n    let #0x123, r0\n";
+        let program = assemble(src, &[]).expect("Failed to assemble");
+        assert_eq!(
+            program.ops,
+            vec![Op::Let {
+                hash: 0x123,
+                src: 0
+            }]
+        );
+        assert_eq!(
+            disassemble(&program, &[]),
+            ".globals\n.functions\n".to_string() + src
+        );
+    }
+
+    #[test]
+    fn runs() {
+        let program = assemble(
+            ".code
+    loadi r0, 4
+    loadi r1, 1
+    loadi r2, 0
+loop:
+    gt r3, r0, r2
+    jmpf r3, end
+    sub r0, r0, r1
+    jmp loop
+end:
+",
+            &[],
+        )
+        .expect("Failed to assemble");
+        let mut vm = program.into_vm();
+        vm.run().expect("Failed to run");
+        assert_eq!(vm.registers[0], Some(Value::Int(0)));
+    }
+
+    #[test]
+    fn errors() {
+        for src in [
+            "loadi r0, 1",
+            ".code\n    loadi x0, 1",
+            ".code\n    add r0, r1",
+            ".code\n    jmp nowhere",
+            ".code\n    call nothing, r0, 0",
+            ".code\n    sys nothing, r0, 0",
+            ".code\n    frobnicate",
+            ".globals\n    \"unterminated",
+            ".code\n    .line 1",
+            ".code\n    .line 1:2",
+            ".globals\n    r#\"unterminated\"",
+            ".functions\n    f0 0\n.code\nf0:\n    ret 1",
+        ] {
+            assert!(assemble(src, &[]).is_err(), "{src:?} should not assemble");
+        }
+    }
+}
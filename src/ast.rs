@@ -6,14 +6,30 @@ pub enum InnerNode<'inner> {
     Atom,
     Ident,
 
-    /// lhs +-*/ rhs
+    /// lhs +-*/% rhs, lhs & | ^ << >> rhs, lhs == != < > <= >= rhs, lhs && || rhs
     ///
-    /// kind is encoded in super::Node::token
+    /// kind is encoded in super::Node::token, && and || only evaluate rhs if lhs did not decide
+    /// the result
     Bin {
         lhs: Box<Node<'inner>>,
         rhs: Box<Node<'inner>>,
     },
 
+    /// -rhs, !rhs, ~rhs
+    ///
+    /// kind is encoded in super::Node::token
+    Unary {
+        rhs: Box<Node<'inner>>,
+    },
+
+    /// "hello {name}, you have {n + 1} items"
+    ///
+    /// the text before the first interpolation is encoded in super::Node::token, parts are the
+    /// embedded expressions and the non empty text between them as Type::String atoms
+    Interpolation {
+        parts: Vec<Node<'inner>>,
+    },
+
     /// [members]
     Array {
         members: Vec<Node<'inner>>,
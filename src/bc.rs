@@ -0,0 +1,831 @@
+//! Packed purple garden bytecode: every instruction is a single 32 bit word, some are followed by
+//! one or two extension words for operands that don't fit into the head word.
+//!
+//! Head word layout, least significant byte first:
+//!
+//! ```text
+//! | opcode:8 | a:8 | b:8 | c:8 |
+//! ```
+//!
+//! Depending on the opcode, `b` and `c` are read as a single 16 bit field (`bc`) and `a`, `b`, `c`
+//! as a single signed 24 bit field (`abc`). Jumps are encoded as word offsets relative to the
+//! jumping instruction, integer immediates, global indexes, sizes, jump offsets, function and
+//! builtin ids each have a narrow form fitting into the head word and a wide form carrying the
+//! operand in extension words. Variable hashes always occupy two extension words.
+//!
+//! [Op] is the builder and inspection form, [encode] packs it, [Bytecode::decode] unpacks it and
+//! [crate::vm::Vm::run] interprets the packed form directly.
+
+use crate::{
+    err::Span,
+    op::{New, Op},
+    vm::BuiltinFn,
+};
+
+/// opcodes of the packed encoding, stored in the lowest byte of each head word
+pub mod opcode {
+    pub const ADD: u8 = 0x00;
+    pub const SUB: u8 = 0x01;
+    pub const MUL: u8 = 0x02;
+    pub const DIV: u8 = 0x03;
+    pub const EQ: u8 = 0x04;
+    pub const LT: u8 = 0x05;
+    pub const GT: u8 = 0x06;
+    pub const MOV: u8 = 0x07;
+    /// a=dst bc=i16
+    pub const LOADI: u8 = 0x08;
+    /// a=dst, 2 extension words: low and high half of the i64
+    pub const LOADI_W: u8 = 0x09;
+    /// a=dst bc=u16
+    pub const LOADG: u8 = 0x0A;
+    /// a=dst, 1 extension word: u32 index
+    pub const LOADG_W: u8 = 0x0B;
+    /// a=dst bc=u16
+    pub const SIZE: u8 = 0x0C;
+    /// a=dst, 1 extension word: u32 size
+    pub const SIZE_W: u8 = 0x0D;
+    /// a=src, 2 extension words: low and high half of the hash
+    pub const LET: u8 = 0x0E;
+    /// a=dst, 2 extension words: low and high half of the hash
+    pub const LOADV: u8 = 0x0F;
+    /// a=dst b=size c=0 for objects, 1 for arrays
+    pub const NEW: u8 = 0x10;
+    pub const APPEND: u8 = 0x11;
+    pub const LEN: u8 = 0x12;
+    pub const IDX: u8 = 0x13;
+    /// abc=i24 relative offset
+    pub const JMP: u8 = 0x14;
+    /// 1 extension word: i32 relative offset
+    pub const JMP_W: u8 = 0x15;
+    /// a=cond bc=i16 relative offset
+    pub const JMPF: u8 = 0x16;
+    /// a=cond, 1 extension word: i32 relative offset
+    pub const JMPF_W: u8 = 0x17;
+    /// a=args_start b=args_len c=func
+    pub const CALL: u8 = 0x18;
+    /// a=args_start b=args_len, 1 extension word: func
+    pub const CALL_W: u8 = 0x19;
+    /// a=times
+    pub const RET: u8 = 0x1A;
+    /// a=args_start b=args_len c=index into Bytecode::builtins
+    pub const SYS: u8 = 0x1B;
+    /// a=args_start b=args_len, 1 extension word: index into Bytecode::builtins
+    pub const SYS_W: u8 = 0x1C;
+    /// a=container b=key c=src
+    pub const SET: u8 = 0x1D;
+    pub const LE: u8 = 0x1E;
+    pub const GE: u8 = 0x1F;
+    pub const NE: u8 = 0x20;
+    pub const MOD: u8 = 0x21;
+    /// a=dst b=src
+    pub const NEG: u8 = 0x22;
+    /// a=dst b=src
+    pub const NOT: u8 = 0x23;
+    pub const BITAND: u8 = 0x24;
+    pub const BITOR: u8 = 0x25;
+    pub const BITXOR: u8 = 0x26;
+    pub const SHL: u8 = 0x27;
+    pub const SHR: u8 = 0x28;
+    /// a=dst b=src
+    pub const BITNOT: u8 = 0x29;
+    /// a=start b=len
+    pub const CONCAT: u8 = 0x2A;
+}
+
+#[inline(always)]
+pub fn op(w: u32) -> u8 {
+    w as u8
+}
+
+#[inline(always)]
+pub fn a(w: u32) -> u8 {
+    (w >> 8) as u8
+}
+
+#[inline(always)]
+pub fn b(w: u32) -> u8 {
+    (w >> 16) as u8
+}
+
+#[inline(always)]
+pub fn c(w: u32) -> u8 {
+    (w >> 24) as u8
+}
+
+#[inline(always)]
+pub fn bc(w: u32) -> u16 {
+    (w >> 16) as u16
+}
+
+/// signed 24 bit field spanning a, b and c, sign extended by the arithmetic shift
+#[inline(always)]
+pub fn abc(w: u32) -> i32 {
+    (w as i32) >> 8
+}
+
+#[inline(always)]
+fn word(op: u8, a: u8, b: u8, c: u8) -> u32 {
+    op as u32 | (a as u32) << 8 | (b as u32) << 16 | (c as u32) << 24
+}
+
+#[inline(always)]
+fn word_bc(op: u8, a: u8, bc: u16) -> u32 {
+    op as u32 | (a as u32) << 8 | (bc as u32) << 16
+}
+
+const I24_MIN: i64 = -(1 << 23);
+const I24_MAX: i64 = (1 << 23) - 1;
+
+/// Packed bytecode, as produced by [encode] and consumed by [crate::vm::Vm::run]
+#[derive(Debug, Default, Clone)]
+pub struct Bytecode<'bc> {
+    pub code: Vec<u32>,
+    /// Op::Sys function pointers don't fit into an instruction word, SYS refers to them by index
+    pub builtins: Vec<BuiltinFn<'bc>>,
+    pub lines: LineTable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LineEntry {
+    pc: u32,
+    line: u32,
+    start: u32,
+    end: u32,
+}
+
+/// Maps pcs to the source span they were compiled from. Only changes are recorded: an entry
+/// covers every pc from its own up to the next entry, consecutive instructions compiled from the
+/// same token share a single entry.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LineTable {
+    entries: Vec<LineEntry>,
+}
+
+impl LineTable {
+    /// Records that the code starting at `pc` was compiled from `span`, pcs must not decrease
+    /// between calls
+    pub fn push(&mut self, pc: usize, span: Span) {
+        let entry = LineEntry {
+            pc: pc as u32,
+            line: span.line as u32,
+            start: span.start as u32,
+            end: span.end as u32,
+        };
+        match self.entries.last_mut() {
+            Some(last)
+                if (last.line, last.start, last.end) == (entry.line, entry.start, entry.end) => {}
+            // nothing was emitted for the previous span
+            Some(last) if last.pc == entry.pc => *last = entry,
+            _ => self.entries.push(entry),
+        }
+    }
+
+    /// Span of the instruction at `pc`
+    pub fn lookup(&self, pc: usize) -> Option<Span> {
+        let idx = self.entries.partition_point(|e| e.pc as usize <= pc);
+        let entry = self.entries.get(idx.checked_sub(1)?)?;
+        Some(Span {
+            line: entry.line as usize,
+            start: entry.start as usize,
+            end: entry.end as usize,
+        })
+    }
+
+    /// Every recorded change as the pc it starts at and its span
+    pub fn iter(&self) -> impl Iterator<Item = (usize, Span)> + '_ {
+        self.entries.iter().map(|entry| {
+            (
+                entry.pc as usize,
+                Span {
+                    line: entry.line as usize,
+                    start: entry.start as usize,
+                    end: entry.end as usize,
+                },
+            )
+        })
+    }
+
+    /// Rewrites op indexes into word offsets, see [encode]
+    pub fn translate(&mut self, offsets: &[usize]) {
+        for entry in &mut self.entries {
+            entry.pc = offsets[entry.pc as usize] as u32;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// number of words `op` takes up, `wide` is only considered for jumps and `builtin`, the index
+/// into Bytecode::builtins, only for Sys
+fn size(op: &Op, wide: bool, builtin: usize) -> usize {
+    match op {
+        Op::LoadI { value, .. } if i16::try_from(*value).is_err() => 3,
+        Op::LoadG { idx, .. } if *idx > u16::MAX as u32 => 2,
+        Op::Size { value, .. } if *value > u16::MAX as u32 => 2,
+        Op::Let { .. } | Op::LoadV { .. } => 3,
+        Op::Call { func, .. } if *func > u8::MAX as u16 => 2,
+        Op::Sys { .. } if builtin > u8::MAX as usize => 2,
+        Op::Jmp { .. } | Op::JmpF { .. } if wide => 2,
+        _ => 1,
+    }
+}
+
+/// Packs `ops` into their word encoding, jump targets in `ops` are op indexes and are rewritten to
+/// offsets relative to the jumping instruction.
+///
+/// Returns the bytecode and the word offset of each op, with one trailing entry for the end of
+/// the bytecode, so callers can translate their own op indexes (function entries, line tables).
+pub fn encode<'bc>(ops: &[Op<'bc>]) -> (Bytecode<'bc>, Vec<usize>) {
+    // the builtin index decides whether a Sys is wide, so the table has to exist before the
+    // offsets are computed
+    let mut builtins: Vec<BuiltinFn<'bc>> = Vec::new();
+    let builtin: Vec<usize> = ops
+        .iter()
+        .map(|op| match *op {
+            Op::Sys { ptr, .. } =>
+            {
+                #[allow(unpredictable_function_pointer_comparisons)]
+                match builtins.iter().position(|&known| known == ptr) {
+                    Some(idx) => idx,
+                    None => {
+                        builtins.push(ptr);
+                        builtins.len() - 1
+                    }
+                }
+            }
+            _ => 0,
+        })
+        .collect();
+
+    // jump relaxation: start with every jump narrow and widen the ones whose offset does not fit
+    // until nothing changes, widening only ever grows offsets, so this terminates
+    let mut wide = vec![false; ops.len()];
+    let offsets = loop {
+        let mut offsets = Vec::with_capacity(ops.len() + 1);
+        let mut pos = 0;
+        for (i, op) in ops.iter().enumerate() {
+            offsets.push(pos);
+            pos += size(op, wide[i], builtin[i]);
+        }
+        offsets.push(pos);
+
+        let mut changed = false;
+        for (i, op) in ops.iter().enumerate() {
+            let fits = match op {
+                Op::Jmp { target } => {
+                    (I24_MIN..=I24_MAX).contains(&(offsets[*target] as i64 - offsets[i] as i64))
+                }
+                Op::JmpF { target, .. } => {
+                    i16::try_from(offsets[*target] as i64 - offsets[i] as i64).is_ok()
+                }
+                _ => true,
+            };
+            if !fits && !wide[i] {
+                wide[i] = true;
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break offsets;
+        }
+    };
+
+    use opcode::*;
+    let mut bc = Bytecode {
+        code: Vec::with_capacity(*offsets.last().unwrap_or(&0)),
+        builtins,
+        ..Default::default()
+    };
+    let code = &mut bc.code;
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Add { dst, lhs, rhs } => code.push(word(ADD, dst, lhs, rhs)),
+            Op::Sub { dst, lhs, rhs } => code.push(word(SUB, dst, lhs, rhs)),
+            Op::Mul { dst, lhs, rhs } => code.push(word(MUL, dst, lhs, rhs)),
+            Op::Div { dst, lhs, rhs } => code.push(word(DIV, dst, lhs, rhs)),
+            Op::Eq { dst, lhs, rhs } => code.push(word(EQ, dst, lhs, rhs)),
+            Op::Lt { dst, lhs, rhs } => code.push(word(LT, dst, lhs, rhs)),
+            Op::Gt { dst, lhs, rhs } => code.push(word(GT, dst, lhs, rhs)),
+            Op::Le { dst, lhs, rhs } => code.push(word(LE, dst, lhs, rhs)),
+            Op::Ge { dst, lhs, rhs } => code.push(word(GE, dst, lhs, rhs)),
+            Op::Ne { dst, lhs, rhs } => code.push(word(NE, dst, lhs, rhs)),
+            Op::Mod { dst, lhs, rhs } => code.push(word(MOD, dst, lhs, rhs)),
+            Op::Mov { dst, src } => code.push(word(MOV, dst, src, 0)),
+            Op::BitAnd { dst, lhs, rhs } => code.push(word(BITAND, dst, lhs, rhs)),
+            Op::BitOr { dst, lhs, rhs } => code.push(word(BITOR, dst, lhs, rhs)),
+            Op::BitXor { dst, lhs, rhs } => code.push(word(BITXOR, dst, lhs, rhs)),
+            Op::Shl { dst, lhs, rhs } => code.push(word(SHL, dst, lhs, rhs)),
+            Op::Shr { dst, lhs, rhs } => code.push(word(SHR, dst, lhs, rhs)),
+            Op::Neg { dst, src } => code.push(word(NEG, dst, src, 0)),
+            Op::BitNot { dst, src } => code.push(word(BITNOT, dst, src, 0)),
+            Op::Not { dst, src } => code.push(word(NOT, dst, src, 0)),
+            Op::LoadI { dst, value } => match i16::try_from(value) {
+                Ok(small) => code.push(word_bc(LOADI, dst, small as u16)),
+                Err(_) => code.extend([
+                    word(LOADI_W, dst, 0, 0),
+                    value as u64 as u32,
+                    (value as u64 >> 32) as u32,
+                ]),
+            },
+            Op::LoadG { dst, idx } => match u16::try_from(idx) {
+                Ok(small) => code.push(word_bc(LOADG, dst, small)),
+                Err(_) => code.extend([word(LOADG_W, dst, 0, 0), idx]),
+            },
+            Op::Size { dst, value } => match u16::try_from(value) {
+                Ok(small) => code.push(word_bc(SIZE, dst, small)),
+                Err(_) => code.extend([word(SIZE_W, dst, 0, 0), value]),
+            },
+            Op::Let { hash, src } => {
+                code.extend([word(LET, src, 0, 0), hash as u32, (hash >> 32) as u32])
+            }
+            Op::LoadV { hash, dst } => {
+                code.extend([word(LOADV, dst, 0, 0), hash as u32, (hash >> 32) as u32])
+            }
+            Op::New {
+                dst,
+                size,
+                ref new_type,
+            } => code.push(word(
+                NEW,
+                dst,
+                size,
+                match new_type {
+                    New::Object => 0,
+                    New::Array => 1,
+                },
+            )),
+            Op::Append { container, src } => code.push(word(APPEND, container, src, 0)),
+            Op::Len { dst, src } => code.push(word(LEN, dst, src, 0)),
+            Op::Idx {
+                dst,
+                container,
+                index,
+            } => code.push(word(IDX, dst, container, index)),
+            Op::Set {
+                container,
+                key,
+                src,
+            } => code.push(word(SET, container, key, src)),
+            Op::Concat { start, len } => code.push(word(CONCAT, start, len, 0)),
+            Op::Jmp { target } => {
+                let rel = (offsets[target] as i64 - offsets[i] as i64) as i32;
+                if wide[i] {
+                    code.extend([word(JMP_W, 0, 0, 0), rel as u32]);
+                } else {
+                    code.push(JMP as u32 | (rel as u32) << 8);
+                }
+            }
+            Op::JmpF { cond, target } => {
+                let rel = (offsets[target] as i64 - offsets[i] as i64) as i32;
+                if wide[i] {
+                    code.extend([word(JMPF_W, cond, 0, 0), rel as u32]);
+                } else {
+                    code.push(word_bc(JMPF, cond, rel as i16 as u16));
+                }
+            }
+            Op::Call {
+                func,
+                args_start,
+                args_len,
+            } => match u8::try_from(func) {
+                Ok(small) => code.push(word(CALL, args_start, args_len, small)),
+                Err(_) => code.extend([word(CALL_W, args_start, args_len, 0), func as u32]),
+            },
+            Op::Ret { times } => code.push(word(RET, times, 0, 0)),
+            Op::Sys {
+                args_start,
+                args_len,
+                ..
+            } => match u8::try_from(builtin[i]) {
+                Ok(small) => code.push(word(SYS, args_start, args_len, small)),
+                Err(_) => code.extend([word(SYS_W, args_start, args_len, 0), builtin[i] as u32]),
+            },
+        }
+    }
+
+    debug_assert_eq!(bc.code.len(), *offsets.last().unwrap_or(&0));
+    (bc, offsets)
+}
+
+impl<'bc> Bytecode<'bc> {
+    /// number of words the instruction with the head word `w` takes up
+    pub fn width(w: u32) -> usize {
+        use opcode::*;
+        match op(w) {
+            LOADI_W | LET | LOADV => 3,
+            LOADG_W | SIZE_W | JMP_W | JMPF_W | CALL_W | SYS_W => 2,
+            _ => 1,
+        }
+    }
+
+    /// Unpacks the bytecode into ops, relative jump offsets are rewritten to op indexes
+    pub fn decode(&self) -> Vec<Op<'bc>> {
+        use opcode::*;
+
+        let mut word_to_op = vec![usize::MAX; self.code.len() + 1];
+        let mut pc = 0;
+        let mut count = 0;
+        while pc < self.code.len() {
+            word_to_op[pc] = count;
+            pc += Self::width(self.code[pc]);
+            count += 1;
+        }
+        word_to_op[self.code.len()] = count;
+
+        let target = |pc: usize, rel: i32| word_to_op[(pc as i64 + rel as i64) as usize];
+        let ext = |pc: usize, n: usize| self.code[pc + n];
+        let ext64 = |pc: usize| ext(pc, 1) as u64 | (ext(pc, 2) as u64) << 32;
+
+        let mut ops = Vec::with_capacity(count);
+        let mut pc = 0;
+        while pc < self.code.len() {
+            let w = self.code[pc];
+            let (dst, lhs, rhs) = (a(w), b(w), c(w));
+            ops.push(match op(w) {
+                ADD => Op::Add { dst, lhs, rhs },
+                SUB => Op::Sub { dst, lhs, rhs },
+                MUL => Op::Mul { dst, lhs, rhs },
+                DIV => Op::Div { dst, lhs, rhs },
+                EQ => Op::Eq { dst, lhs, rhs },
+                LT => Op::Lt { dst, lhs, rhs },
+                GT => Op::Gt { dst, lhs, rhs },
+                LE => Op::Le { dst, lhs, rhs },
+                GE => Op::Ge { dst, lhs, rhs },
+                NE => Op::Ne { dst, lhs, rhs },
+                MOD => Op::Mod { dst, lhs, rhs },
+                MOV => Op::Mov { dst, src: lhs },
+                BITAND => Op::BitAnd { dst, lhs, rhs },
+                BITOR => Op::BitOr { dst, lhs, rhs },
+                BITXOR => Op::BitXor { dst, lhs, rhs },
+                SHL => Op::Shl { dst, lhs, rhs },
+                SHR => Op::Shr { dst, lhs, rhs },
+                NEG => Op::Neg { dst, src: lhs },
+                BITNOT => Op::BitNot { dst, src: lhs },
+                NOT => Op::Not { dst, src: lhs },
+                LOADI => Op::LoadI {
+                    dst,
+                    value: bc(w) as i16 as i64,
+                },
+                LOADI_W => Op::LoadI {
+                    dst,
+                    value: ext64(pc) as i64,
+                },
+                LOADG => Op::LoadG {
+                    dst,
+                    idx: bc(w) as u32,
+                },
+                LOADG_W => Op::LoadG {
+                    dst,
+                    idx: ext(pc, 1),
+                },
+                SIZE => Op::Size {
+                    dst,
+                    value: bc(w) as u32,
+                },
+                SIZE_W => Op::Size {
+                    dst,
+                    value: ext(pc, 1),
+                },
+                LET => Op::Let {
+                    hash: ext64(pc),
+                    src: dst,
+                },
+                LOADV => Op::LoadV {
+                    hash: ext64(pc),
+                    dst,
+                },
+                NEW => Op::New {
+                    dst,
+                    size: lhs,
+                    new_type: if rhs == 0 { New::Object } else { New::Array },
+                },
+                APPEND => Op::Append {
+                    container: dst,
+                    src: lhs,
+                },
+                LEN => Op::Len { dst, src: lhs },
+                IDX => Op::Idx {
+                    dst,
+                    container: lhs,
+                    index: rhs,
+                },
+                SET => Op::Set {
+                    container: dst,
+                    key: lhs,
+                    src: rhs,
+                },
+                CONCAT => Op::Concat {
+                    start: dst,
+                    len: lhs,
+                },
+                JMP => Op::Jmp {
+                    target: target(pc, abc(w)),
+                },
+                JMP_W => Op::Jmp {
+                    target: target(pc, ext(pc, 1) as i32),
+                },
+                JMPF => Op::JmpF {
+                    cond: dst,
+                    target: target(pc, bc(w) as i16 as i32),
+                },
+                JMPF_W => Op::JmpF {
+                    cond: dst,
+                    target: target(pc, ext(pc, 1) as i32),
+                },
+                CALL => Op::Call {
+                    func: rhs as u16,
+                    args_start: dst,
+                    args_len: lhs,
+                },
+                CALL_W => Op::Call {
+                    func: ext(pc, 1) as u16,
+                    args_start: dst,
+                    args_len: lhs,
+                },
+                RET => Op::Ret { times: dst },
+                SYS => Op::Sys {
+                    ptr: self.builtins[rhs as usize],
+                    args_start: dst,
+                    args_len: lhs,
+                },
+                SYS_W => Op::Sys {
+                    ptr: self.builtins[ext(pc, 1) as usize],
+                    args_start: dst,
+                    args_len: lhs,
+                },
+                unknown => unreachable!("Bytecode::decode: unknown opcode {unknown:#04x}"),
+            });
+            pc += Self::width(w);
+        }
+
+        ops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bc::{Bytecode, LineTable, encode, opcode},
+        err::Span,
+        op::{New, Op},
+        vm::{BuiltinFn, ErrorKind, Value, Vm},
+    };
+
+    fn roundtrip(ops: Vec<Op<'static>>) -> Bytecode<'static> {
+        let (bc, offsets) = encode(&ops);
+        assert_eq!(offsets.len(), ops.len() + 1);
+        assert_eq!(*offsets.last().unwrap(), bc.code.len());
+        assert_eq!(bc.decode(), ops);
+        bc
+    }
+
+    #[test]
+    fn narrow() {
+        let bc = roundtrip(vec![
+            Op::LoadI { dst: 0, value: -5 },
+            Op::LoadI { dst: 1, value: 32 },
+            Op::Add {
+                dst: 2,
+                lhs: 0,
+                rhs: 1,
+            },
+            Op::LoadG { dst: 3, idx: 1 },
+            Op::Mov { dst: 4, src: 2 },
+            Op::Mod {
+                dst: 2,
+                lhs: 0,
+                rhs: 1,
+            },
+            Op::Ne {
+                dst: 2,
+                lhs: 0,
+                rhs: 1,
+            },
+            Op::Neg { dst: 4, src: 2 },
+            Op::Shr {
+                dst: 2,
+                lhs: 0,
+                rhs: 1,
+            },
+            Op::BitNot { dst: 4, src: 2 },
+            Op::Not { dst: 4, src: 2 },
+            Op::New {
+                dst: 5,
+                size: 2,
+                new_type: New::Array,
+            },
+            Op::Idx {
+                dst: 6,
+                container: 5,
+                index: 0,
+            },
+            Op::Set {
+                container: 5,
+                key: 0,
+                src: 6,
+            },
+            Op::Concat { start: 4, len: 3 },
+            Op::Call {
+                func: 3,
+                args_start: 0,
+                args_len: 2,
+            },
+            Op::Ret { times: 2 },
+        ]);
+        // every op fits into its head word
+        assert_eq!(bc.code.len(), 17);
+    }
+
+    #[test]
+    fn wide() {
+        let bc = roundtrip(vec![
+            Op::LoadI {
+                dst: 0,
+                value: i64::MIN,
+            },
+            Op::LoadG {
+                dst: 1,
+                idx: u32::MAX,
+            },
+            Op::Size {
+                dst: 2,
+                value: 70_000,
+            },
+            Op::Let {
+                hash: 0xDEAD_BEEF_CAFE_BABE,
+                src: 0,
+            },
+            Op::LoadV {
+                hash: u64::MAX,
+                dst: 3,
+            },
+            Op::Call {
+                func: 300,
+                args_start: 1,
+                args_len: 1,
+            },
+        ]);
+        assert_eq!(bc.code.len(), 3 + 2 + 2 + 3 + 3 + 2);
+        assert_eq!(bc.code[0] as u8, opcode::LOADI_W);
+    }
+
+    #[test]
+    fn relative_jumps() {
+        let bc = roundtrip(vec![
+            Op::LoadI { dst: 0, value: 0 },
+            Op::JmpF { cond: 0, target: 4 },
+            Op::Let { hash: 1, src: 0 },
+            Op::Jmp { target: 0 },
+            Op::Ret { times: 1 },
+        ]);
+        // JmpF at word 1 to Ret at word 6, Jmp at word 5 back to word 0
+        assert_eq!(bc.code[1] >> 16, 5);
+        assert_eq!((bc.code[5] as i32) >> 8, -5);
+    }
+
+    #[test]
+    fn relaxation() {
+        // 40_000 single word ops between the conditional jump and its target don't fit into the
+        // 16 bit offset of JMPF, forcing the wide form
+        let mut ops = vec![Op::JmpF {
+            cond: 0,
+            target: 40_001,
+        }];
+        ops.extend((0..40_000).map(|_| Op::Mov { dst: 0, src: 0 }));
+        ops.push(Op::Jmp { target: 0 });
+        let bc = roundtrip(ops);
+        assert_eq!(bc.code[0] as u8, opcode::JMPF_W);
+        assert_eq!(bc.code[40_002] as u8, opcode::JMP);
+    }
+
+    #[test]
+    fn line_table() {
+        let span = |line, start| Span {
+            line,
+            start,
+            end: start + 1,
+        };
+        let mut lines = LineTable::default();
+        lines.push(0, span(1, 0));
+        lines.push(1, span(1, 0));
+        lines.push(2, span(1, 4));
+        // no code for the span at op 3, replaced by the next one
+        lines.push(3, span(2, 0));
+        lines.push(3, span(2, 8));
+        assert_eq!(lines.len(), 3);
+
+        let ops = [
+            Op::LoadI { dst: 0, value: 1 },
+            Op::Let { hash: 0, src: 0 },
+            Op::LoadI { dst: 0, value: 2 },
+            Op::Ret { times: 1 },
+        ];
+        let (_, offsets) = encode(&ops);
+        lines.translate(&offsets);
+        assert_eq!(lines.lookup(0), Some(span(1, 0)));
+        assert_eq!(lines.lookup(3), Some(span(1, 0)));
+        assert_eq!(lines.lookup(4), Some(span(1, 4)));
+        assert_eq!(lines.lookup(5), Some(span(2, 8)));
+        assert_eq!(lines.lookup(100), Some(span(2, 8)));
+        assert_eq!(LineTable::default().lookup(0), None);
+    }
+
+    #[test]
+    fn builtins_are_deduplicated() {
+        // distinct bodies, so the linker can't fold them into a single function
+        fn first<'vm>(_: &mut Vm<'vm>, _: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+            Ok(Value::Int(1))
+        }
+        fn second<'vm>(_: &mut Vm<'vm>, _: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+            Ok(Value::Int(2))
+        }
+        let bc = roundtrip(vec![
+            Op::Sys {
+                ptr: first,
+                args_start: 0,
+                args_len: 0,
+            },
+            Op::Sys {
+                ptr: second,
+                args_start: 0,
+                args_len: 0,
+            },
+            Op::Sys {
+                ptr: first,
+                args_start: 1,
+                args_len: 2,
+            },
+        ]);
+        assert_eq!(bc.builtins.len(), 2);
+    }
+
+    #[test]
+    fn wide_builtins() {
+        fn builtin<const ROW: usize, const COL: usize>(
+            _: &mut Vm<'static>,
+            _: &[Value<'static>],
+        ) -> Result<Value<'static>, ErrorKind> {
+            Ok(Value::Int((ROW * 16 + COL) as i64))
+        }
+        fn row<const ROW: usize>() -> [BuiltinFn<'static>; 16] {
+            [
+                builtin::<ROW, 0>,
+                builtin::<ROW, 1>,
+                builtin::<ROW, 2>,
+                builtin::<ROW, 3>,
+                builtin::<ROW, 4>,
+                builtin::<ROW, 5>,
+                builtin::<ROW, 6>,
+                builtin::<ROW, 7>,
+                builtin::<ROW, 8>,
+                builtin::<ROW, 9>,
+                builtin::<ROW, 10>,
+                builtin::<ROW, 11>,
+                builtin::<ROW, 12>,
+                builtin::<ROW, 13>,
+                builtin::<ROW, 14>,
+                builtin::<ROW, 15>,
+            ]
+        }
+        let ptrs = [
+            row::<0>(),
+            row::<1>(),
+            row::<2>(),
+            row::<3>(),
+            row::<4>(),
+            row::<5>(),
+            row::<6>(),
+            row::<7>(),
+            row::<8>(),
+            row::<9>(),
+            row::<10>(),
+            row::<11>(),
+            row::<12>(),
+            row::<13>(),
+            row::<14>(),
+            row::<15>(),
+            row::<16>(),
+        ];
+
+        // a jump over every Sys, only lands on the Ret if the wide ones were sized as such
+        let mut ops = vec![Op::Jmp { target: 273 }];
+        ops.extend(ptrs.into_iter().flatten().map(|ptr| Op::Sys {
+            ptr,
+            args_start: 0,
+            args_len: 0,
+        }));
+        ops.push(Op::Ret { times: 1 });
+        let bc = roundtrip(ops);
+        assert_eq!(bc.builtins.len(), 272);
+        assert_eq!(bc.code.len(), 1 + 256 + 16 * 2 + 1);
+        assert_eq!(bc.code[1 + 256] as u8, opcode::SYS_W);
+        assert_eq!((bc.code[0] as i32) >> 8, 1 + 256 + 16 * 2);
+    }
+}
@@ -6,6 +6,8 @@ use crate::cc::Const;
 pub struct Context<'ctx> {
     pub globals: HashMap<Const<'ctx>, usize>,
     pub globals_vec: Vec<Const<'ctx>>,
+    /// identifier names by their hash, for disassembly and error messages
+    pub symbols: HashMap<u64, &'ctx str>,
 }
 
 impl<'ctx> Context<'ctx> {
@@ -15,8 +17,8 @@ impl<'ctx> Context<'ctx> {
         }
 
         let idx = self.globals_vec.len();
+        self.globals.insert(constant.clone(), idx);
         self.globals_vec.push(constant);
-        self.globals.insert(constant, idx);
         idx as u32
     }
 }
@@ -0,0 +1,31 @@
+//! Constant folding of integer expressions with the semantics of vm::int. Only folds what fits
+//! into an i64 without an error, everything else is left to the vm, which promotes overflows to
+//! big ints and fails on errors with the span of the operator.
+
+use crate::{lex::Type, vm::int};
+
+/// `op rhs` for the unary operator `op`, if it folds
+pub fn unary(op: &Type, rhs: i64) -> Option<i64> {
+    match op {
+        Type::Minus => int::neg(rhs),
+        Type::Tilde => Some(!rhs),
+        _ => None,
+    }
+}
+
+/// `lhs op rhs` for the binary operator `op`, if it folds
+pub fn bin(op: &Type, lhs: i64, rhs: i64) -> Option<i64> {
+    match op {
+        Type::Plus => int::add(lhs, rhs),
+        Type::Minus => int::sub(lhs, rhs),
+        Type::Asteriks => int::mul(lhs, rhs),
+        Type::Slash => int::div(lhs, rhs),
+        Type::Percent => int::rem(lhs, rhs),
+        Type::Ampersand => Some(lhs & rhs),
+        Type::Pipe => Some(lhs | rhs),
+        Type::Caret => Some(lhs ^ rhs),
+        Type::ShiftLeft => int::shl(lhs, rhs).ok(),
+        Type::ShiftRight => int::shr(lhs, rhs).ok(),
+        _ => None,
+    }
+}
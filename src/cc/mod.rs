@@ -1,68 +1,128 @@
 use std::{
+    borrow::Cow,
     hash::{DefaultHasher, Hash, Hasher},
     num,
 };
 
 mod ctx;
+mod fold;
 mod reg;
 
 use crate::{
     ast::{InnerNode, Node},
+    bc::LineTable,
     cc::{ctx::Context, reg::RegisterAllocator},
-    err::PgError,
-    lex::Type,
+    err::{PgError, Span},
+    lex::{Token, Type},
     op::Op,
-    vm::{Value, Vm},
+    stdlib,
+    vm::{self, Value, Vm},
 };
 
 /// Compile time Value representation
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Const<'c> {
     False,
     True,
+    None,
     Int(i64),
+    /// the decimal digits of an int literal outside of i64 range
+    BigInt(&'c str),
     Double(u64),
-    Str(&'c str),
+    /// borrowed from the input unless the literal had escapes, see Value::from_const
+    Str(Cow<'c, str>),
+}
+
+/// Registers an interpolation concatenates at once, longer ones are concatenated in chunks into
+/// an accumulating string
+const INTERPOLATION_CHUNK: usize = 8;
+
+/// A compiled expression, see Cc::operand
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Reg(u8),
+    /// a folded int, not loaded into a register yet
+    Int {
+        value: i64,
+        span: Span,
+    },
 }
 
 #[derive(Debug)]
 pub struct Cc<'cc> {
     buf: Vec<Op<'cc>>,
+    /// source spans of the ops in buf, by op index
+    lines: LineTable,
     ctx: Context<'cc>,
     register: RegisterAllocator,
-    hasher: DefaultHasher,
+}
+
+/// Hashes an identifier the way Op::Let and Op::LoadV expect it, each call starts from a fresh
+/// hasher, so equal names always produce equal hashes
+pub fn hash(name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl<'cc> Cc<'cc> {
     pub fn new() -> Self {
         Self {
             buf: Vec::with_capacity(256),
+            lines: LineTable::default(),
             ctx: {
                 let mut ctx = Context::default();
                 ctx.intern(Const::False);
                 ctx.intern(Const::True);
+                ctx.intern(Const::None);
                 ctx
             },
             register: RegisterAllocator::new(),
-            hasher: DefaultHasher::new(),
         }
     }
 
     pub const GLOBAL_FALSE: u32 = 0;
     pub const GLOBAL_TRUE: u32 = 1;
+    pub const GLOBAL_NONE: u32 = 2;
+
+    /// emit appends `op` to the bytecode and records `span` as its source location
+    fn emit(&mut self, op: Op<'cc>, span: impl Into<Span>) {
+        self.lines.push(self.buf.len(), span.into());
+        self.buf.push(op);
+    }
+
+    /// the register holding `operand`, loading it first if it was folded
+    fn materialize(&mut self, operand: Operand) -> u8 {
+        match operand {
+            Operand::Reg(r) => r,
+            Operand::Int { value, span } => {
+                let r = self.register.alloc();
+                self.emit(Op::LoadI { dst: r, value }, span);
+                r
+            }
+        }
+    }
 
-    fn load_const(&mut self, c: Const<'cc>) -> u8 {
+    fn load_const(&mut self, c: Const<'cc>, token: &Token) -> u8 {
         let r = self.register.alloc();
-        self.buf.push(Op::LoadG {
-            dst: r,
-            idx: self.ctx.intern(c),
-        });
+        let idx = self.ctx.intern(c);
+        self.emit(Op::LoadG { dst: r, idx }, token);
         r
     }
 
-    fn hash(&mut self, to_hash: impl Hash) -> u64 {
-        to_hash.hash(&mut self.hasher);
-        self.hasher.finish()
+    /// points the jump at `at` to the next op emitted
+    fn patch(&mut self, at: usize) {
+        let next = self.buf.len();
+        match &mut self.buf[at] {
+            Op::Jmp { target } | Op::JmpF { target, .. } => *target = next,
+            other => unreachable!("Cc::patch: {other:?} is not a jump"),
+        }
+    }
+
+    fn hash(&mut self, name: &'cc str) -> u64 {
+        let hash = hash(name);
+        self.ctx.symbols.insert(hash, name);
+        hash
     }
 
     /// compile is a simple wrapper around self.cc to make sure all registers are deallocated after
@@ -75,23 +135,119 @@ impl<'cc> Cc<'cc> {
     }
 
     pub fn cc(&mut self, ast: Node<'cc>) -> Result<u8, PgError> {
+        let operand = self.operand(ast)?;
+        Ok(self.materialize(operand))
+    }
+
+    /// Like Cc::cc, but int literals and the int expressions folding them leave nothing emitted,
+    /// so the enclosing expression can fold them further. Each node is folded once, bottom up.
+    fn operand(&mut self, ast: Node<'cc>) -> Result<Operand, PgError> {
         #[cfg(feature = "trace")]
         println!("Cc::cc({:?})", &ast.token.t);
 
+        Ok(match ast.inner {
+            InnerNode::Atom
+                if let Type::Integer(s) = ast.token.t
+                    && let Ok(value) = s.parse() =>
+            {
+                Operand::Int {
+                    value,
+                    span: Span::from(&ast.token),
+                }
+            }
+            InnerNode::Unary { rhs } => {
+                let operand = self.operand(*rhs)?;
+                if let Operand::Int { value, .. } = operand
+                    && let Some(value) = fold::unary(&ast.token.t, value)
+                {
+                    return Ok(Operand::Int {
+                        value,
+                        span: Span::from(&ast.token),
+                    });
+                }
+                let src = self.materialize(operand);
+                let dst = self.register.alloc();
+                let op = match ast.token.t {
+                    Type::Minus => Op::Neg { dst, src },
+                    Type::Exlaim => Op::Not { dst, src },
+                    Type::Tilde => Op::BitNot { dst, src },
+                    _ => unreachable!("InnerNode::Unary"),
+                };
+                self.emit(op, &ast.token);
+                self.register.free(src);
+                Operand::Reg(dst)
+            }
+            InnerNode::Bin { lhs, rhs }
+                if !matches!(ast.token.t, Type::DoubleAmpersand | Type::DoublePipe) =>
+            {
+                // loaded right away, so it stays evaluated before rhs if rhs does not fold
+                let lhs_operand = self.operand(*lhs)?;
+                let lhs = self.materialize(lhs_operand);
+                let rhs_operand = self.operand(*rhs)?;
+                if let (Operand::Int { value: l, .. }, Operand::Int { value: r, .. }) =
+                    (lhs_operand, rhs_operand)
+                    && let Some(value) = fold::bin(&ast.token.t, l, r)
+                {
+                    // nothing was emitted for rhs, so the load of lhs is the last op, its line
+                    // table entry is replaced by the next op emitted at its pc
+                    self.buf.pop();
+                    self.register.free(lhs);
+                    return Ok(Operand::Int {
+                        value,
+                        span: Span::from(&ast.token),
+                    });
+                }
+                let rhs = self.materialize(rhs_operand);
+
+                let dst = self.register.alloc();
+                let op = match ast.token.t {
+                    Type::Plus => Op::Add { dst, lhs, rhs },
+                    Type::Minus => Op::Sub { dst, lhs, rhs },
+                    Type::Asteriks => Op::Mul { dst, lhs, rhs },
+                    Type::Slash => Op::Div { dst, lhs, rhs },
+                    Type::Percent => Op::Mod { dst, lhs, rhs },
+                    Type::LessThan => Op::Lt { dst, lhs, rhs },
+                    Type::GreaterThan => Op::Gt { dst, lhs, rhs },
+                    Type::LessEqual => Op::Le { dst, lhs, rhs },
+                    Type::GreaterEqual => Op::Ge { dst, lhs, rhs },
+                    Type::Equal => Op::Eq { dst, lhs, rhs },
+                    Type::NotEqual => Op::Ne { dst, lhs, rhs },
+                    Type::Ampersand => Op::BitAnd { dst, lhs, rhs },
+                    Type::Pipe => Op::BitOr { dst, lhs, rhs },
+                    Type::Caret => Op::BitXor { dst, lhs, rhs },
+                    Type::ShiftLeft => Op::Shl { dst, lhs, rhs },
+                    Type::ShiftRight => Op::Shr { dst, lhs, rhs },
+                    _ => unreachable!(),
+                };
+                self.emit(op, &ast.token);
+
+                self.register.free(lhs);
+                self.register.free(rhs);
+                Operand::Reg(dst)
+            }
+            inner => Operand::Reg(self.expr(Node {
+                token: ast.token,
+                inner,
+            })?),
+        })
+    }
+
+    /// Compiles every node but the ones Cc::operand folds
+    fn expr(&mut self, ast: Node<'cc>) -> Result<u8, PgError> {
         Ok(match ast.inner {
             InnerNode::Atom => {
                 let constant = match &ast.token.t {
-                    Type::Integer(s) => {
-                        let value = s.parse().map_err(|e: num::ParseIntError| {
-                            PgError::with_msg(e.to_string(), &ast.token)
-                        })?;
-
-                        let r = self.register.alloc();
-                        self.buf.push(Op::LoadI { dst: r, value });
+                    Type::Integer(s) => match s.parse() {
+                        Ok(value) => {
+                            let r = self.register.alloc();
+                            self.emit(Op::LoadI { dst: r, value }, &ast.token);
 
-                        // early bail, since we do LoadG for the other values
-                        return Ok(r);
-                    }
+                            // early bail, since we do LoadG for the other values
+                            return Ok(r);
+                        }
+                        Err(e) if *e.kind() == num::IntErrorKind::PosOverflow => Const::BigInt(s),
+                        Err(e) => return Err(PgError::with_msg(e.to_string(), &ast.token)),
+                    },
                     Type::Double(s) => Const::Double(
                         s.parse::<f64>()
                             .map_err(|e: num::ParseFloatError| {
@@ -99,15 +255,16 @@ impl<'cc> Cc<'cc> {
                             })?
                             .to_bits(),
                     ),
-                    Type::String(s) => Const::Str(s),
+                    Type::String(s) => Const::Str(s.clone()),
                     Type::True => Const::True,
                     Type::False => Const::False,
+                    Type::None => Const::None,
                     _ => unreachable!(
-                        "This is considered an impossible path, InnerNode::Atom can only have Type::{{Integer, Double, String, True, False}}"
+                        "This is considered an impossible path, InnerNode::Atom can only have Type::{{Integer, Double, String, True, False, None}}"
                     ),
                 };
 
-                self.load_const(constant)
+                self.load_const(constant, &ast.token)
             }
             InnerNode::Ident => {
                 let Type::Ident(name) = ast.token.t else {
@@ -115,52 +272,228 @@ impl<'cc> Cc<'cc> {
                 };
                 let r = self.register.alloc();
                 let hash = self.hash(name);
-                self.buf.push(Op::LoadV { dst: r, hash });
+                self.emit(Op::LoadV { dst: r, hash }, &ast.token);
                 r
             }
-            InnerNode::Bin { lhs, rhs } => {
-                let lhs = self.cc(*lhs)?;
-                let rhs = self.cc(*rhs)?;
-
+            // short circuits evaluate to the operand that decided the result
+            InnerNode::Bin { lhs, rhs }
+                if matches!(ast.token.t, Type::DoubleAmpersand | Type::DoublePipe) =>
+            {
+                let dst = self.cc(*lhs)?;
+                let jmpf = self.buf.len();
+                self.emit(
+                    Op::JmpF {
+                        cond: dst,
+                        target: 0,
+                    },
+                    &ast.token,
+                );
+                let skip_rhs = if let Type::DoublePipe = ast.token.t {
+                    // lhs is truthy, so is the result
+                    let jmp = self.buf.len();
+                    self.emit(Op::Jmp { target: 0 }, &ast.token);
+                    self.patch(jmpf);
+                    jmp
+                } else {
+                    // lhs is falsy, so is the result
+                    jmpf
+                };
+                let r = self.cc(*rhs)?;
+                self.emit(Op::Mov { dst, src: r }, &ast.token);
+                self.register.free(r);
+                self.patch(skip_rhs);
+                dst
+            }
+            // evaluates to the body of the first case whose condition is neither false nor none,
+            // otherwise to the default or none without one
+            InnerNode::Match { cases, default } => {
                 let dst = self.register.alloc();
-                self.buf.push(match ast.token.t {
-                    Type::Plus => Op::Add { dst, lhs, rhs },
-                    Type::Minus => Op::Sub { dst, lhs, rhs },
-                    Type::Asteriks => Op::Mul { dst, lhs, rhs },
-                    Type::Slash => Op::Div { dst, lhs, rhs },
-                    Type::LessThan => Op::Lt { dst, lhs, rhs },
-                    Type::GreaterThan => Op::Gt { dst, lhs, rhs },
-                    Type::Equal => Op::Eq { dst, lhs, rhs },
-                    _ => unreachable!(),
-                });
+                let mut ends = Vec::with_capacity(cases.len());
+                for (condition, body) in cases {
+                    let cond = self.cc(condition)?;
+                    let next_case = self.buf.len();
+                    self.emit(Op::JmpF { cond, target: 0 }, &ast.token);
+                    self.register.free(cond);
 
-                self.register.free(lhs);
-                self.register.free(rhs);
+                    let r = self.cc(body)?;
+                    self.emit(Op::Mov { dst, src: r }, &ast.token);
+                    self.register.free(r);
+                    ends.push(self.buf.len());
+                    self.emit(Op::Jmp { target: 0 }, &ast.token);
+                    self.patch(next_case);
+                }
+                match default {
+                    Some(default) => {
+                        let r = self.cc(*default)?;
+                        self.emit(Op::Mov { dst, src: r }, &ast.token);
+                        self.register.free(r);
+                    }
+                    None => self.emit(
+                        Op::LoadG {
+                            dst,
+                            idx: Self::GLOBAL_NONE,
+                        },
+                        &ast.token,
+                    ),
+                }
+                for end in ends {
+                    self.patch(end);
+                }
                 dst
             }
-            _ => todo!("{:?}", ast),
+            // the text and the values of the embedded expressions in consecutive registers, joined
+            // by Op::Concat into the first one
+            InnerNode::Interpolation { parts } => {
+                let Type::InterpolationStart(head) = &ast.token.t else {
+                    unreachable!("InnerNode::Interpolation");
+                };
+                let head = (!head.is_empty()).then(|| Const::Str(head.clone()));
+                let len = (parts.len() + head.is_some() as usize).min(INTERPOLATION_CHUNK);
+                let start = self.register.alloc_range(len);
+                let mut next = start;
+                if let Some(head) = head {
+                    let idx = self.ctx.intern(head);
+                    self.emit(Op::LoadG { dst: start, idx }, &ast.token);
+                    next += 1;
+                }
+                for part in parts {
+                    // the range is full, concatenate it into its first register and continue
+                    // after it
+                    if next - start == len as u8 {
+                        self.emit(
+                            Op::Concat {
+                                start,
+                                len: next - start,
+                            },
+                            &ast.token,
+                        );
+                        next = start + 1;
+                    }
+                    let r = self.cc(part)?;
+                    self.emit(Op::Mov { dst: next, src: r }, &ast.token);
+                    self.register.free(r);
+                    next += 1;
+                }
+                self.emit(
+                    Op::Concat {
+                        start,
+                        len: next - start,
+                    },
+                    &ast.token,
+                );
+                for r in start + 1..start + len as u8 {
+                    self.register.free(r);
+                }
+                start
+            }
+            InnerNode::Path { members, leaf } => {
+                let InnerNode::Call { args } = leaf.inner else {
+                    return Err(PgError::with_msg(
+                        "only calls are supported in std paths",
+                        &leaf.token,
+                    ));
+                };
+                let Type::Ident(name) = leaf.token.t else {
+                    unreachable!("InnerNode::Path::leaf");
+                };
+                let mut path: Vec<&str> = members
+                    .iter()
+                    .map(|member| match member.token.t {
+                        Type::Ident(member) => member,
+                        _ => unreachable!("InnerNode::Path::members"),
+                    })
+                    .collect();
+                path.push(name);
+                let path = path.join("::");
+                let ptr = stdlib::resolve(&path).ok_or_else(|| {
+                    PgError::with_msg(format!("unknown builtin `std::{path}`"), &leaf.token)
+                })?;
+
+                // the arguments are passed in consecutive registers of a single window
+                let args_len = u8::try_from(args.len())
+                    .ok()
+                    .filter(|&len| len as usize <= vm::REGISTER_COUNT)
+                    .ok_or_else(|| {
+                        PgError::with_msg(
+                            format!(
+                                "`std::{path}` is called with {} arguments, calls pass at most {}",
+                                args.len(),
+                                vm::REGISTER_COUNT
+                            ),
+                            &leaf.token,
+                        )
+                    })?;
+                // the result lands in the first argument register, so calls without arguments
+                // still need one
+                let args_start = self.register.alloc_range(args_len.max(1) as usize);
+                for (i, arg) in args.into_iter().enumerate() {
+                    let r = self.cc(arg)?;
+                    self.emit(
+                        Op::Mov {
+                            dst: args_start + i as u8,
+                            src: r,
+                        },
+                        &leaf.token,
+                    );
+                    self.register.free(r);
+                }
+                self.emit(
+                    Op::Sys {
+                        ptr,
+                        args_start,
+                        args_len,
+                    },
+                    &leaf.token,
+                );
+                for i in 1..args_len {
+                    self.register.free(args_start + i);
+                }
+                args_start
+            }
+            unsupported => {
+                let kind = match unsupported {
+                    InnerNode::Array { .. } => "arrays",
+                    InnerNode::Object { .. } => "objects",
+                    InnerNode::Let { .. } => "`let` bindings",
+                    InnerNode::Fn { .. } => "function definitions",
+                    InnerNode::Call { .. } => "function calls",
+                    _ => unreachable!("Cc::operand compiles InnerNode::{{Unary, Bin}}"),
+                };
+                return Err(PgError::with_msg(
+                    format!("{kind} are not supported by the compiler yet"),
+                    &ast.token,
+                ));
+            }
         })
     }
 
     pub fn finalize(self) -> Vm<'cc> {
-        let mut v = Vm {
-            ..Default::default()
-        };
-        v.bytecode = self.buf;
-        v.globals = self.ctx.globals_vec.into_iter().map(Value::from).collect();
-        v
+        let mut vm = Vm::new(&self.buf, vec![], vec![], self.lines);
+        vm.globals = self
+            .ctx
+            .globals_vec
+            .into_iter()
+            .map(|constant| Value::from_const(constant, &mut vm.heap))
+            .collect();
+        vm.symbols = self.ctx.symbols;
+        vm
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod cc {
     use std::hash::{Hash, Hasher};
 
     use crate::{
         ast::{InnerNode, Node},
         cc::{Cc, Const},
-        lex::{Token, Type},
+        err::Span,
+        lex::{Lexer, Token, Type},
         op::Op,
+        parser::Parser,
+        stdlib,
+        vm::{ErrorKind, Value, Vm},
     };
 
     macro_rules! node {
@@ -177,12 +510,14 @@ mod cc {
             Token {
                 line: 0,
                 col: 0,
+                len: 0,
                 t: $expr,
             }
         };
     }
 
     #[test]
+    #[allow(clippy::let_unit_value)]
     fn atom_false() {
         let mut cc = Cc::new();
         let ast = Node {
@@ -203,6 +538,7 @@ mod cc {
     }
 
     #[test]
+    #[allow(clippy::let_unit_value)]
     fn atom_true() {
         let mut cc = Cc::new();
         let ast = Node {
@@ -223,15 +559,35 @@ mod cc {
     }
 
     #[test]
+    fn atom_none() {
+        let mut cc = Cc::new();
+        let ast = Node {
+            token: token!(Type::None),
+            inner: InnerNode::Atom,
+        };
+
+        cc.compile(ast).expect("Failed to compile node");
+        assert_eq!(
+            cc.buf,
+            vec![Op::LoadG {
+                dst: 0,
+                idx: Cc::GLOBAL_NONE
+            }],
+        );
+        assert_eq!(cc.ctx.globals_vec[Cc::GLOBAL_NONE as usize], Const::None);
+    }
+
+    #[test]
+    #[allow(clippy::let_unit_value)]
     fn atom_string() {
         let mut cc = Cc::new();
         let ast = Node {
-            token: token!(Type::String("hola")),
+            token: token!(Type::String("hola".into())),
             inner: InnerNode::Atom,
         };
 
         let _ = cc.compile(ast).expect("Failed to compile node");
-        let expected_idx: usize = 2;
+        let expected_idx: usize = 3;
         assert_eq!(
             cc.buf,
             vec![Op::LoadG {
@@ -239,10 +595,33 @@ mod cc {
                 idx: expected_idx as u32
             }],
         );
-        assert_eq!(cc.ctx.globals_vec[expected_idx], Const::Str("hola"));
+        assert_eq!(cc.ctx.globals_vec[expected_idx], Const::Str("hola".into()));
+    }
+
+    /// strings with escapes no longer borrow the input, they still intern by content
+    #[test]
+    fn atom_escaped_string() {
+        let mut cc = Cc::new();
+        for src in [r#""a\tb""#, r#""a\u{9}b""#, "\"a\tb\""] {
+            cc.compile(parse(src)).expect("Failed to compile node");
+        }
+        assert_eq!(cc.ctx.globals_vec.len(), 4);
+        assert_eq!(cc.ctx.globals_vec[3], Const::Str("a\tb".into()));
+
+        let (mut vm, r) = compile_with(r#""\u{1F600}\n""#, &[]);
+        vm.run().expect("Failed to run");
+        let Some(Value::String(s)) = vm.registers[r as usize] else {
+            panic!("an escaped literal should be a heap string");
+        };
+        assert_eq!(vm.heap.get(s), "\u{1F600}\n");
+        assert_eq!(
+            run_with(r##""say \"hi\"" == r#"say "hi""#"##, &[]),
+            Ok(Value::True)
+        );
     }
 
     #[test]
+    #[allow(clippy::let_unit_value)]
     fn atom_int() {
         let mut cc = Cc::new();
         let ast = Node {
@@ -254,6 +633,7 @@ mod cc {
     }
 
     #[test]
+    #[allow(clippy::let_unit_value, clippy::approx_constant)]
     fn atom_double() {
         let mut cc = Cc::new();
         let ast = Node {
@@ -261,7 +641,7 @@ mod cc {
             inner: InnerNode::Atom,
         };
         let _ = cc.compile(ast).expect("Failed to compile node");
-        let expected_idx: usize = 2;
+        let expected_idx: usize = 3;
         assert_eq!(
             cc.buf,
             vec![Op::LoadG {
@@ -276,6 +656,7 @@ mod cc {
     }
 
     #[test]
+    #[allow(clippy::let_unit_value)]
     fn atom_ident() {
         let mut cc = Cc::new();
         let name = "thisisavariablename";
@@ -292,6 +673,7 @@ mod cc {
     }
 
     #[test]
+    #[allow(clippy::let_unit_value, clippy::type_complexity)]
     fn bin() {
         use crate::lex::Type::*;
         use crate::op::Op::*;
@@ -304,6 +686,15 @@ mod cc {
             (Equal, |dst, lhs, rhs| Eq { dst, lhs, rhs }),
             (LessThan, |dst, lhs, rhs| Lt { dst, lhs, rhs }),
             (GreaterThan, |dst, lhs, rhs| Gt { dst, lhs, rhs }),
+            (Percent, |dst, lhs, rhs| Mod { dst, lhs, rhs }),
+            (NotEqual, |dst, lhs, rhs| Ne { dst, lhs, rhs }),
+            (LessEqual, |dst, lhs, rhs| Le { dst, lhs, rhs }),
+            (GreaterEqual, |dst, lhs, rhs| Ge { dst, lhs, rhs }),
+            (Ampersand, |dst, lhs, rhs| BitAnd { dst, lhs, rhs }),
+            (Pipe, |dst, lhs, rhs| BitOr { dst, lhs, rhs }),
+            (Caret, |dst, lhs, rhs| BitXor { dst, lhs, rhs }),
+            (ShiftLeft, |dst, lhs, rhs| Shl { dst, lhs, rhs }),
+            (ShiftRight, |dst, lhs, rhs| Shr { dst, lhs, rhs }),
         ];
 
         for (token_type, make_op) in tests {
@@ -312,7 +703,8 @@ mod cc {
             let ast = Node {
                 token: token!(token_type.clone()),
                 inner: InnerNode::Bin {
-                    lhs: Box::new(node!(token!(Type::Integer("45")), InnerNode::Atom)),
+                    // an ident, so the operation is not folded
+                    lhs: Box::new(node!(token!(Type::Ident("a")), InnerNode::Ident)),
                     rhs: Box::new(node!(token!(Type::Integer("45")), InnerNode::Atom)),
                 },
             };
@@ -321,7 +713,10 @@ mod cc {
             assert_eq!(
                 cc.buf,
                 vec![
-                    Op::LoadI { dst: 0, value: 45 },
+                    Op::LoadV {
+                        dst: 0,
+                        hash: super::hash("a"),
+                    },
                     Op::LoadI { dst: 1, value: 45 },
                     expected_op,
                 ],
@@ -332,6 +727,7 @@ mod cc {
     }
 
     #[test]
+    #[allow(clippy::let_unit_value)]
     fn bin_nested() {
         let ast = Node {
             token: token!(Type::Asteriks),
@@ -340,7 +736,7 @@ mod cc {
                     token!(Type::Plus),
                     InnerNode::Bin {
                         lhs: Box::new(node!(token!(Type::Integer("2")), InnerNode::Atom)),
-                        rhs: Box::new(node!(token!(Type::Integer("3")), InnerNode::Atom)),
+                        rhs: Box::new(node!(token!(Type::Ident("b")), InnerNode::Ident)),
                     }
                 )),
                 rhs: Box::new(node!(
@@ -358,25 +754,640 @@ mod cc {
             cc.buf,
             vec![
                 Op::LoadI { dst: 0, value: 2 },
-                Op::LoadI { dst: 1, value: 3 },
+                Op::LoadV {
+                    dst: 1,
+                    hash: super::hash("b"),
+                },
                 Op::Add {
                     dst: 2,
                     lhs: 0,
                     rhs: 1,
                 },
-                Op::LoadI { dst: 1, value: 4 },
-                Op::LoadI { dst: 0, value: 1 },
-                Op::Sub {
-                    dst: 3,
-                    lhs: 1,
-                    rhs: 0,
-                },
+                // 4 - 1 is folded
+                Op::LoadI { dst: 1, value: 3 },
                 Op::Mul {
                     dst: 0,
                     lhs: 2,
-                    rhs: 3,
+                    rhs: 1,
                 },
             ]
         )
     }
+
+    #[test]
+    fn line_table() {
+        let at = |t, line, col| Token {
+            line,
+            col,
+            len: 0,
+            t,
+        };
+        let ast = node!(
+            at(Type::Plus, 1, 3),
+            InnerNode::Bin {
+                lhs: Box::new(node!(at(Type::Integer("2"), 1, 1), InnerNode::Atom)),
+                // a double, so the addition is not folded
+                rhs: Box::new(node!(at(Type::Double("3.5"), 2, 0), InnerNode::Atom)),
+            }
+        );
+        let mut cc = Cc::new();
+        cc.compile(ast).expect("Failed to compile node");
+        let vm = cc.finalize();
+        let span = |line, start, end| Some(Span { line, start, end });
+        assert_eq!(vm.span(0), span(1, 1, 2));
+        assert_eq!(vm.span(1), span(2, 0, 3));
+        assert_eq!(vm.span(2), span(1, 3, 4));
+    }
+
+    fn atom(t: Type<'static>) -> Node<'static> {
+        node!(token!(t), InnerNode::Atom)
+    }
+
+    /// compiles and runs `ast`, returns the value of its result register
+    fn eval(ast: Node<'static>) -> Value<'static> {
+        let mut cc = Cc::new();
+        let r = cc.cc(ast).expect("Failed to compile node");
+        cc.register.free(r);
+        let mut vm = cc.finalize();
+        vm.run().expect("Failed to run");
+        vm.registers[r as usize].expect("result register should be set")
+    }
+
+    #[test]
+    fn none_comparisons() {
+        let eq = |lhs, rhs| {
+            eval(node!(
+                token!(Type::Equal),
+                InnerNode::Bin {
+                    lhs: Box::new(atom(lhs)),
+                    rhs: Box::new(atom(rhs)),
+                }
+            ))
+        };
+        assert_eq!(eq(Type::None, Type::None), Value::True);
+        assert_eq!(eq(Type::None, Type::False), Value::False);
+        assert_eq!(eq(Type::Integer("0"), Type::None), Value::False);
+        assert_eq!(eq(Type::String("".into()), Type::None), Value::False);
+    }
+
+    #[test]
+    fn match_cases() {
+        let case = |cond, value| (atom(cond), atom(Type::Integer(value)));
+        let match_ = |cases, default: Option<&'static str>| {
+            node!(
+                token!(Type::Match),
+                InnerNode::Match {
+                    cases,
+                    default: default.map(|d| Box::new(atom(Type::Integer(d)))),
+                }
+            )
+        };
+
+        // none is falsy like false, every other value truthy
+        assert_eq!(
+            eval(match_(
+                vec![case(Type::None, "1"), case(Type::False, "2")],
+                Some("3")
+            )),
+            Value::Int(3)
+        );
+        assert_eq!(
+            eval(match_(
+                vec![case(Type::None, "1"), case(Type::Integer("0"), "2")],
+                Some("3")
+            )),
+            Value::Int(2)
+        );
+        // without a default, a match no case matched is none
+        assert_eq!(
+            eval(match_(vec![case(Type::False, "1")], None)),
+            Value::None
+        );
+        assert_eq!(eval(match_(vec![], None)), Value::None);
+    }
+
+    fn parse(src: &'static str) -> Node<'static> {
+        Parser::new(Lexer::new(src))
+            .and_then(|mut parser| parser.expr())
+            .expect("Failed to parse")
+    }
+
+    #[test]
+    fn operators() {
+        let tests = [
+            ("7 % 3", Value::Int(1)),
+            ("-7 % 3", Value::Int(-1)),
+            ("7.5 % 2.0", Value::Double(1.5)),
+            ("1 <= 1", Value::True),
+            ("2 <= 1", Value::False),
+            ("2.5 >= 2.5", Value::True),
+            ("2 >= 3", Value::False),
+            ("1 != 2", Value::True),
+            ("none != none", Value::False),
+            ("-(2 + 3)", Value::Int(-5)),
+            ("-2.5", Value::Double(-2.5)),
+            ("!false", Value::True),
+            ("!none", Value::True),
+            ("!0", Value::False),
+            ("!!true", Value::True),
+            // precedence end to end
+            ("1 + 2 * 3 - 4 % 3", Value::Int(6)),
+            ("-2 * -3 + 1", Value::Int(7)),
+            ("2 * 3 == 6 != false", Value::True),
+            ("!(1 < 2) == false", Value::True),
+            ("1 + 1 >= 2 == 3 - 1 <= 2", Value::True),
+        ];
+        for (src, expected) in tests {
+            assert_eq!(eval(parse(src)), expected, "{src}");
+        }
+    }
+
+    #[test]
+    fn short_circuits() {
+        let tests = [
+            ("true && 2", Value::Int(2)),
+            ("false && 2", Value::False),
+            ("none && 2", Value::None),
+            ("1 && 2 && 3", Value::Int(3)),
+            ("false || 2", Value::Int(2)),
+            ("none || false", Value::False),
+            ("1 || 2", Value::Int(1)),
+            ("false || none || 3", Value::Int(3)),
+            ("1 < 2 && 2 < 3", Value::True),
+            ("1 > 2 || 2 > 3", Value::False),
+            // rhs errors are never raised if rhs is skipped
+            ("false && 1 / 0", Value::False),
+            ("true || -true", Value::True),
+        ];
+        for (src, expected) in tests {
+            assert_eq!(eval(parse(src)), expected, "{src}");
+        }
+    }
+
+    #[test]
+    fn short_circuits_skip_side_effects() {
+        let threshold = |src| {
+            let mut cc = Cc::new();
+            let r = cc.cc(parse(src)).expect("Failed to compile node");
+            cc.register.free(r);
+            let mut vm = cc.finalize();
+            vm.run().expect("Failed to run");
+            vm.heap.threshold
+        };
+        let initial = crate::gc::INITIAL_THRESHOLD;
+        assert_eq!(
+            threshold("false && std::runtime::gc::set_threshold(1)"),
+            initial
+        );
+        assert_eq!(
+            threshold("1 == 1 || std::runtime::gc::set_threshold(1)"),
+            initial
+        );
+        assert_eq!(threshold("true && std::runtime::gc::set_threshold(1)"), 1);
+        assert_eq!(threshold("none || std::runtime::gc::set_threshold(1)"), 1);
+    }
+
+    #[test]
+    fn operator_errors() {
+        let run = |src| {
+            let mut cc = Cc::new();
+            let r = cc.cc(parse(src)).expect("Failed to compile node");
+            cc.register.free(r);
+            cc.finalize().run().expect_err("should fail").kind
+        };
+        assert_eq!(run("5 % 0"), ErrorKind::DivisionByZero);
+        assert_eq!(
+            run("-true"),
+            ErrorKind::Type {
+                op: "-",
+                lhs: "bool",
+                rhs: None
+            }
+        );
+        assert_eq!(
+            run("1 <= none"),
+            ErrorKind::Type {
+                op: "<=",
+                lhs: "int",
+                rhs: Some("none")
+            }
+        );
+    }
+
+    #[test]
+    fn folding() {
+        let ops = |src| {
+            let mut cc = Cc::new();
+            let r = cc.cc(parse(src)).expect("Failed to compile node");
+            cc.register.free(r);
+            cc.buf
+        };
+        for (src, value) in [
+            ("(2 + 3) * (4 - 1)", 15),
+            ("-(7 % 3)", -1),
+            ("1 << 3 | ~0 & 6 ^ 3", 13),
+            ("-1 >> 70", -1),
+            ("1 << 64", 0),
+        ] {
+            assert_eq!(ops(src), vec![Op::LoadI { dst: 0, value }], "{src}");
+        }
+        // errors and overflows are left to the vm
+        for src in [
+            "1 / 0",
+            "1 % 0",
+            "1 << -1",
+            "9223372036854775807 + 1",
+            "1 & 1.5",
+        ] {
+            assert!(ops(src).len() > 1, "{src} should not be folded");
+        }
+
+        // folding is bottom up, so a long left leaning chain is walked once
+        let chain = |head: &str| {
+            let src = format!("{head}{}", " + 1".repeat(200));
+            let mut cc = Cc::new();
+            let r = cc.cc(parse(src.leak())).expect("Failed to compile node");
+            cc.register.free(r);
+            cc.buf
+        };
+        assert_eq!(chain("0"), vec![Op::LoadI { dst: 0, value: 200 }]);
+        assert_eq!(chain("a").len(), 1 + 2 * 200);
+    }
+
+    #[test]
+    fn bitwise() {
+        let tests = [
+            ("12 & 10", Value::Int(8)),
+            ("12 | 10", Value::Int(14)),
+            ("12 ^ 10", Value::Int(6)),
+            ("~5", Value::Int(-6)),
+            ("1 << 62", Value::Int(1 << 62)),
+            ("-8 >> 1", Value::Int(-4)),
+            ("flags & 4 == 4", Value::True),
+            ("flags >> 1 & 1", Value::Int(0)),
+            ("~flags", Value::Int(-6)),
+            ("1 << flags", Value::Int(32)),
+            ("flags << 64", Value::Int(0)),
+            ("flags >> 99", Value::Int(0)),
+            ("-flags >> 99", Value::Int(-1)),
+            ("flags << 9223372036854775807", Value::Int(0)),
+        ];
+        for (src, expected) in tests {
+            // a variable, so the operations are not folded
+            assert_eq!(run_with(src, &[("flags", 5)]), Ok(expected), "{src}");
+        }
+    }
+
+    /// compiles `src` after defining `vars`, returns the vm and the result register
+    fn compile_with(src: &'static str, vars: &[(&'static str, i64)]) -> (Vm<'static>, u8) {
+        let mut cc = Cc::new();
+        for &(name, value) in vars {
+            let token = token!(Type::Ident(name));
+            cc.emit(Op::LoadI { dst: 0, value }, &token);
+            let hash = cc.hash(name);
+            cc.emit(Op::Let { hash, src: 0 }, &token);
+        }
+        let r = cc.cc(parse(src)).expect("Failed to compile node");
+        cc.register.free(r);
+        (cc.finalize(), r)
+    }
+
+    /// compiles `src` after defining `vars`, runs it and returns the value of its result
+    fn run_with(
+        src: &'static str,
+        vars: &[(&'static str, i64)],
+    ) -> Result<Value<'static>, ErrorKind> {
+        let (mut vm, r) = compile_with(src, vars);
+        vm.run().map_err(|e| e.kind)?;
+        Ok(vm.registers[r as usize].expect("result register is empty"))
+    }
+
+    /// like run_with, renders an int result as its digits, prefixed with `big` for big ints
+    fn run_int(src: &'static str, vars: &[(&'static str, i64)]) -> Result<String, ErrorKind> {
+        let (mut vm, r) = compile_with(src, vars);
+        vm.run().map_err(|e| e.kind)?;
+        Ok(match vm.registers[r as usize] {
+            Some(Value::Int(i)) => i.to_string(),
+            Some(Value::BigInt(big)) => format!("big {}", vm.heap.get(big)),
+            other => panic!("{src} should evaluate to an int, got {other:?}"),
+        })
+    }
+
+    /// ints promote to big ints on overflow and demote once they fit again, folded or not
+    #[test]
+    fn overflow() {
+        // source, result, whether the literal form is folded, which needs every step to fit
+        let tests = [
+            ("max + 1", "big 9223372036854775808", false),
+            ("min + -1", "big -9223372036854775809", false),
+            ("max - -1", "big 9223372036854775808", false),
+            ("min - 1", "big -9223372036854775809", false),
+            ("max * 2", "big 18446744073709551614", false),
+            ("min * -1", "big 9223372036854775808", false),
+            ("min / -1", "big 9223372036854775808", false),
+            ("-min", "big 9223372036854775808", false),
+            (
+                "max * max",
+                "big 85070591730234615847396907784232501249",
+                false,
+            ),
+            ("max + 1 - 1", "9223372036854775807", false),
+            ("-(-min)", "-9223372036854775808", false),
+            ("min * -1 / -1", "-9223372036854775808", false),
+            ("(max + 1) % 10", "8", false),
+            ("-(min + 1)", "9223372036854775807", true),
+            ("max + min", "-1", true),
+            ("max - 1 + 1", "9223372036854775807", true),
+            ("min / 1", "-9223372036854775808", true),
+            ("min % -1", "0", true),
+            ("max * -1", "-9223372036854775807", true),
+            ("min << 1", "0", true),
+        ];
+        for (src, expected, folded) in tests {
+            assert_eq!(
+                run_int(src, &[("max", i64::MAX), ("min", i64::MIN)]).as_deref(),
+                Ok(expected),
+                "{src}"
+            );
+
+            let literal = src
+                .replace("max", "9223372036854775807")
+                .replace("min", "(-9223372036854775807 - 1)")
+                .leak();
+            let mut cc = Cc::new();
+            let r = cc.cc(parse(literal)).expect("Failed to compile node");
+            cc.register.free(r);
+            assert_eq!(cc.buf.len() == 1, folded, "{literal}");
+            assert_eq!(run_int(literal, &[]).as_deref(), Ok(expected), "{literal}");
+        }
+    }
+
+    #[test]
+    fn big_ints() {
+        let tests = [
+            ("100000000000000000000", "big 100000000000000000000"),
+            ("-100000000000000000000", "big -100000000000000000000"),
+            ("-9223372036854775808", "-9223372036854775808"),
+            ("9223372036854775808 - 1", "9223372036854775807"),
+            ("100000000000000000000 / 10", "big 10000000000000000000"),
+            ("100000000000000000000 / 100", "1000000000000000000"),
+            ("-100000000000000000007 % 10", "-7"),
+            (
+                "99999999999999999999 * 99999999999999999999 / 99999999999999999999",
+                "big 99999999999999999999",
+            ),
+            ("18446744073709551616 - 18446744073709551616", "0"),
+        ];
+        for (src, expected) in tests {
+            assert_eq!(run_int(src, &[]).as_deref(), Ok(expected), "{src}");
+        }
+
+        let comparisons = [
+            ("100000000000000000000 > 9223372036854775807", Value::True),
+            ("-100000000000000000000 < -9223372036854775807", Value::True),
+            (
+                "100000000000000000000 <= 99999999999999999999",
+                Value::False,
+            ),
+            (
+                "99999999999999999999 + 1 == 100000000000000000000",
+                Value::True,
+            ),
+            ("99999999999999999999 != 99999999999999999999", Value::False),
+            (
+                "9223372036854775808 - 1 == 9223372036854775807",
+                Value::True,
+            ),
+            ("100000000000000000000 == 1", Value::False),
+        ];
+        for (src, expected) in comparisons {
+            assert_eq!(run_with(src, &[]), Ok(expected), "{src}");
+        }
+
+        assert_eq!(
+            run_with("100000000000000000000 / 0", &[]),
+            Err(ErrorKind::DivisionByZero)
+        );
+        assert_eq!(
+            run_with("100000000000000000000 & 1", &[]),
+            Err(ErrorKind::Type {
+                op: "&",
+                lhs: "bigint",
+                rhs: Some("int")
+            })
+        );
+        assert_eq!(
+            run_with("100000000000000000000 + \"a\"", &[]),
+            Err(ErrorKind::Type {
+                op: "+",
+                lhs: "bigint",
+                rhs: Some("str")
+            })
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let tests = [
+            ("\"a\" + \"b\" == \"ab\"", Value::True),
+            ("\"a\" + \"b\" + \"c\" != \"abc\"", Value::False),
+            ("\"apple\" < \"banana\"", Value::True),
+            ("\"a\" + \"b\" > \"a\"", Value::True),
+            ("\"B\" > \"a\"", Value::False),
+        ];
+        for (src, expected) in tests {
+            assert_eq!(run_with(src, &[]), Ok(expected), "{src}");
+        }
+        assert_eq!(
+            run_with("\"a\" < 1", &[]),
+            Err(ErrorKind::Type {
+                op: "<",
+                lhs: "str",
+                rhs: Some("int")
+            })
+        );
+    }
+
+    #[test]
+    fn interpolation() {
+        let string = |src| {
+            let (mut vm, r) = compile_with(src, &[("n", 2)]);
+            vm.run().expect("Failed to run");
+            let Some(value @ (Value::Str(_) | Value::String(_))) = vm.registers[r as usize] else {
+                panic!("{src} should evaluate to a string");
+            };
+            vm.str(&value).unwrap().to_string()
+        };
+        let tests = [
+            (
+                r#""hello {n}, you have {n + 1} items""#,
+                "hello 2, you have 3 items",
+            ),
+            (r#""{n}{n}""#, "22"),
+            (
+                r#""{1.5} {2.0} {true} {none} {"x"} {n == 2}""#,
+                "1.5 2.0 true none x true",
+            ),
+            (r#""{100000000000000000000 * n}""#, "200000000000000000000"),
+            (r#""a {"b {n} c"} d""#, "a b 2 c d"),
+            (r#""{"a" + "b"}\{n\}""#, "ab{n}"),
+        ];
+        for (src, expected) in tests {
+            assert_eq!(string(src), expected, "{src}");
+        }
+
+        // more parts than registers, concatenated in chunks
+        let src: String = (0..100).map(|i| format!("{{{i}}} ")).collect();
+        let expected: String = (0..100).map(|i| format!("{i} ")).collect();
+        assert_eq!(
+            string(format!("\"<{src}>\"").leak()),
+            format!("<{expected}>")
+        );
+    }
+
+    #[test]
+    fn bitwise_errors() {
+        let run = |src| {
+            let mut cc = Cc::new();
+            let r = cc.cc(parse(src)).expect("Failed to compile node");
+            cc.register.free(r);
+            cc.finalize().run().expect_err("should fail")
+        };
+        assert_eq!(run("1 << -1").kind, ErrorKind::NegativeShift(-1));
+        assert_eq!(
+            run("~1.5").kind,
+            ErrorKind::Type {
+                op: "~",
+                lhs: "double",
+                rhs: None
+            }
+        );
+        let err = run("0.5 + 1.0 & 2");
+        assert_eq!(
+            err.kind,
+            ErrorKind::Type {
+                op: "&",
+                lhs: "double",
+                rhs: Some("int")
+            }
+        );
+        // the span of the operator, not of the operands
+        assert_eq!(
+            err.trace[0].span,
+            Some(Span {
+                line: 0,
+                start: 10,
+                end: 11
+            })
+        );
+    }
+
+    /// std::runtime::gc::<name>(args)
+    fn gc_call(name: &'static str, args: Vec<Node<'static>>) -> Node<'static> {
+        node!(
+            token!(Type::Std),
+            InnerNode::Path {
+                members: vec![
+                    node!(token!(Type::Ident("runtime")), InnerNode::Ident),
+                    node!(token!(Type::Ident("gc")), InnerNode::Ident),
+                ],
+                leaf: Box::new(node!(token!(Type::Ident(name)), InnerNode::Call { args })),
+            }
+        )
+    }
+
+    #[test]
+    fn std_path() {
+        let mut cc = Cc::new();
+        let ast = gc_call(
+            "set_threshold",
+            vec![node!(token!(Type::Integer("4096")), InnerNode::Atom)],
+        );
+        cc.compile(ast).expect("Failed to compile node");
+        assert_eq!(
+            cc.buf,
+            vec![
+                Op::LoadI {
+                    dst: 1,
+                    value: 4096
+                },
+                Op::Mov { dst: 0, src: 1 },
+                Op::Sys {
+                    ptr: stdlib::resolve("runtime::gc::set_threshold").unwrap(),
+                    args_start: 0,
+                    args_len: 1
+                },
+            ]
+        );
+        cc.compile(gc_call("cycle", vec![]))
+            .expect("Failed to compile node");
+
+        let mut vm = cc.finalize();
+        vm.run().expect("Failed to run");
+        assert_eq!(vm.heap.threshold, 4096);
+        assert_eq!(vm.registers[0], Some(Value::Int(0)));
+        assert_eq!(vm.heap.stats.major, 1);
+    }
+
+    #[test]
+    fn unknown_std_path() {
+        let mut cc = Cc::new();
+        let err = cc
+            .compile(gc_call("frobnicate", vec![]))
+            .expect_err("should not compile");
+        assert_eq!(
+            err.to_string(),
+            "err: unknown builtin `std::runtime::gc::frobnicate` at l:0:0-10"
+        );
+    }
+
+    #[test]
+    fn too_many_std_arguments() {
+        for len in [crate::vm::REGISTER_COUNT + 1, u8::MAX as usize + 1] {
+            let mut cc = Cc::new();
+            let args = (0..len)
+                .map(|_| node!(token!(Type::Integer("1")), InnerNode::Atom))
+                .collect();
+            let err = cc
+                .compile(gc_call("cycle", args))
+                .expect_err("should not compile");
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "err: `std::runtime::gc::cycle` is called with {len} arguments, calls pass at most {} at l:0:0-5",
+                    crate::vm::REGISTER_COUNT
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_syntax() {
+        let tests = [
+            (
+                "let x = 1",
+                "err: `let` bindings are not supported by the compiler yet at l:0:4-5",
+            ),
+            (
+                "fn f(a) { a }",
+                "err: function definitions are not supported by the compiler yet at l:0:3-4",
+            ),
+            (
+                "square(25 5)",
+                "err: function calls are not supported by the compiler yet at l:0:0-6",
+            ),
+            (
+                "[1 2]",
+                "err: arrays are not supported by the compiler yet at l:0:0-1",
+            ),
+        ];
+        for (src, expected) in tests {
+            let ast = Parser::new(Lexer::new(src))
+                .and_then(Parser::parse)
+                .expect("Failed to parse")
+                .remove(0);
+            let err = Cc::new().compile(ast).expect_err("should not compile");
+            assert_eq!(err.to_string(), expected, "{src}");
+        }
+    }
 }
@@ -23,6 +23,20 @@ impl RegisterAllocator {
         })
     }
 
+    /// allocates `n` consecutive registers and returns the first, for the arguments of calls
+    pub fn alloc_range(&mut self, n: usize) -> u8 {
+        let start = (0..=vm::REGISTER_COUNT.saturating_sub(n))
+            .find(|&start| (start..start + n).all(|r| self.free.contains(&(r as u8))))
+            .unwrap_or_else(|| {
+                panic!("RegisterAllocator: out of registers, do open a bug report please")
+            });
+        #[cfg(feature = "trace")]
+        println!("RegisterAllocator::alloc_range(r{start}..r{})", start + n);
+        self.free
+            .retain(|&r| !(start..start + n).contains(&(r as usize)));
+        start as u8
+    }
+
     pub fn free(&mut self, r: u8) {
         #[cfg(feature = "trace")]
         println!("RegisterAllocator::free(r{r})");
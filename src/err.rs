@@ -1,3 +1,5 @@
+use std::fmt;
+
 use crate::{
     ast::Node,
     lex::{Token, Type},
@@ -9,21 +11,45 @@ pub struct PgError {
     line: usize,
     start: usize,
     end: usize,
+    /// purple garden stack trace of runtime errors as (function name, location), innermost frame
+    /// first, empty for compile time errors
+    trace: Vec<(String, Option<Span>)>,
 }
 
-impl From<&Token<'_>> for PgError {
+/// Location of a token in the input: its line and the column range it covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<&Token<'_>> for Span {
     fn from(value: &Token) -> Self {
         let len = match value.t {
-            Type::String(i) | Type::Ident(i) | Type::Double(i) | Type::Integer(i) => i.len(),
-            Type::True => 4,
+            Type::Ident(i) | Type::Double(i) | Type::Integer(i) => i.len(),
+            // escapes and raw delimiters make the literal longer than its contents
+            Type::String(_)
+            | Type::InterpolationStart(_)
+            | Type::InterpolationMiddle(_)
+            | Type::InterpolationEnd(_) => value.len,
+            Type::True | Type::None => 4,
             Type::False | Type::Match => 5,
             Type::Let | Type::Std | Type::For => 3,
-            Type::Fn | Type::DoubleColon => 2,
+            Type::Fn
+            | Type::DoubleColon
+            | Type::Equal
+            | Type::NotEqual
+            | Type::LessEqual
+            | Type::GreaterEqual
+            | Type::ShiftLeft
+            | Type::ShiftRight
+            | Type::DoubleAmpersand
+            | Type::DoublePipe => 2,
             // all others are a single byte long
             _ => 1,
         };
-        PgError {
-            msg: None,
+        Span {
             line: value.line,
             start: value.col,
             end: value.col + len,
@@ -31,6 +57,24 @@ impl From<&Token<'_>> for PgError {
     }
 }
 
+impl From<Span> for PgError {
+    fn from(value: Span) -> Self {
+        PgError {
+            msg: None,
+            line: value.line,
+            start: value.start,
+            end: value.end,
+            trace: vec![],
+        }
+    }
+}
+
+impl From<&Token<'_>> for PgError {
+    fn from(value: &Token) -> Self {
+        Span::from(value).into()
+    }
+}
+
 impl From<&Node<'_>> for PgError {
     fn from(value: &Node<'_>) -> Self {
         (&value.token).into()
@@ -38,15 +82,24 @@ impl From<&Node<'_>> for PgError {
 }
 
 impl PgError {
+    pub fn new(msg: impl Into<String>, line: usize, start: usize, end: usize) -> Self {
+        PgError {
+            msg: Some(msg.into()),
+            line,
+            start,
+            end,
+            trace: vec![],
+        }
+    }
+
     // TODO: replace with writing to some kind of std::writer
     pub fn render(self) {
-        println!(
-            "err: {} at l:{}:{}-{}",
-            self.msg.unwrap_or_default(),
-            self.line,
-            self.start,
-            self.end
-        );
+        println!("{self}");
+    }
+
+    pub fn with_trace(mut self, trace: Vec<(String, Option<Span>)>) -> Self {
+        self.trace = trace;
+        self
     }
 
     pub fn with_msg(msg: impl Into<String>, from: impl Into<PgError>) -> Self {
@@ -55,3 +108,27 @@ impl PgError {
         conv
     }
 }
+
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "err: {} at l:{}:{}-{}",
+            self.msg.as_deref().unwrap_or_default(),
+            self.line,
+            self.start,
+            self.end
+        )?;
+        for (name, span) in &self.trace {
+            match span {
+                Some(span) => write!(
+                    f,
+                    "\n    at {name} (l:{}:{}-{})",
+                    span.line, span.start, span.end
+                )?,
+                None => write!(f, "\n    at {name}")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+//! The young generation of Collector::Generational. Objects are bump allocated into the nursery,
+//! a contiguous Vec, until it holds NURSERY_SIZE bytes. A minor collection then copies every
+//! nursery object reachable from the roots or from a remembered old object into the old
+//! generation, rewrites the handles pointing to it and resets the nursery. Old objects only
+//! reference young ones if Heap::barrier recorded them in Heap::remembered.
+
+use crate::{
+    gc::{Heap, Object},
+    vm::Value,
+};
+
+/// Nursery bytes a minor collection is triggered at
+pub const NURSERY_SIZE: usize = 256 * 1024;
+
+/// set in the handles of nursery objects, the remaining bits are the offset into Heap::nursery
+const YOUNG: u32 = 1 << 31;
+
+/// forward entry of a nursery object not yet copied
+const UNFORWARDED: u32 = u32::MAX;
+
+pub(super) fn is_young(idx: u32) -> bool {
+    idx & YOUNG != 0
+}
+
+pub(super) fn offset(idx: u32) -> usize {
+    (idx & !YOUNG) as usize
+}
+
+/// handle index of the nursery object at `offset`
+pub(super) fn young(offset: usize) -> u32 {
+    let idx = offset as u32;
+    assert!(!is_young(idx), "Heap: nursery exceeds {YOUNG} objects");
+    idx | YOUNG
+}
+
+/// points the handle in `value` at `idx`
+fn relocate(value: &mut Value, idx: u32) {
+    match value {
+        Value::BigInt(gc) => gc.idx = idx,
+        Value::String(gc) => gc.idx = idx,
+        Value::Arr(gc) => gc.idx = idx,
+        Value::Obj(gc) => gc.idx = idx,
+        Value::Weak(gc) => gc.idx = idx,
+        Value::Host(gc) => gc.idx = idx,
+        _ => unreachable!("relocate: {value:?} is not a heap reference"),
+    }
+}
+
+/// State of a minor collection
+struct Evacuation<'h> {
+    /// Heap::nursery_base when the collection started
+    base: usize,
+    /// the nursery objects not copied yet
+    young: Vec<Option<Object<'h>>>,
+    /// old generation slot of each copied nursery object
+    forward: Vec<u32>,
+    /// old objects whose references still need evacuating
+    scan: Vec<u32>,
+    /// copied or remembered weak references, fixed up once every survivor is copied
+    weaks: Vec<u32>,
+}
+
+impl Evacuation<'_> {
+    /// index into young and forward of the nursery handle `idx`
+    fn index(&self, idx: u32) -> usize {
+        offset(idx)
+            .checked_sub(self.base)
+            .expect("Heap::minor: reference to a freed nursery object, missing root?")
+    }
+}
+
+impl<'h> Heap<'h> {
+    pub(super) fn bump(&mut self, object: Object<'h>) -> u32 {
+        self.nursery_bytes += object.size();
+        self.nursery.push(object);
+        young(self.nursery_base + self.nursery.len() - 1)
+    }
+
+    /// index into Heap::nursery of the nursery handle `idx`
+    pub(super) fn nursery_index(&self, idx: u32) -> usize {
+        offset(idx)
+            .checked_sub(self.nursery_base)
+            .filter(|index| *index < self.nursery.len())
+            .unwrap_or_else(|| panic!("Gc: use of a freed nursery object {idx}, missing root?"))
+    }
+
+    /// Copies the nursery objects reachable from `roots` and from remembered old objects into the
+    /// old generation, rewriting every reference to them, and empties the nursery
+    pub(super) fn minor<'r>(&mut self, roots: impl Iterator<Item = &'r mut Value<'h>>)
+    where
+        'h: 'r,
+    {
+        let young: Vec<_> = self.nursery.drain(..).map(Some).collect();
+        self.nursery_bytes = 0;
+        let base = self.nursery_base;
+        // keeps handles to freed nursery objects from aliasing the next nursery generation
+        if cfg!(feature = "gc-stress") {
+            self.nursery_base += young.len();
+        }
+        let mut evacuation = Evacuation {
+            base,
+            forward: vec![UNFORWARDED; young.len()],
+            young,
+            scan: vec![],
+            weaks: vec![],
+        };
+
+        for root in roots {
+            self.evacuate(&mut evacuation, root);
+        }
+        for idx in std::mem::take(&mut self.remembered) {
+            self.slots[idx as usize].remembered = false;
+            evacuation.scan.push(idx);
+        }
+        // copied objects may reference further nursery objects
+        while let Some(idx) = evacuation.scan.pop() {
+            let slot = &mut self.slots[idx as usize];
+            let Some(mut object) = slot.object.take() else {
+                continue;
+            };
+            if let Object::Weak(_) = object {
+                evacuation.weaks.push(idx);
+            }
+            for value in object.values_mut() {
+                self.evacuate(&mut evacuation, value);
+            }
+            self.slots[idx as usize].object = Some(object);
+        }
+
+        // weak references follow their target if it was copied and are cleared otherwise
+        for idx in std::mem::take(&mut evacuation.weaks) {
+            let Some(Object::Weak(weak)) = &mut self.slots[idx as usize].object else {
+                continue;
+            };
+            let Some(target) = weak.target_mut() else {
+                continue;
+            };
+            match target.heap_ref().filter(|idx| is_young(*idx)) {
+                Some(young) if evacuation.forward[evacuation.index(young)] != UNFORWARDED => {
+                    relocate(target, evacuation.forward[evacuation.index(young)])
+                }
+                Some(_) => weak.clear(),
+                None => {}
+            }
+        }
+        for object in evacuation.young.into_iter().flatten() {
+            self.release(object);
+        }
+    }
+
+    fn evacuate(&mut self, evacuation: &mut Evacuation<'h>, value: &mut Value<'h>) {
+        let Some(idx) = value.heap_ref().filter(|idx| is_young(*idx)) else {
+            return;
+        };
+        let offset = evacuation.index(idx);
+        if evacuation.forward[offset] == UNFORWARDED {
+            let object = evacuation.young[offset]
+                .take()
+                .expect("Heap::minor: nursery object copied twice");
+            let promoted = self.insert(object);
+            evacuation.forward[offset] = promoted;
+            evacuation.scan.push(promoted);
+        }
+        relocate(value, evacuation.forward[offset]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::{Array, Collector, Heap, generational::is_young},
+        vm::Value,
+    };
+
+    #[test]
+    fn minor_collection_promotes_survivors() {
+        let mut heap = Heap::new(Collector::Generational);
+        let inner = heap.alloc(String::from("inner"));
+        let array = heap.alloc(Array::from([Value::String(inner)]));
+        heap.alloc(String::from("garbage"));
+        assert!(is_young(array.idx()));
+
+        let mut roots = [Value::Arr(array)];
+        heap.collect(roots.iter_mut());
+        assert_eq!(heap.stats.minor, 1);
+        assert_eq!(heap.stats.major, 0);
+        assert_eq!(heap.len(), 2);
+
+        let Value::Arr(array) = roots[0] else {
+            unreachable!()
+        };
+        assert!(!is_young(array.idx()));
+        let Value::String(inner) = heap.get(array)[0] else {
+            panic!("the array should still hold the string");
+        };
+        assert!(!is_young(inner.idx()));
+        assert_eq!(heap.get(inner), "inner");
+    }
+
+    #[test]
+    fn remembered_set() {
+        let mut heap = Heap::new(Collector::Generational);
+        let old = heap.alloc(Array::new());
+        let mut roots = [Value::Arr(old)];
+        heap.collect(roots.iter_mut());
+        let Value::Arr(old) = roots[0] else {
+            unreachable!()
+        };
+
+        // only reachable through the old array
+        let young = Value::String(heap.alloc(String::from("young")));
+        heap.get_mut(old).push(young);
+        heap.barrier(old, &young);
+        heap.collect(roots.iter_mut());
+
+        let Value::String(promoted) = heap.get(old)[0] else {
+            unreachable!()
+        };
+        assert!(!is_young(promoted.idx()));
+        assert_eq!(heap.get(promoted), "young");
+        assert!(heap.remembered.is_empty());
+    }
+
+    #[test]
+    fn full_collection_after_minor() {
+        let mut heap = Heap::new(Collector::Generational);
+        let mut roots = [Value::Arr(heap.alloc(Array::new()))];
+        heap.alloc(String::from("garbage"));
+        heap.collect(roots.iter_mut());
+        // both were copied or dropped, the old generation only holds the array
+        assert_eq!(heap.len(), 1);
+
+        roots[0] = Value::True;
+        heap.collect_full(roots.iter_mut());
+        assert!(heap.is_empty());
+        assert_eq!(heap.stats.major, 1);
+    }
+}
@@ -0,0 +1,268 @@
+//! Collector::Incremental splits a mark and sweep cycle into slices of at most Heap::budget units
+//! of work, one slice runs whenever Vm::alloc finds a collection due and, while a cycle is
+//! running, every vm::SLICE_INTERVAL instructions, so the program runs between slices and a cycle
+//! finishes even if the program stops allocating.
+//!
+//! Marking is tri-color: unmarked objects are white, marked objects still in Heap::grey are grey,
+//! marked objects no longer in it are black. Since the program mutates the heap between slices,
+//! Heap::barrier shades every object stored into a container while marking (an insertion
+//! barrier), so no black object ever points to a white one. Registers and variables are written
+//! without barriers, therefore the roots are shaded again once the grey set runs empty, marking
+//! only finishes once that turns up nothing the slice can't scan within its budget, otherwise the
+//! next slices continue with the objects this uncovered. Objects allocated during
+//! marking start out grey, objects allocated during sweeping in not yet swept slots start out
+//! marked, so neither is freed by the running cycle. Weak references to objects still white once
+//! marking finished are cleared before sweeping starts.
+
+use crate::{
+    gc::{Heap, generational},
+    vm::Value,
+};
+
+/// Default for Heap::budget
+pub const DEFAULT_BUDGET: usize = 256;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Phase {
+    /// no cycle running
+    #[default]
+    Idle,
+    Mark,
+    /// slots below cursor are swept
+    Sweep {
+        cursor: usize,
+    },
+}
+
+impl<'h> Heap<'h> {
+    /// marks the old object `idx` grey if it is white
+    pub(super) fn shade(&mut self, idx: u32) {
+        if generational::is_young(idx) {
+            return;
+        }
+        let slot = &mut self.slots[idx as usize];
+        if !slot.marked {
+            slot.marked = true;
+            self.grey.push(idx);
+        }
+    }
+
+    pub(super) fn allocated_during_cycle(&mut self, idx: u32) {
+        match self.phase {
+            Phase::Idle => {}
+            // its initial contents may be white
+            Phase::Mark => self.shade(idx),
+            Phase::Sweep { cursor } => self.slots[idx as usize].marked = idx as usize >= cursor,
+        }
+    }
+
+    /// Drops the state of a running cycle, for full collections
+    pub(super) fn abort_cycle(&mut self) {
+        if self.phase == Phase::Idle {
+            return;
+        }
+        for slot in &mut self.slots {
+            slot.marked = false;
+        }
+        self.grey.clear();
+        self.phase = Phase::Idle;
+    }
+
+    /// Processes the grey set until empty or `budget` objects were scanned, returns the unused
+    /// budget
+    fn mark(&mut self, mut budget: usize) -> usize {
+        while budget > 0
+            && let Some(idx) = self.grey.pop()
+        {
+            let refs: Vec<u32> = self
+                .object(idx)
+                .values()
+                .filter_map(Value::heap_ref)
+                .collect();
+            for child in refs {
+                self.shade(child);
+            }
+            budget -= 1;
+        }
+        budget
+    }
+
+    /// Runs the next slice of the current cycle or starts a new one
+    pub(super) fn slice<'r>(&mut self, roots: impl Iterator<Item = &'r Value<'h>>)
+    where
+        'h: 'r,
+    {
+        let mut budget = self.budget.max(1);
+        if self.phase == Phase::Idle {
+            #[cfg(feature = "trace")]
+            println!(
+                "Heap::slice(start, live={}, bytes={})",
+                self.len(),
+                self.bytes
+            );
+            let roots: Vec<u32> = roots.filter_map(Value::heap_ref).collect();
+            for root in roots {
+                self.shade(root);
+            }
+            self.phase = Phase::Mark;
+            return;
+        }
+
+        if self.phase == Phase::Mark {
+            budget = self.mark(budget);
+            if !self.grey.is_empty() {
+                return;
+            }
+            // the roots may reference white objects moved out of the heap, the slice can only
+            // finish marking if it scans everything they uncover, the mutator doesn't run in
+            // between
+            let roots: Vec<u32> = roots.filter_map(Value::heap_ref).collect();
+            for root in roots {
+                self.shade(root);
+            }
+            budget = self.mark(budget);
+            if !self.grey.is_empty() {
+                return;
+            }
+            self.clear_weaks(|heap, idx| heap.slots[idx as usize].marked);
+            self.phase = Phase::Sweep { cursor: 0 };
+        }
+
+        if let Phase::Sweep { cursor } = self.phase {
+            let end = (cursor + budget).min(self.slots.len());
+            for idx in cursor..end {
+                let slot = &mut self.slots[idx];
+                if slot.marked {
+                    slot.marked = false;
+                } else if let Some(object) = slot.object.take() {
+                    self.bytes = self.bytes.saturating_sub(object.size());
+                    self.free_slot(idx as u32);
+                    self.release(object);
+                }
+            }
+            if end < self.slots.len() {
+                self.phase = Phase::Sweep { cursor: end };
+                return;
+            }
+            self.phase = Phase::Idle;
+            self.threshold = self.min_threshold.max(self.bytes * 2);
+            self.stats.major += 1;
+        }
+    }
+
+    /// whether an incremental cycle is running
+    pub fn cycle_running(&self) -> bool {
+        self.phase != Phase::Idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::{Array, Collector, Heap},
+        vm::Value,
+    };
+
+    fn heap() -> Heap<'static> {
+        Heap {
+            budget: 1,
+            threshold: 0,
+            ..Heap::new(Collector::Incremental)
+        }
+    }
+
+    /// runs slices until the current cycle finished, returns how many it took
+    fn finish(heap: &mut Heap<'static>, roots: &mut [Value<'static>]) -> usize {
+        let mut slices = 1;
+        heap.collect(roots.iter_mut());
+        while heap.cycle_running() {
+            heap.collect(roots.iter_mut());
+            slices += 1;
+        }
+        slices
+    }
+
+    #[test]
+    fn cycle_is_split_into_slices() {
+        let mut heap = heap();
+        let strings: Array = (0..4)
+            .map(|i| Value::String(heap.alloc(i.to_string())))
+            .collect();
+        let mut roots = [Value::Arr(heap.alloc(strings))];
+        heap.alloc(String::from("garbage"));
+
+        let slices = finish(&mut heap, &mut roots);
+        // start, 5 objects to mark, 6 slots to sweep
+        assert!(slices > 5, "took {slices} slices");
+        assert_eq!(heap.stats.slices, slices);
+        assert_eq!(heap.stats.major, 1);
+        assert_eq!(heap.len(), 5);
+    }
+
+    #[test]
+    fn barrier_keeps_stored_objects_alive() {
+        let mut heap = heap();
+        let string = Value::String(heap.alloc(String::from("moved")));
+        let from = heap.alloc(Array::from([string]));
+        let to = heap.alloc(Array::new());
+        let mut roots = [Value::Arr(from), Value::Arr(to)];
+
+        // start, then scan `to`, making it black while the string is still white
+        heap.collect(roots.iter_mut());
+        heap.collect(roots.iter_mut());
+        assert!(heap.cycle_running());
+
+        // move the string from the grey array into the black one
+        heap.get_mut(from)[0] = Value::False;
+        heap.get_mut(to).push(string);
+        heap.barrier(to, &string);
+        finish(&mut heap, &mut roots);
+
+        assert_eq!(heap.len(), 3);
+        let Value::String(moved) = heap.get(to)[0] else {
+            unreachable!()
+        };
+        assert_eq!(heap.get(moved), "moved");
+    }
+
+    #[test]
+    fn allocations_during_a_cycle_survive_it() {
+        let mut heap = heap();
+        let mut roots = [Value::Arr(heap.alloc(Array::new()))];
+        heap.collect(roots.iter_mut());
+        let during = heap.alloc(String::from("during"));
+        while heap.cycle_running() {
+            heap.collect(roots.iter_mut());
+        }
+        assert_eq!(heap.get(during), "during");
+    }
+
+    #[test]
+    fn final_remark_is_bounded() {
+        let mut heap = heap();
+        let mut roots = [Value::Arr(heap.alloc(Array::new()))];
+        heap.collect(roots.iter_mut());
+
+        // a root the cycle hasn't seen, with more children than a slice may scan
+        let strings: Array = (0..8)
+            .map(|i| Value::String(heap.alloc(i.to_string())))
+            .collect();
+        let moved = heap.alloc(strings);
+        // allocated while marking, so they are grey already, whiten everything as if they existed
+        // before the cycle started and only became reachable from a root since
+        for slot in &mut heap.slots {
+            slot.marked = false;
+        }
+        heap.grey.clear();
+        let mut roots = [roots[0], Value::Arr(moved)];
+
+        heap.collect(roots.iter_mut());
+        assert!(heap.cycle_running());
+        assert!(
+            !heap.grey.is_empty(),
+            "the re-mark should stop at the budget"
+        );
+        finish(&mut heap, &mut roots);
+        assert_eq!(heap.len(), 10);
+    }
+}
@@ -1,7 +1,613 @@
-use std::marker::PhantomData;
+use std::{
+    collections::HashMap,
+    fmt, hash,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+use crate::vm::{BigInt, Value};
+
+/// nursery and copying promotion of the generational collector
+mod generational;
+/// tri-color marking in bounded slices
+mod incremental;
+/// JSON export of the object graph
+mod snapshot;
+/// heap verification and the gc-stress feature
+mod stress;
+/// weak references and finalized host objects
+mod weak;
+
+pub use generational::NURSERY_SIZE;
+pub use incremental::DEFAULT_BUDGET;
+use incremental::Phase;
+pub use weak::{Host, Weak};
+
+/// Handle to an object managed by a [Heap], only valid for the heap that allocated it and only
+/// as long as the object is reachable from the roots passed to [Heap::collect]
 pub struct Gc<T: ?Sized> {
-    // TODO:
-    _phantom: PhantomData<T>,
+    idx: u32,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+// derives would require T to implement these traits, a handle is just an index though
+
+impl<T: ?Sized> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Gc<T> {}
+
+impl<T: ?Sized> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.idx == other.idx
+    }
+}
+
+impl<T: ?Sized> Eq for Gc<T> {}
+
+impl<T: ?Sized> hash::Hash for Gc<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.idx.hash(state);
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gc({})", self.idx)
+    }
+}
+
+impl<T: ?Sized> Gc<T> {
+    /// untyped slot index of the handle
+    pub fn idx(&self) -> u32 {
+        self.idx
+    }
+
+    /// handle to the slot index `idx`, which has to hold a T
+    pub(crate) fn from_idx(idx: u32) -> Self {
+        Gc {
+            idx,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+pub type Array<'h> = Vec<Value<'h>>;
+pub type Map<'h> = HashMap<String, Value<'h>>;
+
+/// Everything the heap manages
+#[derive(Debug)]
+pub enum Object<'h> {
+    Array(Array<'h>),
+    Map(Map<'h>),
+    String(String),
+    BigInt(BigInt),
+    Weak(Weak<'h>),
+    Host(Host),
+}
+
+impl<'h> Object<'h> {
+    /// approximation of the bytes the object keeps alive, used for collection thresholds
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + match self {
+                Object::Array(a) => a.capacity() * std::mem::size_of::<Value>(),
+                Object::Map(m) => {
+                    m.capacity() * (std::mem::size_of::<String>() + std::mem::size_of::<Value>())
+                        + m.keys().map(String::len).sum::<usize>()
+                }
+                Object::String(s) => s.capacity(),
+                Object::BigInt(i) => i.size(),
+                Object::Weak(_) | Object::Host(_) => 0,
+            }
+    }
+
+    /// the values the object holds, without the target of a weak reference
+    fn values(&self) -> Box<dyn Iterator<Item = &Value<'h>> + '_> {
+        match self {
+            Object::Array(a) => Box::new(a.iter()),
+            Object::Map(m) => Box::new(m.values()),
+            Object::String(_) | Object::BigInt(_) | Object::Weak(_) | Object::Host(_) => {
+                Box::new(std::iter::empty())
+            }
+        }
+    }
+
+    /// whether the object, including a weak target, references a nursery object
+    fn references_young(&self) -> bool {
+        let weak = match self {
+            Object::Weak(weak) => weak.get(),
+            _ => None,
+        };
+        self.values()
+            .chain(weak.as_ref())
+            .filter_map(Value::heap_ref)
+            .any(generational::is_young)
+    }
+
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut Value<'h>> + '_> {
+        match self {
+            Object::Array(a) => Box::new(a.iter_mut()),
+            Object::Map(m) => Box::new(m.values_mut()),
+            Object::String(_) | Object::BigInt(_) | Object::Weak(_) | Object::Host(_) => {
+                Box::new(std::iter::empty())
+            }
+        }
+    }
+}
+
+/// Types a [Gc] can point to, maps them into and out of [Object] and their handles into and out
+/// of [Value]
+pub trait Managed<'h>: Sized {
+    fn into_object(self) -> Object<'h>;
+    fn from_object<'o>(o: &'o Object<'h>) -> &'o Self;
+    fn from_object_mut<'o>(o: &'o mut Object<'h>) -> &'o mut Self;
+    fn into_value(gc: Gc<Self>) -> Value<'h>;
+    fn from_value(value: Value<'h>) -> Gc<Self>;
+}
+
+macro_rules! managed {
+    ($type:ty, $variant:ident, $value:ident) => {
+        impl<'h> Managed<'h> for $type {
+            fn into_object(self) -> Object<'h> {
+                Object::$variant(self)
+            }
+
+            fn into_value(gc: Gc<Self>) -> Value<'h> {
+                Value::$value(gc)
+            }
+
+            fn from_value(value: Value<'h>) -> Gc<Self> {
+                match value {
+                    Value::$value(gc) => gc,
+                    _ => unreachable!("Gc: value does not hold a handle of this type"),
+                }
+            }
+
+            fn from_object<'o>(o: &'o Object<'h>) -> &'o Self {
+                match o {
+                    Object::$variant(inner) => inner,
+                    _ => unreachable!("Gc: handle type does not match the object"),
+                }
+            }
+
+            fn from_object_mut<'o>(o: &'o mut Object<'h>) -> &'o mut Self {
+                match o {
+                    Object::$variant(inner) => inner,
+                    _ => unreachable!("Gc: handle type does not match the object"),
+                }
+            }
+        }
+    };
+}
+
+managed!(Array<'h>, Array, Arr);
+managed!(Map<'h>, Map, Obj);
+managed!(String, String, String);
+managed!(BigInt, BigInt, BigInt);
+managed!(Weak<'h>, Weak, Weak);
+managed!(Host, Host, Host);
+
+#[derive(Debug)]
+struct Slot<'h> {
+    object: Option<Object<'h>>,
+    marked: bool,
+    /// whether the slot is in Heap::remembered
+    remembered: bool,
+}
+
+/// Old generation size the first full collection is triggered at
+pub const INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+/// Strategy a [Heap] allocates and collects with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Collector {
+    /// every collection marks and sweeps the whole heap
+    #[default]
+    MarkSweep,
+    /// new objects are bump allocated into a nursery, minor collections copy its survivors into
+    /// the old generation, which is only marked and swept once it grew past Heap::threshold
+    Generational,
+    /// like MarkSweep, but marking and sweeping are split into slices of at most Heap::budget
+    /// units of work, interleaved with the allocations of the program
+    Incremental,
+}
+
+/// Counters for comparing collectors
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// collections that only evacuated the nursery
+    pub minor: usize,
+    /// collections that marked and swept the whole heap, including finished incremental cycles
+    pub major: usize,
+    /// incremental marking and sweeping slices
+    pub slices: usize,
+    /// time spent in collections and slices
+    pub total_pause: Duration,
+    /// longest single collection or slice
+    pub max_pause: Duration,
+}
+
+impl Stats {
+    pub fn collections(&self) -> usize {
+        self.minor + self.major
+    }
+}
+
+/// Size of a heap at one point in time, together with its Stats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Report {
+    /// approximate bytes of all objects, see Object::size
+    pub bytes: usize,
+    pub objects: usize,
+    pub threshold: usize,
+    pub stats: Stats,
+}
+
+/// Managed heap: old objects live in slots, freed slots are reused for later allocations, a full
+/// collection marks everything reachable from the roots and frees the rest. With
+/// Collector::Generational new objects start out in the nursery, see the generational module.
+#[derive(Debug)]
+pub struct Heap<'h> {
+    pub collector: Collector,
+    slots: Vec<Slot<'h>>,
+    free: Vec<u32>,
+    /// freed slots never reused, see the stress module
+    poisoned: usize,
+    /// approximate bytes allocated in the old generation, see Object::size
+    bytes: usize,
+    /// run a full collection once bytes exceeds this
+    pub threshold: usize,
+    /// lower bound for threshold, which collections set to twice the surviving bytes
+    min_threshold: usize,
+    /// young objects, handles to them have the generational::YOUNG bit set
+    nursery: Vec<Object<'h>>,
+    /// offset of the first nursery object, only advanced with the gc-stress feature
+    nursery_base: usize,
+    nursery_bytes: usize,
+    /// old objects a nursery reference was stored into since the last minor collection
+    remembered: Vec<u32>,
+    /// objects or slots an incremental slice processes at most
+    pub budget: usize,
+    phase: Phase,
+    /// marked objects whose references were not marked yet
+    grey: Vec<u32>,
+    /// freed host objects whose finalizer did not run yet
+    finalizable: Vec<Host>,
+    pub stats: Stats,
+}
+
+impl Default for Heap<'_> {
+    fn default() -> Self {
+        Heap::new(Collector::default())
+    }
+}
+
+impl<'h> Heap<'h> {
+    pub fn new(collector: Collector) -> Self {
+        Heap {
+            collector,
+            slots: vec![],
+            free: vec![],
+            poisoned: 0,
+            bytes: 0,
+            threshold: INITIAL_THRESHOLD,
+            min_threshold: INITIAL_THRESHOLD,
+            nursery: vec![],
+            nursery_base: 0,
+            nursery_bytes: 0,
+            remembered: vec![],
+            budget: DEFAULT_BUDGET,
+            phase: Phase::Idle,
+            grey: vec![],
+            finalizable: vec![],
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn alloc<T: Managed<'h>>(&mut self, value: T) -> Gc<T> {
+        let object = value.into_object();
+        let idx = match self.collector {
+            Collector::MarkSweep | Collector::Incremental => {
+                // the nursery is only populated if the collector was switched, then new old
+                // objects may reference young ones
+                let young = !self.nursery.is_empty() && object.references_young();
+                let idx = self.insert(object);
+                if young {
+                    self.slots[idx as usize].remembered = true;
+                    self.remembered.push(idx);
+                }
+                idx
+            }
+            Collector::Generational => self.bump(object),
+        };
+        Gc {
+            idx,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// places `object` into the old generation
+    fn insert(&mut self, object: Object<'h>) -> u32 {
+        self.bytes += object.size();
+        let slot = Slot {
+            object: Some(object),
+            marked: false,
+            remembered: false,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx as usize] = slot;
+                idx
+            }
+            None => {
+                self.slots.push(slot);
+                (self.slots.len() - 1) as u32
+            }
+        };
+        self.allocated_during_cycle(idx);
+        idx
+    }
+
+    fn object(&self, idx: u32) -> &Object<'h> {
+        if generational::is_young(idx) {
+            return &self.nursery[self.nursery_index(idx)];
+        }
+        self.slots[idx as usize]
+            .object
+            .as_ref()
+            .unwrap_or_else(|| panic!("Gc: use of a freed object {idx}, missing root?"))
+    }
+
+    pub fn get<T: Managed<'h>>(&self, gc: Gc<T>) -> &T {
+        T::from_object(self.object(gc.idx))
+    }
+
+    /// Values stored into the object have to go through Heap::barrier
+    pub fn get_mut<T: Managed<'h>>(&mut self, gc: Gc<T>) -> &mut T {
+        let idx = gc.idx;
+        if generational::is_young(idx) {
+            let index = self.nursery_index(idx);
+            return T::from_object_mut(&mut self.nursery[index]);
+        }
+        T::from_object_mut(
+            self.slots[idx as usize]
+                .object
+                .as_mut()
+                .unwrap_or_else(|| panic!("Gc: use of a freed object {idx}, missing root?")),
+        )
+    }
+
+    /// Write barrier, has to be called whenever `value` is stored into `container`, so minor
+    /// collections find nursery objects that are only referenced by old objects
+    pub fn barrier<T>(&mut self, container: Gc<T>, value: &Value<'h>) {
+        if self.phase == Phase::Mark
+            && let Some(target) = value.heap_ref()
+        {
+            self.shade(target);
+        }
+        let idx = container.idx;
+        if generational::is_young(idx) || !value.heap_ref().is_some_and(generational::is_young) {
+            return;
+        }
+        let slot = &mut self.slots[idx as usize];
+        if !slot.remembered {
+            slot.remembered = true;
+            self.remembered.push(idx);
+        }
+    }
+
+    /// Accounts for `bytes` `container` grew by after its allocation, for instance by Op::Append
+    pub fn grew<T>(&mut self, container: Gc<T>, bytes: usize) {
+        if generational::is_young(container.idx) {
+            self.nursery_bytes += bytes;
+        } else {
+            self.bytes += bytes;
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes + self.nursery_bytes
+    }
+
+    /// number of live objects
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len() - self.poisoned + self.nursery.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Collect once the old generation holds `bytes`, collections never lower the threshold below
+    /// this
+    pub fn set_threshold(&mut self, bytes: usize) {
+        self.threshold = bytes;
+        self.min_threshold = bytes;
+    }
+
+    pub fn report(&self) -> Report {
+        Report {
+            bytes: self.bytes(),
+            objects: self.len(),
+            threshold: self.threshold,
+            stats: self.stats,
+        }
+    }
+
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "gc-stress")
+            || self.phase != Phase::Idle
+            || self.nursery_bytes >= NURSERY_SIZE
+            || self.bytes >= self.threshold
+    }
+
+    /// Runs the collection that is due: with Collector::Incremental the next slice of the current
+    /// cycle, otherwise evacuates the nursery and marks and sweeps the whole heap if the collector
+    /// is Collector::MarkSweep or the old generation outgrew Heap::threshold.
+    ///
+    /// Minor collections move objects, the handles in `roots` are rewritten, all other handles
+    /// are invalid afterwards.
+    pub fn collect<'r>(&mut self, roots: impl Iterator<Item = &'r mut Value<'h>>)
+    where
+        'h: 'r,
+    {
+        if self.collector == Collector::Incremental && self.nursery.is_empty() {
+            let start = Instant::now();
+            let roots: Vec<&'r mut Value<'h>> = roots.collect();
+            self.slice(roots.iter().map(|root| &**root));
+            self.stats.slices += 1;
+            self.paused(start);
+            if cfg!(feature = "gc-stress") && self.phase == Phase::Idle {
+                self.assert_valid(roots.iter().map(|root| &**root));
+            }
+            self.finalize();
+            return;
+        }
+        let full = self.collector == Collector::MarkSweep || self.bytes >= self.threshold;
+        self.collect_with(full, roots);
+    }
+
+    /// Evacuates the nursery and marks and sweeps the whole heap, see Heap::collect
+    pub fn collect_full<'r>(&mut self, roots: impl Iterator<Item = &'r mut Value<'h>>)
+    where
+        'h: 'r,
+    {
+        self.collect_with(true, roots);
+    }
+
+    fn collect_with<'r>(&mut self, full: bool, roots: impl Iterator<Item = &'r mut Value<'h>>)
+    where
+        'h: 'r,
+    {
+        #[cfg(feature = "trace")]
+        println!(
+            "Heap::collect(full={full}, live={}, bytes={})",
+            self.len(),
+            self.bytes()
+        );
+
+        let start = Instant::now();
+        let mut roots: Vec<&'r mut Value<'h>> = roots.collect();
+        // the nursery is empty unless the collector is generational or was switched to
+        // mark and sweep, evacuating it first leaves no young references for marking
+        if !self.nursery.is_empty() {
+            self.minor(roots.iter_mut().map(|root| &mut **root));
+            self.stats.minor += 1;
+        }
+        if full {
+            self.abort_cycle();
+            self.mark_sweep(roots.iter().map(|root| &**root));
+            self.stats.major += 1;
+        }
+        self.paused(start);
+        if cfg!(feature = "gc-stress") {
+            self.assert_valid(roots.iter().map(|root| &**root));
+        }
+        self.finalize();
+    }
+
+    fn paused(&mut self, start: Instant) {
+        let pause = start.elapsed();
+        self.stats.total_pause += pause;
+        self.stats.max_pause = self.stats.max_pause.max(pause);
+    }
+
+    /// Marks every old object reachable from `roots`, clears weak references to the others and
+    /// frees them, afterwards the threshold is set to twice the surviving bytes
+    fn mark_sweep<'r>(&mut self, roots: impl Iterator<Item = &'r Value<'h>>)
+    where
+        'h: 'r,
+    {
+        let mut grey: Vec<u32> = roots.filter_map(Value::heap_ref).collect();
+        while let Some(idx) = grey.pop() {
+            let slot = &mut self.slots[idx as usize];
+            if slot.marked {
+                continue;
+            }
+            slot.marked = true;
+            grey.extend(self.object(idx).values().filter_map(Value::heap_ref));
+        }
+
+        self.clear_weaks(|heap, idx| heap.slots[idx as usize].marked);
+
+        self.bytes = 0;
+        for idx in 0..self.slots.len() {
+            let slot = &mut self.slots[idx];
+            if slot.marked {
+                slot.marked = false;
+                self.bytes += slot.object.as_ref().map_or(0, Object::size);
+            } else if let Some(object) = slot.object.take() {
+                self.free_slot(idx as u32);
+                self.release(object);
+            }
+        }
+        self.threshold = self.min_threshold.max(self.bytes * 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::{Array, Heap, INITIAL_THRESHOLD, Map},
+        vm::Value,
+    };
+
+    #[test]
+    #[cfg_attr(feature = "gc-stress", ignore = "gc-stress never reuses freed slots")]
+    fn unreachable_objects_are_freed() {
+        let mut heap = Heap::default();
+        let kept = heap.alloc(String::from("kept"));
+        heap.alloc(String::from("garbage"));
+        assert_eq!(heap.len(), 2);
+
+        heap.collect([Value::String(kept)].iter_mut());
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.get(kept), "kept");
+
+        // the freed slot is reused
+        let reused = heap.alloc(String::from("reused"));
+        assert_eq!(reused.idx(), 1);
+        assert_eq!(heap.len(), 2);
+    }
+
+    #[test]
+    fn marking_follows_containers() {
+        let mut heap = Heap::default();
+        let inner = heap.alloc(String::from("inner"));
+        let map = heap.alloc(Map::from([("key".to_string(), Value::String(inner))]));
+        let array = heap.alloc(Array::from([Value::Int(1), Value::Obj(map)]));
+        // cycle back to the array
+        heap.get_mut(map)
+            .insert("self".to_string(), Value::Arr(array));
+        heap.alloc(String::from("garbage"));
+
+        heap.collect([Value::Arr(array)].iter_mut());
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.get(inner), "inner");
+
+        heap.collect([].iter_mut());
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(
+        feature = "gc-stress",
+        ignore = "gc-stress collects on every allocation"
+    )]
+    fn threshold() {
+        let mut heap = Heap {
+            threshold: 0,
+            ..Default::default()
+        };
+        assert!(heap.should_collect());
+        let s = heap.alloc(String::from("abc"));
+        heap.collect([Value::String(s)].iter_mut());
+        assert!(heap.bytes() > 0);
+        assert_eq!(heap.threshold, INITIAL_THRESHOLD);
+        assert!(!heap.should_collect());
+    }
 }
@@ -0,0 +1,174 @@
+//! Heap snapshots as a JSON graph, for finding out which structures retain memory:
+//!
+//! ```text
+//! {
+//!   "bytes": 312,
+//!   "objects": [
+//!     {"id": 0, "type": "array", "size": 128, "len": 2, "references": [{"to": 1, "via": "[1]"}]},
+//!     {"id": 1, "type": "string", "size": 53, "len": 5, "references": []}
+//!   ],
+//!   "roots": [
+//!     {"name": "<main> r3", "to": 0}
+//!   ]
+//! }
+//! ```
+//!
+//! Ids are the slot indexes of the objects, `size` is the approximation of Object::size, `via` is
+//! the array index or object key a reference is stored at, or `weak` for the target of a weak
+//! reference, which does not retain it.
+
+use std::io::{self, Write};
+
+use crate::{
+    gc::{Heap, Object, generational},
+    vm::Value,
+};
+
+/// Escapes `s` as a JSON string, including the quotes
+fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl<'h> Heap<'h> {
+    /// every live object with its handle index, old generation first
+    pub fn objects(&self) -> impl Iterator<Item = (u32, &Object<'h>)> {
+        let old = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, slot)| Some((idx as u32, slot.object.as_ref()?)));
+        let young = self
+            .nursery
+            .iter()
+            .enumerate()
+            .map(|(offset, object)| (generational::young(self.nursery_base + offset), object));
+        old.chain(young)
+    }
+
+    /// Writes the snapshot described in the module documentation, `roots` are named by the caller
+    pub fn snapshot<'r>(
+        &self,
+        roots: impl Iterator<Item = (String, &'r Value<'h>)>,
+        out: &mut impl Write,
+    ) -> io::Result<()>
+    where
+        'h: 'r,
+    {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"bytes\": {},", self.bytes())?;
+        writeln!(out, "  \"objects\": [")?;
+        let mut first = true;
+        for (id, object) in self.objects() {
+            let (type_name, len, references): (_, _, Vec<(String, u32)>) = match object {
+                Object::Array(a) => (
+                    "array",
+                    a.len(),
+                    a.iter()
+                        .enumerate()
+                        .filter_map(|(i, v)| Some((format!("[{i}]"), v.heap_ref()?)))
+                        .collect(),
+                ),
+                Object::Map(m) => (
+                    "object",
+                    m.len(),
+                    m.iter()
+                        .filter_map(|(k, v)| Some((k.clone(), v.heap_ref()?)))
+                        .collect(),
+                ),
+                Object::String(s) => ("string", s.len(), vec![]),
+                Object::BigInt(_) => ("bigint", 0, vec![]),
+                // the target is not retained, but knowing it helps finding out why it is
+                Object::Weak(w) => (
+                    "weak",
+                    0,
+                    w.get()
+                        .and_then(|v| v.heap_ref())
+                        .map(|to| ("weak".to_string(), to))
+                        .into_iter()
+                        .collect(),
+                ),
+                Object::Host(_) => ("host", 0, vec![]),
+            };
+            let references = references
+                .iter()
+                .map(|(via, to)| format!("{{\"to\": {to}, \"via\": {}}}", string(via)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if !first {
+                writeln!(out, ",")?;
+            }
+            first = false;
+            write!(
+                out,
+                "    {{\"id\": {id}, \"type\": \"{type_name}\", \"size\": {}, \"len\": {len}, \"references\": [{references}]}}",
+                object.size()
+            )?;
+        }
+        writeln!(out, "\n  ],")?;
+
+        writeln!(out, "  \"roots\": [")?;
+        let mut first = true;
+        for (name, value) in roots {
+            let Some(to) = value.heap_ref() else {
+                continue;
+            };
+            if !first {
+                writeln!(out, ",")?;
+            }
+            first = false;
+            write!(out, "    {{\"name\": {}, \"to\": {to}}}", string(&name))?;
+        }
+        writeln!(out, "\n  ]")?;
+        writeln!(out, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::{Array, Heap, Map, snapshot::string},
+        vm::Value,
+    };
+
+    #[test]
+    fn escaping() {
+        assert_eq!(string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+    }
+
+    #[test]
+    fn graph() {
+        let mut heap = Heap::default();
+        let s = heap.alloc(String::from("hello"));
+        let map = heap.alloc(Map::from([("greeting".to_string(), Value::String(s))]));
+        let arr = heap.alloc(Array::from([Value::Int(1), Value::Obj(map)]));
+        let root = Value::Arr(arr);
+
+        let mut out = vec![];
+        heap.snapshot([("r0".to_string(), &root)].into_iter(), &mut out)
+            .expect("Failed to write snapshot");
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains(r#"{"id": 0, "type": "string", "size": "#));
+        assert!(out.contains(r#""references": [{"to": 0, "via": "greeting"}]"#));
+        assert!(out.contains(r#"{"id": 2, "type": "array", "size": "#));
+        assert!(out.contains(r#""len": 2, "references": [{"to": 1, "via": "[1]"}]"#));
+        assert!(out.contains(r#"{"name": "r0", "to": 2}"#));
+        assert_eq!(out.matches("\"id\"").count(), 3);
+        assert_eq!(out.matches('{').count(), out.matches('}').count());
+        assert_eq!(out.matches('[').count(), out.matches(']').count());
+    }
+}
@@ -0,0 +1,161 @@
+//! Heap verification and the `gc-stress` feature, which makes missing roots and barriers show up
+//! at the first allocation after the bug instead of much later:
+//!
+//! - Heap::should_collect is always true, so every Vm::alloc collects
+//! - freed slots are poisoned: they are never handed out again, a handle to one panics on use
+//!   instead of silently reading a later object, nursery handles are never reused either
+//! - Heap::verify runs after every collection and finished incremental cycle, panicking on the
+//!   first inconsistency
+
+use crate::{
+    gc::{Heap, Object, generational, incremental::Phase},
+    vm::Value,
+};
+
+impl<'h> Heap<'h> {
+    /// Makes the slot `idx` available for allocations, or poisons it with the gc-stress feature
+    pub(super) fn free_slot(&mut self, idx: u32) {
+        if cfg!(feature = "gc-stress") {
+            self.poisoned += 1;
+        } else {
+            self.free.push(idx);
+        }
+    }
+
+    /// whether `idx` refers to a live object
+    fn is_live(&self, idx: u32) -> bool {
+        if generational::is_young(idx) {
+            return generational::offset(idx)
+                .checked_sub(self.nursery_base)
+                .is_some_and(|index| index < self.nursery.len());
+        }
+        self.slots
+            .get(idx as usize)
+            .is_some_and(|slot| slot.object.is_some())
+    }
+
+    /// Checks that neither `roots` nor any object references a freed object, that every old
+    /// object referencing a nursery object is remembered and, outside of incremental cycles, that
+    /// no mark bits are left over. Returns a description of the first violation.
+    pub fn verify<'r>(&self, roots: impl Iterator<Item = &'r Value<'h>>) -> Result<(), String>
+    where
+        'h: 'r,
+    {
+        for (i, root) in roots.enumerate() {
+            if let Some(to) = root.heap_ref()
+                && !self.is_live(to)
+            {
+                return Err(format!("root {i} references freed object {to}"));
+            }
+        }
+
+        for (id, object) in self.objects() {
+            let weak = match object {
+                Object::Weak(weak) => weak.get(),
+                _ => None,
+            };
+            for to in object
+                .values()
+                .chain(weak.as_ref())
+                .filter_map(Value::heap_ref)
+            {
+                if !self.is_live(to) {
+                    return Err(format!("object {id} references freed object {to}"));
+                }
+                if generational::is_young(to)
+                    && !generational::is_young(id)
+                    && !self.slots[id as usize].remembered
+                {
+                    return Err(format!(
+                        "old object {id} references nursery object {to} without a write barrier"
+                    ));
+                }
+            }
+        }
+
+        if self.phase == Phase::Idle
+            && let Some(idx) = self.slots.iter().position(|slot| slot.marked)
+        {
+            return Err(format!("slot {idx} is still marked after the cycle"));
+        }
+        Ok(())
+    }
+
+    /// Panics if Heap::verify fails
+    pub(super) fn assert_valid<'r>(&self, roots: impl Iterator<Item = &'r Value<'h>>)
+    where
+        'h: 'r,
+    {
+        if let Err(e) = self.verify(roots) {
+            panic!("Heap::verify: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::{Array, Collector, Heap, Weak},
+        vm::Value,
+    };
+
+    #[test]
+    fn consistent_heap() {
+        let mut heap = Heap::new(Collector::Generational);
+        let s = Value::String(heap.alloc(String::from("s")));
+        let weak = Value::Weak(heap.alloc(Weak::new(s)));
+        let mut roots = [Value::Arr(heap.alloc(Array::from([s, weak])))];
+        assert_eq!(heap.verify(roots.iter()), Ok(()));
+        heap.collect_full(roots.iter_mut());
+        assert_eq!(heap.verify(roots.iter()), Ok(()));
+    }
+
+    #[test]
+    fn dangling_references() {
+        let mut heap = Heap::default();
+        let s = Value::String(heap.alloc(String::from("freed")));
+        // freed last, so its slot is reused first
+        heap.alloc(String::from("garbage"));
+        heap.collect_full([].iter_mut());
+        assert_eq!(
+            heap.verify([s].iter()),
+            Err("root 0 references freed object 0".to_string())
+        );
+
+        heap.alloc(Array::from([s]));
+        assert!(
+            heap.verify([].iter())
+                .is_err_and(|e| e.ends_with("references freed object 0"))
+        );
+    }
+
+    #[test]
+    fn missing_barrier() {
+        let mut heap = Heap::new(Collector::Generational);
+        let mut roots = [Value::Arr(heap.alloc(Array::new()))];
+        heap.collect(roots.iter_mut());
+        let Value::Arr(old) = roots[0] else {
+            unreachable!()
+        };
+
+        let young = Value::String(heap.alloc(String::from("young")));
+        heap.get_mut(old).push(young);
+        assert!(
+            heap.verify(roots.iter())
+                .is_err_and(|e| e.ends_with("without a write barrier"))
+        );
+        heap.barrier(old, &young);
+        assert_eq!(heap.verify(roots.iter()), Ok(()));
+    }
+
+    #[cfg(feature = "gc-stress")]
+    #[test]
+    #[should_panic(expected = "use of a freed nursery object")]
+    fn stale_nursery_handles_are_poisoned() {
+        let mut heap = Heap::new(Collector::Generational);
+        let stale = heap.alloc(String::from("stale"));
+        heap.collect([].iter_mut());
+        heap.alloc(String::from("would reuse the offset"));
+        heap.get(stale);
+    }
+}
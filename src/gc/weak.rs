@@ -0,0 +1,186 @@
+//! Weak references and host objects. A [Weak] does not keep its target alive, collections clear
+//! it once the target is freed. A [Host] wraps a resource of the embedding program, its finalizer
+//! is called with the resource once the collection that freed it finished.
+
+use std::{any::Any, fmt};
+
+use crate::{
+    gc::{Heap, Object},
+    vm::Value,
+};
+
+/// See the module documentation
+#[derive(Debug)]
+pub struct Weak<'h> {
+    target: Option<Value<'h>>,
+}
+
+impl<'h> Weak<'h> {
+    pub fn new(target: Value<'h>) -> Self {
+        Weak {
+            target: Some(target),
+        }
+    }
+
+    /// the target, None once it was collected
+    pub fn get(&self) -> Option<Value<'h>> {
+        self.target
+    }
+
+    pub(super) fn target_mut(&mut self) -> Option<&mut Value<'h>> {
+        self.target.as_mut()
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.target = None;
+    }
+}
+
+/// Called with the resource of a collected [Host]
+pub type Finalizer = fn(Box<dyn Any>);
+
+/// See the module documentation
+pub struct Host {
+    pub resource: Box<dyn Any>,
+    finalizer: Option<Finalizer>,
+}
+
+impl Host {
+    pub fn new(resource: impl Any, finalizer: Option<Finalizer>) -> Self {
+        Host {
+            resource: Box::new(resource),
+            finalizer,
+        }
+    }
+}
+
+impl fmt::Debug for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Host")
+            .field("finalizer", &self.finalizer.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'h> Heap<'h> {
+    /// Clears the weak references in the old generation whose target is not `live`
+    pub(super) fn clear_weaks(&mut self, live: impl Fn(&Self, u32) -> bool) {
+        let dead: Vec<usize> = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, slot)| match &slot.object {
+                Some(Object::Weak(weak)) => {
+                    let target = weak.get()?.heap_ref()?;
+                    (!live(self, target)).then_some(idx)
+                }
+                _ => None,
+            })
+            .collect();
+        for idx in dead {
+            if let Some(Object::Weak(weak)) = &mut self.slots[idx].object {
+                weak.clear();
+            }
+        }
+    }
+
+    /// Hands freed objects to Heap::finalize
+    pub(super) fn release(&mut self, object: Object<'h>) {
+        if let Object::Host(host) = object
+            && host.finalizer.is_some()
+        {
+            self.finalizable.push(host);
+        }
+    }
+
+    /// Runs the finalizers of the host objects freed since the last call
+    pub(super) fn finalize(&mut self) {
+        for host in std::mem::take(&mut self.finalizable) {
+            if let Some(finalizer) = host.finalizer {
+                finalizer(host.resource);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        any::Any,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use crate::{
+        gc::{Array, Collector, Heap, Host, Weak},
+        vm::Value,
+    };
+
+    fn weak_survives(collector: Collector) {
+        let mut heap = Heap::new(collector);
+        let live = Value::Arr(heap.alloc(Array::new()));
+        let dead = Value::Arr(heap.alloc(Array::new()));
+        let to_live = Value::Weak(heap.alloc(Weak::new(live)));
+        let to_dead = Value::Weak(heap.alloc(Weak::new(dead)));
+        let mut roots = [live, to_live, to_dead];
+        heap.collect_full(roots.iter_mut());
+
+        let [live, Value::Weak(to_live), Value::Weak(to_dead)] = roots else {
+            unreachable!()
+        };
+        assert_eq!(heap.get(to_live).get(), Some(live));
+        assert_eq!(heap.get(to_dead).get(), None);
+        assert_eq!(heap.len(), 3);
+    }
+
+    #[test]
+    fn weak_references_are_cleared() {
+        weak_survives(Collector::MarkSweep);
+        weak_survives(Collector::Generational);
+        weak_survives(Collector::Incremental);
+    }
+
+    #[test]
+    fn weak_references_in_minor_collections() {
+        let mut heap = Heap::new(Collector::Generational);
+        let live = Value::String(heap.alloc(String::from("live")));
+        let dead = Value::String(heap.alloc(String::from("dead")));
+        let mut roots = [
+            live,
+            Value::Weak(heap.alloc(Weak::new(live))),
+            Value::Weak(heap.alloc(Weak::new(dead))),
+        ];
+        heap.collect(roots.iter_mut());
+        assert_eq!(heap.stats.major, 0);
+
+        let [live, Value::Weak(to_live), Value::Weak(to_dead)] = roots else {
+            unreachable!()
+        };
+        // relocated along with the promoted target
+        assert_eq!(heap.get(to_live).get(), Some(live));
+        assert_eq!(heap.get(to_dead).get(), None);
+    }
+
+    static FINALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    fn finalizer(resource: Box<dyn Any>) {
+        let fd = resource.downcast::<usize>().unwrap();
+        FINALIZED.fetch_add(*fd, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn finalizers_run_after_collection() {
+        for collector in [Collector::MarkSweep, Collector::Generational] {
+            FINALIZED.store(0, Ordering::SeqCst);
+            let mut heap = Heap::new(collector);
+            let kept = Value::Host(heap.alloc(Host::new(1usize, Some(finalizer))));
+            heap.alloc(Host::new(2usize, Some(finalizer)));
+            heap.alloc(Host::new(4usize, None));
+            let mut roots = [kept];
+            heap.collect_full(roots.iter_mut());
+            assert_eq!(FINALIZED.load(Ordering::SeqCst), 2, "{collector:?}");
+
+            heap.collect_full([].iter_mut());
+            assert_eq!(FINALIZED.load(Ordering::SeqCst), 3, "{collector:?}");
+        }
+    }
+}
@@ -1,4 +1,9 @@
+use std::borrow::Cow;
+
+use crate::err::PgError;
+
 #[derive(Debug, Clone)]
+#[cfg_attr(test, derive(PartialEq))]
 pub enum Type<'t> {
     Eof,
     DelimitLeft,
@@ -7,21 +12,54 @@ pub enum Type<'t> {
     Minus,
     Asteriks,
     Slash,
+    Percent,
+    /// =
+    Assign,
+    /// ==
     Equal,
+    /// !=
+    NotEqual,
     LessThan,
     GreaterThan,
+    /// <=
+    LessEqual,
+    /// >=
+    GreaterEqual,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    /// <<
+    ShiftLeft,
+    /// >>
+    ShiftRight,
+    /// &&
+    DoubleAmpersand,
+    /// ||
+    DoublePipe,
     Exlaim,
     DoubleColon,
     BraketLeft,
     BraketRight,
     CurlyLeft,
     CurlyRight,
-    String(&'t str),
+    /// the contents of a string literal with its escapes resolved, borrowed from the input if it
+    /// has none, raw strings are always borrowed
+    String(Cow<'t, str>),
+    /// the contents of a string literal up to the `{` opening its first interpolation, the
+    /// tokens of the embedded expression follow
+    InterpolationStart(Cow<'t, str>),
+    /// the contents between the `}` closing an interpolation and the `{` opening the next
+    InterpolationMiddle(Cow<'t, str>),
+    /// the contents between the `}` closing the last interpolation and the closing quote
+    InterpolationEnd(Cow<'t, str>),
     Ident(&'t str),
     Double(&'t str),
     Integer(&'t str),
     True,
     False,
+    /// the absent value
+    None,
     Let,
     Fn,
     Match,
@@ -33,6 +71,9 @@ pub enum Type<'t> {
 pub struct Token<'t> {
     pub line: usize,
     pub col: usize,
+    /// byte length of the token in the input, for string literals with their quotes, escapes and
+    /// raw delimiters, since those are not part of Type::String
+    pub len: usize,
     pub t: Type<'t>,
 }
 
@@ -41,4 +82,598 @@ pub struct Lexer<'l> {
     pos: usize,
     line: usize,
     col: usize,
+    /// the open interpolations of the string literals being lexed, innermost last, each with the
+    /// number of `{` opened in its expression and not closed yet
+    interpolations: Vec<usize>,
+}
+
+impl<'l> Lexer<'l> {
+    pub fn new(input: &'l str) -> Self {
+        Lexer {
+            input: input.as_bytes(),
+            pos: 0,
+            line: 0,
+            col: 0,
+            interpolations: vec![],
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn advance(&mut self) {
+        if self.peek() == Some(b'\n') {
+            self.line += 1;
+            self.col = 0;
+        } else {
+            self.col += 1;
+        }
+        self.pos += 1;
+    }
+
+    /// consumes the next byte if it is `c`, for tokens of two chars
+    fn eat(&mut self, c: u8) -> bool {
+        let matches = self.peek() == Some(c);
+        if matches {
+            self.advance();
+        }
+        matches
+    }
+
+    fn advance_while(&mut self, pred: impl Fn(u8) -> bool) {
+        while self.peek().is_some_and(&pred) {
+            self.advance();
+        }
+    }
+
+    /// the input between `start` and the current position
+    fn slice(&self, start: usize) -> &'l str {
+        // start and the current position are always at ascii bytes, so char boundaries
+        std::str::from_utf8(&self.input[start..self.pos])
+            .expect("Lexer: slice not at char boundaries")
+    }
+
+    /// Lexes the rest of a string literal after its opening quote or after the `}` closing an
+    /// interpolation, up to the closing quote or the `{` opening the next interpolation, true for
+    /// the latter. An escape is a `\` followed by one of `n r t 0 \ " ' { }` or by `u{...}` with
+    /// the hex code point of a unicode scalar value.
+    fn string(&mut self, line: usize, col: usize) -> Result<(Cow<'l, str>, bool), PgError> {
+        let start = self.pos;
+        let mut unescaped: Option<String> = None;
+        // start of the input not yet copied into unescaped
+        let mut copied = start;
+        loop {
+            match self.peek() {
+                None => return Err(PgError::new("unterminated string", line, col, col + 1)),
+                Some(b'"' | b'{') => break,
+                // a `\` at the end of the input leaves the string unterminated
+                Some(b'\\') if self.pos + 1 < self.input.len() => {
+                    let s = unescaped.get_or_insert_with(String::new);
+                    s.push_str(self.slice(copied));
+                    s.push(self.escape()?);
+                    copied = self.pos;
+                }
+                Some(_) => self.advance(),
+            }
+        }
+        let s = match unescaped {
+            Some(mut s) => {
+                s.push_str(self.slice(copied));
+                Cow::Owned(s)
+            }
+            None => Cow::Borrowed(self.slice(start)),
+        };
+        let interpolates = self.peek() == Some(b'{');
+        if interpolates {
+            self.interpolations.push(0);
+        }
+        self.advance();
+        Ok((s, interpolates))
+    }
+
+    /// Lexes the escape at the current position
+    fn escape(&mut self) -> Result<char, PgError> {
+        let (line, col) = (self.line, self.col);
+        self.advance();
+        let c = match self.peek() {
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'0') => '\0',
+            Some(b'\\') => '\\',
+            Some(b'"') => '"',
+            Some(b'\'') => '\'',
+            Some(b'{') => '{',
+            Some(b'}') => '}',
+            Some(b'u') => {
+                self.advance();
+                return self.unicode_escape(line, col);
+            }
+            _ => {
+                return Err(PgError::new(
+                    format!("invalid escape `\\{}`", self.char_at(self.pos)),
+                    line,
+                    col,
+                    col + 2,
+                ));
+            }
+        };
+        self.advance();
+        Ok(c)
+    }
+
+    /// Lexes the `{...}` of a `\u{...}` escape starting at `col`
+    fn unicode_escape(&mut self, line: usize, col: usize) -> Result<char, PgError> {
+        let start = self.pos;
+        let mut code = None;
+        if self.eat(b'{') {
+            self.advance_while(|c| c.is_ascii_hexdigit());
+            let digits = &self.slice(start)[1..];
+            if self.eat(b'}') && (1..=6).contains(&digits.len()) {
+                code = u32::from_str_radix(digits, 16).ok();
+            }
+        }
+        // the escape spans from its `\` to the current position
+        let err = |msg: &str| PgError::new(msg, line, col, col + 2 + self.pos - start);
+        let code = code.ok_or_else(|| err("expected 1 to 6 hex digits in `\\u{...}`"))?;
+        char::from_u32(code)
+            .ok_or_else(|| err("invalid unicode escape, not a unicode scalar value"))
+    }
+
+    /// Lexes the rest of a raw string after its `r`: `n` hashes, a quote and the contents up to
+    /// the first quote followed by `n` hashes, nothing is escaped
+    fn raw_string(&mut self, line: usize, col: usize) -> Result<&'l str, PgError> {
+        let hashes = self.pos;
+        self.advance_while(|c| c == b'#');
+        let hashes = self.pos - hashes;
+        if !self.eat(b'"') {
+            return Err(PgError::new(
+                "invalid raw string, expected `\"`",
+                line,
+                col,
+                self.col,
+            ));
+        }
+        let closing = format!("\"{}", "#".repeat(hashes));
+        let start = self.pos;
+        loop {
+            if self.input[self.pos..].starts_with(closing.as_bytes()) {
+                let s = self.slice(start);
+                for _ in 0..closing.len() {
+                    self.advance();
+                }
+                return Ok(s);
+            }
+            if self.peek().is_none() {
+                return Err(PgError::new("unterminated raw string", line, col, col + 1));
+            }
+            self.advance();
+        }
+    }
+
+    /// the char starting at `pos`, which is at a char boundary
+    fn char_at(&self, pos: usize) -> &'l str {
+        let rest =
+            std::str::from_utf8(&self.input[pos..]).expect("Lexer: pos not at char boundary");
+        rest.chars().next().map_or("", |c| &rest[..c.len_utf8()])
+    }
+
+    /// skips whitespace and `//` comments
+    fn skip(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_ascii_whitespace() => self.advance(),
+                Some(b'/') if self.input.get(self.pos + 1) == Some(&b'/') => {
+                    self.advance_while(|c| c != b'\n')
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Produces the next token, Type::Eof once the input is exhausted
+    pub fn next(&mut self) -> Result<Token<'l>, PgError> {
+        self.skip();
+        let (line, col, start) = (self.line, self.col, self.pos);
+        let Some(c) = self.peek() else {
+            return Ok(Token {
+                line,
+                col,
+                len: 0,
+                t: Type::Eof,
+            });
+        };
+        self.advance();
+
+        let t = match c {
+            b'(' => Type::DelimitLeft,
+            b')' => Type::DelimitRight,
+            b'+' => Type::Plus,
+            b'-' => Type::Minus,
+            b'*' => Type::Asteriks,
+            b'/' => Type::Slash,
+            b'%' => Type::Percent,
+            b'=' if self.eat(b'=') => Type::Equal,
+            b'=' => Type::Assign,
+            b'!' if self.eat(b'=') => Type::NotEqual,
+            b'!' => Type::Exlaim,
+            b'<' if self.eat(b'<') => Type::ShiftLeft,
+            b'<' if self.eat(b'=') => Type::LessEqual,
+            b'<' => Type::LessThan,
+            b'>' if self.eat(b'>') => Type::ShiftRight,
+            b'>' if self.eat(b'=') => Type::GreaterEqual,
+            b'>' => Type::GreaterThan,
+            b'&' if self.eat(b'&') => Type::DoubleAmpersand,
+            b'&' => Type::Ampersand,
+            b'|' if self.eat(b'|') => Type::DoublePipe,
+            b'|' => Type::Pipe,
+            b'^' => Type::Caret,
+            b'~' => Type::Tilde,
+            b'[' => Type::BraketLeft,
+            b']' => Type::BraketRight,
+            b'{' => {
+                if let Some(open) = self.interpolations.last_mut() {
+                    *open += 1;
+                }
+                Type::CurlyLeft
+            }
+            b'}' if self.interpolations.last() == Some(&0) => {
+                self.interpolations.pop();
+                match self.string(line, col)? {
+                    (s, true) => Type::InterpolationMiddle(s),
+                    (s, false) => Type::InterpolationEnd(s),
+                }
+            }
+            b'}' => {
+                if let Some(open) = self.interpolations.last_mut() {
+                    *open -= 1;
+                }
+                Type::CurlyRight
+            }
+            b':' if self.eat(b':') => Type::DoubleColon,
+            b'"' => match self.string(line, col)? {
+                (s, true) => Type::InterpolationStart(s),
+                (s, false) => Type::String(s),
+            },
+            b'r' if matches!(self.peek(), Some(b'"' | b'#')) => {
+                Type::String(Cow::Borrowed(self.raw_string(line, col)?))
+            }
+            b'0'..=b'9' => {
+                self.advance_while(|c| c.is_ascii_digit());
+                if self.peek() == Some(b'.') {
+                    self.advance();
+                    self.advance_while(|c| c.is_ascii_digit());
+                    Type::Double(self.slice(start))
+                } else {
+                    Type::Integer(self.slice(start))
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                self.advance_while(|c| c.is_ascii_alphanumeric() || c == b'_');
+                match self.slice(start) {
+                    "true" => Type::True,
+                    "false" => Type::False,
+                    "none" => Type::None,
+                    "let" => Type::Let,
+                    "fn" => Type::Fn,
+                    "match" => Type::Match,
+                    "std" => Type::Std,
+                    "for" => Type::For,
+                    ident => Type::Ident(ident),
+                }
+            }
+            _ => {
+                // report the whole char, not just its first byte
+                return Err(PgError::new(
+                    format!("unexpected `{}`", self.char_at(start)),
+                    line,
+                    col,
+                    col + 1,
+                ));
+            }
+        };
+        Ok(Token {
+            line,
+            col,
+            len: self.pos - start,
+            t,
+        })
+    }
+
+    /// Lexes the whole input, the last token is always Type::Eof
+    pub fn all(mut self) -> Result<Vec<Token<'l>>, PgError> {
+        let mut tokens = vec![];
+        loop {
+            let token = self.next()?;
+            let eof = matches!(token.t, Type::Eof);
+            tokens.push(token);
+            if eof {
+                return Ok(tokens);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::{
+        err::Span,
+        lex::{Lexer, Type},
+    };
+
+    fn types(input: &str) -> Vec<Type<'_>> {
+        Lexer::new(input)
+            .all()
+            .expect("Failed to lex")
+            .into_iter()
+            .map(|token| token.t)
+            .collect()
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            types("( ) + - * / % = == != < > <= >= & | ^ ~ << >> && || ! :: [ ] { }"),
+            vec![
+                Type::DelimitLeft,
+                Type::DelimitRight,
+                Type::Plus,
+                Type::Minus,
+                Type::Asteriks,
+                Type::Slash,
+                Type::Percent,
+                Type::Assign,
+                Type::Equal,
+                Type::NotEqual,
+                Type::LessThan,
+                Type::GreaterThan,
+                Type::LessEqual,
+                Type::GreaterEqual,
+                Type::Ampersand,
+                Type::Pipe,
+                Type::Caret,
+                Type::Tilde,
+                Type::ShiftLeft,
+                Type::ShiftRight,
+                Type::DoubleAmpersand,
+                Type::DoublePipe,
+                Type::Exlaim,
+                Type::DoubleColon,
+                Type::BraketLeft,
+                Type::BraketRight,
+                Type::CurlyLeft,
+                Type::CurlyRight,
+                Type::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn literals_and_keywords() {
+        assert_eq!(
+            types("let x_1 = 25 2.5 \"hello\" true false none fn match std for // comment"),
+            vec![
+                Type::Let,
+                Type::Ident("x_1"),
+                Type::Assign,
+                Type::Integer("25"),
+                Type::Double("2.5"),
+                Type::String("hello".into()),
+                Type::True,
+                Type::False,
+                Type::None,
+                Type::Fn,
+                Type::Match,
+                Type::Std,
+                Type::For,
+                Type::Eof,
+            ]
+        );
+        // keywords only match whole identifiers
+        assert_eq!(types("nonempty"), vec![Type::Ident("nonempty"), Type::Eof]);
+    }
+
+    #[test]
+    fn escapes() {
+        let strings = [
+            (r#""plain""#, "plain"),
+            (r#""a\nb\tc\r\0""#, "a\nb\tc\r\0"),
+            (r#""\"quoted\" \\ \'""#, "\"quoted\" \\ '"),
+            (r#""\u{1F600} \u{e9}\u{0}""#, "\u{1F600} \u{e9}\0"),
+            (r#""é\n""#, "é\n"),
+        ];
+        for (input, expected) in strings {
+            assert_eq!(types(input), vec![Type::String(expected.into()), Type::Eof]);
+        }
+        // only literals with escapes copy their contents out of the input
+        let [Type::String(plain), Type::String(escaped), Type::Eof] = &types(r#""a" "\n""#)[..]
+        else {
+            panic!("expected two strings");
+        };
+        assert!(matches!(plain, Cow::Borrowed("a")));
+        assert!(matches!(escaped, Cow::Owned(_)));
+    }
+
+    #[test]
+    fn interpolation() {
+        assert_eq!(
+            types(r#""a {b + 1} c {d}""#),
+            vec![
+                Type::InterpolationStart("a ".into()),
+                Type::Ident("b"),
+                Type::Plus,
+                Type::Integer("1"),
+                Type::InterpolationMiddle(" c ".into()),
+                Type::Ident("d"),
+                Type::InterpolationEnd("".into()),
+                Type::Eof,
+            ]
+        );
+        // braces of the expression do not close the interpolation, strings in it nest
+        assert_eq!(
+            types(r#""{ {x} "in {y}" }\n" }"#),
+            vec![
+                Type::InterpolationStart("".into()),
+                Type::CurlyLeft,
+                Type::Ident("x"),
+                Type::CurlyRight,
+                Type::InterpolationStart("in ".into()),
+                Type::Ident("y"),
+                Type::InterpolationEnd("".into()),
+                Type::InterpolationEnd("\n".into()),
+                Type::CurlyRight,
+                Type::Eof,
+            ]
+        );
+        // escaped braces and raw strings do not interpolate
+        assert_eq!(
+            types(r#""\{a\}" r"{a}""#),
+            vec![
+                Type::String("{a}".into()),
+                Type::String("{a}".into()),
+                Type::Eof,
+            ]
+        );
+        let tokens = Lexer::new("\"ab {\n  x } c\"")
+            .all()
+            .expect("Failed to lex");
+        let positions: Vec<_> = tokens.iter().map(|t| (t.line, t.col)).collect();
+        assert_eq!(positions, vec![(0, 0), (1, 2), (1, 4), (1, 8)]);
+    }
+
+    #[test]
+    fn raw_strings() {
+        let strings = [
+            (r#"r"a\nb""#, r"a\nb"),
+            (r##"r#"say "hi""#"##, r#"say "hi""#),
+            (r###"r##"a"# b"##"###, r##"a"# b"##),
+            ("r\"multi\nline\"", "multi\nline"),
+            (r#"r"""#, ""),
+        ];
+        for (input, expected) in strings {
+            let tokens = types(input);
+            assert_eq!(tokens, vec![Type::String(expected.into()), Type::Eof]);
+            assert!(matches!(tokens[0], Type::String(Cow::Borrowed(_))));
+        }
+        // an r not followed by a quote or a hash is an identifier
+        assert_eq!(
+            types("r + r2"),
+            vec![Type::Ident("r"), Type::Plus, Type::Ident("r2"), Type::Eof]
+        );
+        let tokens = Lexer::new("r#\"a\"# b").all().expect("Failed to lex");
+        assert_eq!(tokens[1].col, 7);
+    }
+
+    #[test]
+    fn two_char_tokens() {
+        // the longest token wins, whitespace separates
+        assert_eq!(
+            types("a<=-b !!c = =="),
+            vec![
+                Type::Ident("a"),
+                Type::LessEqual,
+                Type::Minus,
+                Type::Ident("b"),
+                Type::Exlaim,
+                Type::Exlaim,
+                Type::Ident("c"),
+                Type::Assign,
+                Type::Equal,
+                Type::Eof,
+            ]
+        );
+        assert_eq!(
+            types("a<<=b&&&c"),
+            vec![
+                Type::Ident("a"),
+                Type::ShiftLeft,
+                Type::Assign,
+                Type::Ident("b"),
+                Type::DoubleAmpersand,
+                Type::Ampersand,
+                Type::Ident("c"),
+                Type::Eof,
+            ]
+        );
+        let tokens = Lexer::new("1 >= 2").all().expect("Failed to lex");
+        assert_eq!(crate::err::Span::from(&tokens[1]).end, 4);
+    }
+
+    #[test]
+    fn positions() {
+        let tokens = Lexer::new("let a\n  none").all().expect("Failed to lex");
+        let positions: Vec<_> = tokens.iter().map(|t| (t.line, t.col)).collect();
+        assert_eq!(positions, vec![(0, 0), (0, 4), (1, 2), (1, 6)]);
+    }
+
+    #[test]
+    fn string_spans() {
+        let tokens = Lexer::new(r##""a\n\u{e9}" r#"b"# + "c{d}\t""##)
+            .all()
+            .expect("Failed to lex");
+        let spans: Vec<_> = tokens
+            .iter()
+            .map(|t| {
+                let span = Span::from(t);
+                (span.start, span.end)
+            })
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (0, 11),
+                (12, 18),
+                (19, 20),
+                (21, 24),
+                (24, 25),
+                (25, 29),
+                (29, 30)
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = |input| {
+            Lexer::new(input)
+                .all()
+                .expect_err("should fail")
+                .to_string()
+        };
+        assert_eq!(err("\"open"), "err: unterminated string at l:0:0-1");
+        assert_eq!(err("\"open\\"), "err: unterminated string at l:0:0-1");
+        assert_eq!(err(r#"x = "a\qb""#), "err: invalid escape `\\q` at l:0:6-8");
+        assert_eq!(err(r#""\ä""#), "err: invalid escape `\\ä` at l:0:1-3");
+        assert_eq!(
+            err(r#""\u{110000}""#),
+            "err: invalid unicode escape, not a unicode scalar value at l:0:1-11"
+        );
+        assert_eq!(
+            err(r#""\u{d800}""#),
+            "err: invalid unicode escape, not a unicode scalar value at l:0:1-9"
+        );
+        assert_eq!(
+            err(r#""\u{}""#),
+            "err: expected 1 to 6 hex digits in `\\u{...}` at l:0:1-5"
+        );
+        assert_eq!(
+            err(r#""\u{1234567}""#),
+            "err: expected 1 to 6 hex digits in `\\u{...}` at l:0:1-12"
+        );
+        assert_eq!(
+            err(r#""\u41""#),
+            "err: expected 1 to 6 hex digits in `\\u{...}` at l:0:1-3"
+        );
+        assert_eq!(err("r\"open"), "err: unterminated raw string at l:0:0-1");
+        assert_eq!(err("r#\"open\""), "err: unterminated raw string at l:0:0-1");
+        assert_eq!(
+            err("r##x"),
+            "err: invalid raw string, expected `\"` at l:0:0-3"
+        );
+        assert_eq!(err("a : b"), "err: unexpected `:` at l:0:2-3");
+        assert_eq!(err("1 ä"), "err: unexpected `ä` at l:0:2-3");
+    }
 }
@@ -1,23 +1,26 @@
 #![allow(dead_code, unused_variables)]
 
-use crate::op::Op;
+use crate::{asm::Program, op::Op};
 
+/// textual bytecode assembler and disassembler
+mod asm;
 mod ast;
+/// packed fixed width bytecode encoding, decoded directly by the vm
+mod bc;
 mod cc;
 /// pretty print errors
 mod err;
-/// simple mark and sweep garbage collector, will be replaced by a manchester garbage collector in
-/// the future
+/// mark and sweep, generational and incremental garbage collectors, selected via gc::Collector
 mod gc;
 mod lex;
 /// purple garden bytecode virtual machine operations
 mod op;
 mod parser;
+/// the standard library, builtins reachable via `std::` paths
+mod stdlib;
 /// register based virtual machine
 mod vm;
 
-type Todo = ();
-
 // TODO:
 // - port pg cli to serde
 // - port frontend (lexer, parser)
@@ -51,14 +54,24 @@ fn main() {
             args_len: 2,
         },
         Op::Sys {
-            ptr: |_, _| {},
+            ptr: |_, _| Ok(vm::Value::False),
             args_start: 0,
             args_len: 1,
         },
         Op::Ret { times: 1 },
     ];
 
-    for (i, op) in bytecode.iter().enumerate() {
-        println!("{:04} {:?}", i, op)
-    }
+    let program = Program {
+        ops: bytecode.clone(),
+        ..Default::default()
+    };
+    print!("{}", asm::disassemble(&program, &[]));
+
+    let (packed, _) = bc::encode(&bytecode);
+    println!(
+        "{} ops: {}B as Op, {}B packed",
+        bytecode.len(),
+        std::mem::size_of_val(bytecode.as_slice()),
+        std::mem::size_of_val(packed.code.as_slice())
+    );
 }
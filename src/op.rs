@@ -1,6 +1,6 @@
 use crate::vm::BuiltinFn;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[allow(unpredictable_function_pointer_comparisons)]
 #[cfg_attr(test, derive(PartialEq, Eq))]
 pub enum Op<'vm> {
@@ -39,6 +39,67 @@ pub enum Op<'vm> {
         lhs: u8,
         rhs: u8,
     },
+    Le {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    Ge {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    Ne {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    /// remainder of lhs / rhs, with the sign of lhs
+    Mod {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    BitAnd {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    BitOr {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    BitXor {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    /// see vm::int::shl
+    Shl {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    /// see vm::int::shr
+    Shr {
+        dst: u8,
+        lhs: u8,
+        rhs: u8,
+    },
+    Neg {
+        dst: u8,
+        src: u8,
+    },
+    BitNot {
+        dst: u8,
+        src: u8,
+    },
+    /// true if src is false or none, false otherwise
+    Not {
+        dst: u8,
+        src: u8,
+    },
     Mov {
         dst: u8,
         src: u8,
@@ -72,22 +133,35 @@ pub enum Op<'vm> {
         container: u8,
         src: u8,
     },
+    /// elements of an array, entries of an object, chars of a string, see Value
     Len {
         dst: u8,
         src: u8,
     },
+    /// the element at an int index of an array, the value at a string key of an object or none if
+    /// it has no such key, the char at an int index of a string
     Idx {
         dst: u8,
         container: u8,
         index: u8,
     },
+    /// stores src at key of container, key is an int for arrays and a string for objects
+    Set {
+        container: u8,
+        key: u8,
+        src: u8,
+    },
     Jmp {
         target: usize,
     },
+    /// jumps if cond is false or none
     JmpF {
         cond: u8,
         target: usize,
     },
+    /// calls the function at func with the args_len arguments from r{args_start}, the callee's
+    /// register window starts at r{args_start}, which receives the result, and overwrites every
+    /// register above it
     Call {
         func: u16,
         args_start: u8,
@@ -102,9 +176,15 @@ pub enum Op<'vm> {
         args_start: u8,
         args_len: u8,
     },
+    /// the strings, numbers, bools and nones in r{start}..r{start+len} rendered and joined into a
+    /// new string in r{start}, for string interpolation
+    Concat {
+        start: u8,
+        len: u8,
+    },
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum New {
     Object,
     Array,
@@ -0,0 +1,476 @@
+//! Recursive descent parser producing ast::Node trees from lex::Lexer tokens. Binary operators
+//! are parsed by precedence climbing, from loosest to tightest binding:
+//!
+//! ```text
+//! ||
+//! &&
+//! == !=
+//! < > <= >=
+//! |
+//! ^
+//! &
+//! << >>
+//! + -
+//! * / %
+//! - ! ~ (unary)
+//! ```
+//!
+//! All binary operators are left associative, unary operators nest: `--a` is `-(-a)`. Arguments
+//! and array members are separated by whitespace only, so `[1 -2]` is `[(1 - 2)]`, a negative
+//! member needs parentheses: `[1 (-2)]`.
+
+use std::mem::discriminant;
+
+use crate::{
+    ast::{InnerNode, Node},
+    err::PgError,
+    lex::{Lexer, Token, Type},
+};
+
+pub struct Parser<'p> {
+    lexer: Lexer<'p>,
+    /// the next token, not consumed yet
+    current: Token<'p>,
+}
+
+/// binding power of the binary operator `t`, higher binds tighter
+fn precedence(t: &Type) -> Option<u8> {
+    Some(match t {
+        Type::DoublePipe => 1,
+        Type::DoubleAmpersand => 2,
+        Type::Equal | Type::NotEqual => 3,
+        Type::LessThan | Type::GreaterThan | Type::LessEqual | Type::GreaterEqual => 4,
+        Type::Pipe => 5,
+        Type::Caret => 6,
+        Type::Ampersand => 7,
+        Type::ShiftLeft | Type::ShiftRight => 8,
+        Type::Plus | Type::Minus => 9,
+        Type::Asteriks | Type::Slash | Type::Percent => 10,
+        _ => return None,
+    })
+}
+
+impl<'p> Parser<'p> {
+    pub fn new(mut lexer: Lexer<'p>) -> Result<Self, PgError> {
+        let current = lexer.next()?;
+        Ok(Parser { lexer, current })
+    }
+
+    /// consumes and returns the current token
+    fn advance(&mut self) -> Result<Token<'p>, PgError> {
+        let next = self.lexer.next()?;
+        Ok(std::mem::replace(&mut self.current, next))
+    }
+
+    fn at(&self, t: &Type) -> bool {
+        discriminant(&self.current.t) == discriminant(t)
+    }
+
+    /// consumes the current token if it is of type `t`, `what` describes it for the error
+    fn expect(&mut self, t: Type, what: &str) -> Result<Token<'p>, PgError> {
+        if !self.at(&t) {
+            return Err(PgError::with_msg(
+                format!("expected {what}, found {:?}", self.current.t),
+                &self.current,
+            ));
+        }
+        self.advance()
+    }
+
+    /// Parses statements until the end of the input
+    pub fn parse(mut self) -> Result<Vec<Node<'p>>, PgError> {
+        let mut nodes = vec![];
+        while !self.at(&Type::Eof) {
+            nodes.push(self.statement()?);
+        }
+        Ok(nodes)
+    }
+
+    fn statement(&mut self) -> Result<Node<'p>, PgError> {
+        match self.current.t {
+            Type::Let => {
+                self.advance()?;
+                let token = self.expect(Type::Ident(""), "a variable name")?;
+                self.expect(Type::Assign, "`=`")?;
+                let rhs = Box::new(self.expr()?);
+                Ok(Node {
+                    token,
+                    inner: InnerNode::Let { rhs },
+                })
+            }
+            Type::Fn => {
+                self.advance()?;
+                let token = self.expect(Type::Ident(""), "a function name")?;
+                self.expect(Type::DelimitLeft, "`(`")?;
+                let mut args = vec![];
+                while !self.at(&Type::DelimitRight) {
+                    let arg = self.expect(Type::Ident(""), "an argument name or `)`")?;
+                    args.push(Node {
+                        token: arg,
+                        inner: InnerNode::Ident,
+                    });
+                }
+                self.advance()?;
+                self.expect(Type::CurlyLeft, "`{`")?;
+                let mut body = vec![];
+                while !self.at(&Type::CurlyRight) {
+                    if self.at(&Type::Eof) {
+                        return Err(PgError::with_msg("unclosed function body", &token));
+                    }
+                    body.push(self.statement()?);
+                }
+                self.advance()?;
+                Ok(Node {
+                    token,
+                    inner: InnerNode::Fn { args, body },
+                })
+            }
+            _ => self.expr(),
+        }
+    }
+
+    pub fn expr(&mut self) -> Result<Node<'p>, PgError> {
+        self.binary(1)
+    }
+
+    /// parses operands and operators binding at least as tight as `min`
+    fn binary(&mut self, min: u8) -> Result<Node<'p>, PgError> {
+        let mut lhs = self.unary()?;
+        while let Some(precedence) = precedence(&self.current.t).filter(|p| *p >= min) {
+            let token = self.advance()?;
+            let rhs = self.binary(precedence + 1)?;
+            lhs = Node {
+                token,
+                inner: InnerNode::Bin {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Node<'p>, PgError> {
+        if self.at(&Type::Minus) || self.at(&Type::Exlaim) || self.at(&Type::Tilde) {
+            let token = self.advance()?;
+            let rhs = Box::new(self.unary()?);
+            return Ok(Node {
+                token,
+                inner: InnerNode::Unary { rhs },
+            });
+        }
+        self.primary()
+    }
+
+    /// expressions up to the closing `end`, which is consumed
+    fn list(&mut self, end: Type, what: &str) -> Result<Vec<Node<'p>>, PgError> {
+        let mut nodes = vec![];
+        while !self.at(&end) {
+            if self.at(&Type::Eof) {
+                return Err(PgError::with_msg(
+                    format!("expected {what}, found end of input"),
+                    &self.current,
+                ));
+            }
+            nodes.push(self.expr()?);
+        }
+        self.advance()?;
+        Ok(nodes)
+    }
+
+    /// `{ expr }`
+    fn block(&mut self) -> Result<Node<'p>, PgError> {
+        self.expect(Type::CurlyLeft, "`{`")?;
+        let node = self.expr()?;
+        self.expect(Type::CurlyRight, "`}`")?;
+        Ok(node)
+    }
+
+    /// the parts of a string literal after its Type::InterpolationStart
+    fn interpolation(&mut self) -> Result<Vec<Node<'p>>, PgError> {
+        let mut parts = vec![];
+        loop {
+            if let Type::InterpolationMiddle(_) | Type::InterpolationEnd(_) = self.current.t {
+                return Err(PgError::with_msg("empty interpolation", &self.current));
+            }
+            parts.push(self.expr()?);
+            let token = self.advance()?;
+            let (text, done) = match token.t {
+                Type::InterpolationMiddle(text) => (text, false),
+                Type::InterpolationEnd(text) => (text, true),
+                _ => {
+                    return Err(PgError::with_msg(
+                        format!(
+                            "expected `}}` closing the interpolation, found {:?}",
+                            token.t
+                        ),
+                        &token,
+                    ));
+                }
+            };
+            if !text.is_empty() {
+                parts.push(Node {
+                    token: Token {
+                        t: Type::String(text),
+                        ..token
+                    },
+                    inner: InnerNode::Atom,
+                });
+            }
+            if done {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node<'p>, PgError> {
+        let token = self.advance()?;
+        let inner = match token.t {
+            Type::Integer(_)
+            | Type::Double(_)
+            | Type::String(_)
+            | Type::True
+            | Type::False
+            | Type::None => InnerNode::Atom,
+            Type::Ident(_) if self.at(&Type::DelimitLeft) => {
+                self.advance()?;
+                InnerNode::Call {
+                    args: self.list(Type::DelimitRight, "`)`")?,
+                }
+            }
+            Type::Ident(_) => InnerNode::Ident,
+            Type::DelimitLeft => {
+                let inner = self.expr()?;
+                self.expect(Type::DelimitRight, "`)`")?;
+                return Ok(inner);
+            }
+            Type::InterpolationStart(_) => InnerNode::Interpolation {
+                parts: self.interpolation()?,
+            },
+            Type::BraketLeft => InnerNode::Array {
+                members: self.list(Type::BraketRight, "`]`")?,
+            },
+            Type::Match => {
+                self.expect(Type::CurlyLeft, "`{`")?;
+                let mut cases = vec![];
+                let mut default = None;
+                while !self.at(&Type::CurlyRight) {
+                    if self.at(&Type::CurlyLeft) {
+                        default = Some(Box::new(self.block()?));
+                        break;
+                    }
+                    let condition = self.expr()?;
+                    cases.push((condition, self.block()?));
+                }
+                self.expect(Type::CurlyRight, "`}`")?;
+                InnerNode::Match { cases, default }
+            }
+            Type::Std => {
+                let mut members = vec![];
+                loop {
+                    self.expect(Type::DoubleColon, "`::`")?;
+                    let member = self.expect(Type::Ident(""), "a path member")?;
+                    if self.at(&Type::DelimitLeft) {
+                        self.advance()?;
+                        let args = self.list(Type::DelimitRight, "`)`")?;
+                        let leaf = Box::new(Node {
+                            token: member,
+                            inner: InnerNode::Call { args },
+                        });
+                        break InnerNode::Path { members, leaf };
+                    }
+                    members.push(Node {
+                        token: member,
+                        inner: InnerNode::Ident,
+                    });
+                }
+            }
+            _ => {
+                return Err(PgError::with_msg(
+                    format!("expected an expression, found {:?}", token.t),
+                    &token,
+                ));
+            }
+        };
+        Ok(Node { token, inner })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast::{InnerNode, Node},
+        lex::{Lexer, Type},
+        parser::Parser,
+    };
+
+    /// s-expression of `node`, for comparing tree shapes
+    fn sexp(node: &Node) -> String {
+        let token = match node.token.t {
+            Type::Integer(s) | Type::Double(s) | Type::Ident(s) => s.to_string(),
+            Type::String(ref s) | Type::InterpolationStart(ref s) => format!("{s:?}"),
+            Type::Plus => "+".into(),
+            Type::Minus => "-".into(),
+            Type::Asteriks => "*".into(),
+            Type::Slash => "/".into(),
+            Type::Percent => "%".into(),
+            Type::Equal => "==".into(),
+            Type::NotEqual => "!=".into(),
+            Type::LessThan => "<".into(),
+            Type::GreaterThan => ">".into(),
+            Type::LessEqual => "<=".into(),
+            Type::GreaterEqual => ">=".into(),
+            Type::Exlaim => "!".into(),
+            Type::Ampersand => "&".into(),
+            Type::Pipe => "|".into(),
+            Type::Caret => "^".into(),
+            Type::Tilde => "~".into(),
+            Type::ShiftLeft => "<<".into(),
+            Type::ShiftRight => ">>".into(),
+            Type::DoubleAmpersand => "&&".into(),
+            Type::DoublePipe => "||".into(),
+            ref other => format!("{other:?}").to_lowercase(),
+        };
+        let children =
+            |nodes: &mut dyn Iterator<Item = &Node>| nodes.map(sexp).collect::<Vec<_>>().join(" ");
+        match &node.inner {
+            InnerNode::Atom | InnerNode::Ident => token,
+            InnerNode::Bin { lhs, rhs } => format!("({token} {} {})", sexp(lhs), sexp(rhs)),
+            InnerNode::Unary { rhs } => format!("({token} {})", sexp(rhs)),
+            InnerNode::Call { args } => format!("({token} {})", children(&mut args.iter())),
+            InnerNode::Array { members } => format!("[{}]", children(&mut members.iter())),
+            InnerNode::Interpolation { parts } => {
+                format!("(interp {token} {})", children(&mut parts.iter()))
+            }
+            InnerNode::Let { rhs } => format!("(let {token} {})", sexp(rhs)),
+            InnerNode::Match { cases, default } => format!(
+                "(match {}{})",
+                children(&mut cases.iter().flat_map(|(c, b)| [c, b])),
+                default
+                    .as_ref()
+                    .map(|d| format!(" else {}", sexp(d)))
+                    .unwrap_or_default()
+            ),
+            InnerNode::Path { members, leaf } => {
+                format!("(std {} {})", children(&mut members.iter()), sexp(leaf))
+            }
+            other => format!("{other:?}"),
+        }
+    }
+
+    fn parse(input: &str) -> String {
+        Parser::new(Lexer::new(input))
+            .and_then(Parser::parse)
+            .expect("Failed to parse")
+            .iter()
+            .map(sexp)
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    #[test]
+    fn precedence() {
+        let tests = [
+            ("1 + 2 * 3", "(+ 1 (* 2 3))"),
+            ("1 * 2 + 3", "(+ (* 1 2) 3)"),
+            ("1 - 2 - 3", "(- (- 1 2) 3)"),
+            ("8 / 4 % 3", "(% (/ 8 4) 3)"),
+            ("1 + 2 % 3", "(+ 1 (% 2 3))"),
+            ("(1 + 2) * 3", "(* (+ 1 2) 3)"),
+            ("1 + 2 < 3 * 4", "(< (+ 1 2) (* 3 4))"),
+            ("1 <= 2 == 3 >= 4", "(== (<= 1 2) (>= 3 4))"),
+            ("a != b == c", "(== (!= a b) c)"),
+            ("-a * b", "(* (- a) b)"),
+            ("-a - -b", "(- (- a) (- b))"),
+            ("!a == b", "(== (! a) b)"),
+            ("!!a", "(! (! a))"),
+            ("--2 % 3", "(% (- (- 2)) 3)"),
+            ("a - -1 < -b", "(< (- a (- 1)) (- b))"),
+            ("a || b && c", "(|| a (&& b c))"),
+            ("a && b || c && d", "(|| (&& a b) (&& c d))"),
+            ("a == 1 && !b || c < 2", "(|| (&& (== a 1) (! b)) (< c 2))"),
+            ("a && b && c", "(&& (&& a b) c)"),
+            ("a | b ^ c & d", "(| a (^ b (& c d)))"),
+            ("a & b | c & d", "(| (& a b) (& c d))"),
+            ("flags & 4 == 4", "(== (& flags 4) 4)"),
+            ("1 << 2 + 3", "(<< 1 (+ 2 3))"),
+            ("a >> 1 & 1 << b", "(& (>> a 1) (<< 1 b))"),
+            ("a < b << 1", "(< a (<< b 1))"),
+            ("~a & -b", "(& (~ a) (- b))"),
+            ("~~a", "(~ (~ a))"),
+            ("a | b || c & d", "(|| (| a b) (& c d))"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(parse(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn statements() {
+        assert_eq!(parse("let x = 1 + 2"), "(let x (+ 1 2))");
+        assert_eq!(
+            parse("square(25 5) [1 (-2) 3 -4]"),
+            "(square 25 5); [1 (- 2) (- 3 4)]"
+        );
+        assert_eq!(
+            parse("std::runtime::gc::cycle()"),
+            "(std runtime gc (cycle ))"
+        );
+        assert_eq!(
+            parse("match { a == 1 { 2 } none { 3 } { 4 } }"),
+            "(match (== a 1) 2 none 3 else 4)"
+        );
+    }
+
+    #[test]
+    fn interpolation() {
+        let tests = [
+            (
+                r#""hello {name}, you have {n + 1} items""#,
+                r#"(interp "hello " name ", you have " (+ n 1) " items")"#,
+            ),
+            (r#""{a}{b}""#, r#"(interp "" a b)"#),
+            (r#""a {"b {c}"}""#, r#"(interp "a " (interp "b " c))"#),
+            (
+                r#""{match { a { 1 } { 2 } }}!""#,
+                r#"(interp "" (match a 1 else 2) "!")"#,
+            ),
+            (r#""\{a}""#, r#""{a}""#),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(parse(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = |input| {
+            Parser::new(Lexer::new(input))
+                .and_then(Parser::parse)
+                .expect_err("should fail")
+                .to_string()
+        };
+        assert_eq!(
+            err("1 +"),
+            "err: expected an expression, found Eof at l:0:3-4"
+        );
+        assert_eq!(err("(1 + 2"), "err: expected `)`, found Eof at l:0:6-7");
+        assert_eq!(
+            err("let = 2"),
+            "err: expected a variable name, found Assign at l:0:4-5"
+        );
+        assert_eq!(
+            err("[1 2"),
+            "err: expected `]`, found end of input at l:0:4-5"
+        );
+        assert_eq!(err(r#""a {}""#), "err: empty interpolation at l:0:4-6");
+        assert_eq!(
+            err(r#""a {b c}""#),
+            "err: expected `}` closing the interpolation, found Ident(\"c\") at l:0:6-7"
+        );
+        assert_eq!(
+            err(r#""a {b"#),
+            "err: expected `}` closing the interpolation, found Eof at l:0:5-6"
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Builtins scripts reach via `std::` paths, for instance `std::runtime::gc::cycle()`. Cc resolves
+//! the path below `std` with [resolve] and emits an Op::Sys calling the builtin.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+};
+
+use crate::{
+    gc::{Map, Weak},
+    vm::{BuiltinFn, ErrorKind, Value, Vm},
+};
+
+/// Every builtin by its path below `std`
+pub fn builtins<'vm>() -> [(&'static str, BuiltinFn<'vm>); 7] {
+    [
+        ("runtime::gc::cycle", gc_cycle),
+        ("runtime::gc::stats", gc_stats),
+        ("runtime::gc::set_threshold", gc_set_threshold),
+        ("runtime::gc::set_budget", gc_set_budget),
+        ("runtime::gc::snapshot", gc_snapshot),
+        ("runtime::gc::weak", gc_weak),
+        ("runtime::gc::deref", gc_deref),
+    ]
+}
+
+/// Looks up the builtin at `path`, relative to `std`
+pub fn resolve<'vm>(path: &str) -> Option<BuiltinFn<'vm>> {
+    builtins()
+        .into_iter()
+        .find(|(name, _)| *name == path)
+        .map(|(_, f)| f)
+}
+
+fn arity(name: &'static str, args: &[Value], expected: usize) -> Result<(), ErrorKind> {
+    if args.len() != expected {
+        return Err(ErrorKind::Arity {
+            name,
+            expected,
+            got: args.len(),
+        });
+    }
+    Ok(())
+}
+
+/// the single int argument of a setter, negative values are clamped to 0
+fn count(name: &'static str, args: &[Value]) -> Result<usize, ErrorKind> {
+    arity(name, args, 1)?;
+    match args[0] {
+        Value::Int(i) => Ok(i.max(0) as usize),
+        other => Err(ErrorKind::Type {
+            op: name,
+            lhs: other.type_name(),
+            rhs: None,
+        }),
+    }
+}
+
+/// `std::runtime::gc::cycle()` runs a full collection and returns the number of freed objects
+fn gc_cycle<'vm>(vm: &mut Vm<'vm>, args: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+    arity("std::runtime::gc::cycle", args, 0)?;
+    Ok(Value::Int(vm.collect() as i64))
+}
+
+/// `std::runtime::gc::stats()` returns an object with the current heap size in bytes, the
+/// number of live objects, the threshold, the collections run and the pause times in
+/// microseconds
+fn gc_stats<'vm>(vm: &mut Vm<'vm>, args: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+    arity("std::runtime::gc::stats", args, 0)?;
+    let report = vm.gc_report();
+    let int = |n: usize| Value::Int(n as i64);
+    let stats = Map::from([
+        ("bytes".to_string(), int(report.bytes)),
+        ("objects".to_string(), int(report.objects)),
+        ("threshold".to_string(), int(report.threshold)),
+        ("collections".to_string(), int(report.stats.collections())),
+        ("minor".to_string(), int(report.stats.minor)),
+        ("major".to_string(), int(report.stats.major)),
+        ("slices".to_string(), int(report.stats.slices)),
+        (
+            "total_pause_us".to_string(),
+            int(report.stats.total_pause.as_micros() as usize),
+        ),
+        (
+            "max_pause_us".to_string(),
+            int(report.stats.max_pause.as_micros() as usize),
+        ),
+    ]);
+    Ok(Value::Obj(vm.alloc(stats)))
+}
+
+/// `std::runtime::gc::set_threshold(bytes)` see Heap::set_threshold, returns the previous
+/// threshold
+fn gc_set_threshold<'vm>(vm: &mut Vm<'vm>, args: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+    let bytes = count("std::runtime::gc::set_threshold", args)?;
+    let previous = vm.heap.threshold;
+    vm.set_gc_threshold(bytes);
+    Ok(Value::Int(previous as i64))
+}
+
+/// `std::runtime::gc::set_budget(units)` sets Heap::budget of the incremental collector, returns
+/// the previous budget
+fn gc_set_budget<'vm>(vm: &mut Vm<'vm>, args: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+    let budget = count("std::runtime::gc::set_budget", args)?;
+    let previous = std::mem::replace(&mut vm.heap.budget, budget);
+    Ok(Value::Int(previous as i64))
+}
+
+/// `std::runtime::gc::snapshot(path)` writes a heap snapshot to the file at `path`, see
+/// Vm::heap_snapshot, returns the number of objects in it
+fn gc_snapshot<'vm>(vm: &mut Vm<'vm>, args: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+    const NAME: &str = "std::runtime::gc::snapshot";
+    arity(NAME, args, 1)?;
+    let Some(path) = vm.str(&args[0]) else {
+        return Err(ErrorKind::Type {
+            op: NAME,
+            lhs: args[0].type_name(),
+            rhs: None,
+        });
+    };
+    let io = |e: io::Error| ErrorKind::Io(format!("{path}: {e}"));
+    let mut out = io::BufWriter::new(File::create(path).map_err(io)?);
+    vm.heap_snapshot(&mut out).map_err(io)?;
+    out.flush().map_err(io)?;
+    Ok(Value::Int(vm.heap.len() as i64))
+}
+
+/// `std::runtime::gc::weak(value)` returns a weak reference to the heap value `value`
+fn gc_weak<'vm>(vm: &mut Vm<'vm>, args: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+    const NAME: &str = "std::runtime::gc::weak";
+    arity(NAME, args, 1)?;
+    if args[0].heap_ref().is_none() {
+        return Err(ErrorKind::Type {
+            op: NAME,
+            lhs: args[0].type_name(),
+            rhs: None,
+        });
+    }
+    Ok(Value::Weak(vm.alloc(Weak::new(args[0]))))
+}
+
+/// `std::runtime::gc::deref(weak)` returns the target of `weak`, none once it was collected
+fn gc_deref<'vm>(vm: &mut Vm<'vm>, args: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+    const NAME: &str = "std::runtime::gc::deref";
+    arity(NAME, args, 1)?;
+    match args[0] {
+        Value::Weak(weak) => Ok(vm.heap.get(weak).get().unwrap_or(Value::None)),
+        other => Err(ErrorKind::Type {
+            op: NAME,
+            lhs: other.type_name(),
+            rhs: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asm,
+        gc::Collector,
+        stdlib::builtins,
+        vm::{ErrorKind, Value},
+    };
+
+    #[test]
+    #[cfg_attr(
+        feature = "gc-stress",
+        ignore = "gc-stress collects on every allocation"
+    )]
+    fn gc_builtins() {
+        let builtins = builtins();
+        let mut vm = asm::assemble(
+            ".code
+    new r0, 0, array
+    new r1, 0, array
+    loadi r1, 0
+    sys runtime::gc::cycle, r2, 0
+    loadi r3, 4096
+    sys runtime::gc::set_threshold, r3, 1
+    sys runtime::gc::stats, r4, 0
+",
+            &builtins,
+        )
+        .expect("Failed to assemble")
+        .into_vm();
+        vm.run().expect("Failed to run");
+
+        // the second array was only referenced by r1
+        assert_eq!(vm.registers[2], Some(Value::Int(1)));
+        assert_eq!(
+            vm.registers[3],
+            Some(Value::Int(crate::gc::INITIAL_THRESHOLD as i64))
+        );
+        assert_eq!(vm.heap.threshold, 4096);
+
+        let Some(Value::Obj(stats)) = vm.registers[4] else {
+            panic!("stats should return an object");
+        };
+        let stats = vm.heap.get(stats);
+        assert_eq!(stats["objects"], Value::Int(1));
+        assert_eq!(stats["threshold"], Value::Int(4096));
+        assert_eq!(stats["collections"], Value::Int(1));
+        assert!(matches!(stats["total_pause_us"], Value::Int(_)));
+    }
+
+    #[test]
+    fn weak_references() {
+        let builtins = builtins();
+        let mut vm = asm::assemble(
+            ".code
+    new r0, 0, array
+    sys runtime::gc::weak, r0, 1
+    new r5, 0, object
+    mov r2, r5
+    sys runtime::gc::weak, r2, 1
+    sys runtime::gc::cycle, r3, 0
+    mov r3, r0
+    sys runtime::gc::deref, r3, 1
+    mov r4, r2
+    sys runtime::gc::deref, r4, 1
+",
+            &builtins,
+        )
+        .expect("Failed to assemble")
+        .into_vm();
+        vm.run().expect("Failed to run");
+
+        // the array was only reachable through the weak reference in r0, the object is kept by r5
+        assert_eq!(vm.registers[3], Some(Value::None));
+        assert!(matches!(vm.registers[4], Some(Value::Obj(_))));
+    }
+
+    #[test]
+    fn returned_frames_are_no_roots() {
+        let builtins = builtins();
+        let mut vm = asm::assemble(
+            ".functions
+    f 0
+.code
+    call f, r20, 0
+    sys runtime::gc::cycle, r1, 0
+    call f, r20, 0
+    ret 1
+f:
+    sys runtime::gc::cycle, r0, 0
+    new r15, 0, array
+    ret 1
+",
+            &builtins,
+        )
+        .expect("Failed to assemble")
+        .into_vm();
+        vm.run().expect("Failed to run");
+
+        // the array of the first call was only referenced by the callees r15
+        assert_eq!(vm.registers[1], Some(Value::Int(1)));
+        assert_eq!(vm.registers[20 + 15], None);
+    }
+
+    /// every iteration creates a weak reference to a young array and checks its target, some of
+    /// the weak references are allocated right before a minor collection moves their target
+    #[test]
+    fn weak_references_across_minor_collections() {
+        let iterations = if cfg!(feature = "gc-stress") {
+            200
+        } else {
+            50_000
+        };
+        let builtins = builtins();
+        let src = format!(
+            ".code
+    loadi r1, 0
+    loadi r2, 1
+    loadi r3, {iterations}
+loop:
+    lt r4, r1, r3
+    jmpf r4, end
+    new r5, 1, array
+    append r5, r1
+    mov r6, r5
+    sys runtime::gc::weak, r6, 1
+    sys runtime::gc::deref, r6, 1
+    loadi r8, 0
+    idx r7, r6, r8
+    eq r8, r7, r1
+    jmpf r8, end
+    add r1, r1, r2
+    jmp loop
+end:
+"
+        );
+        let mut vm = asm::assemble(&src, &builtins)
+            .expect("Failed to assemble")
+            .into_vm();
+        vm.heap.collector = Collector::Generational;
+        vm.run().expect("Failed to run");
+        assert!(vm.heap.stats.minor > 0);
+        assert_eq!(vm.registers[1], Some(Value::Int(iterations)));
+    }
+
+    #[test]
+    fn argument_errors() {
+        let builtins = builtins();
+        let run = |src| {
+            asm::assemble(src, &builtins)
+                .expect("Failed to assemble")
+                .into_vm()
+                .run()
+                .expect_err("should fail")
+                .kind
+        };
+        assert_eq!(
+            run(".code\n    loadi r0, 1\n    sys runtime::gc::cycle, r0, 1"),
+            ErrorKind::Arity {
+                name: "std::runtime::gc::cycle",
+                expected: 0,
+                got: 1
+            }
+        );
+        assert_eq!(
+            run(".code\n    new r0, 0, array\n    sys runtime::gc::set_threshold, r0, 1"),
+            ErrorKind::Type {
+                op: "std::runtime::gc::set_threshold",
+                lhs: "array",
+                rhs: None
+            }
+        );
+    }
+
+    #[test]
+    fn snapshot() {
+        let path = std::env::temp_dir().join(format!("pg-snapshot-{}.json", std::process::id()));
+        let src = format!(
+            ".globals
+    {:?}
+.functions
+    hold 1
+.code
+    new r0, 0, array
+    loadi r1, 0
+    call hold, r1, 1
+    ret 1
+hold:
+    new r1, 0, object
+    let cache, r1
+    loadg r2, g0
+    sys runtime::gc::snapshot, r2, 1
+    ret 1
+",
+            path.to_str().unwrap()
+        );
+        let builtins = builtins();
+        asm::assemble(&src, &builtins)
+            .expect("Failed to assemble")
+            .into_vm()
+            .run()
+            .expect("Failed to run");
+
+        let snapshot = std::fs::read_to_string(&path).expect("Failed to read snapshot");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshot.matches("\"id\"").count(), 2);
+        assert!(snapshot.contains(r#"{"name": "<main> r0", "to": 0}"#));
+        assert!(snapshot.contains(r#"{"name": "hold r1", "to": 1}"#));
+        assert!(snapshot.contains(r#"{"name": "hold cache", "to": 1}"#));
+    }
+}
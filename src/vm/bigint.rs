@@ -0,0 +1,490 @@
+//! Arbitrary precision integers, the heap allocated form of ints outside of i64::MIN..=i64::MAX.
+//!
+//! Vm::run computes on i64 as long as results fit, see vm::int, and redoes operations that
+//! overflow on [BigInt]. Results are demoted back to Value::Int once they fit again, so an int
+//! has exactly one representation and a Value::BigInt is never in i64 range.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, Mul, Neg, Sub},
+};
+
+use crate::vm::int;
+
+/// Sign and magnitude, the magnitude as little endian base 2^32 digits
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BigInt {
+    /// never set for zero
+    negative: bool,
+    /// without trailing zero digits, empty for zero
+    digits: Vec<u32>,
+}
+
+impl BigInt {
+    fn new(negative: bool, digits: Vec<u32>) -> Self {
+        let digits = trim(digits);
+        BigInt {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+
+    /// Parses decimal digits with an optional leading `-`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (negative, decimal) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        if decimal.is_empty() || !decimal.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let mut digits = vec![];
+        // 9 decimal digits at a time still fit into a u32
+        for chunk in decimal.as_bytes().chunks(9) {
+            let value = chunk.iter().fold(0, |acc, b| acc * 10 + (b - b'0') as u32);
+            mul_add_small(&mut digits, 10u32.pow(chunk.len() as u32), value);
+        }
+        Some(BigInt::new(negative, digits))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    /// approximate heap bytes of the digits
+    pub fn size(&self) -> usize {
+        self.digits.capacity() * size_of::<u32>()
+    }
+
+    /// the nearest double, infinity beyond f64::MAX
+    pub fn to_f64(&self) -> f64 {
+        let bits = self.bits();
+        let magnitude = if bits <= 64 {
+            self.bits_at(0) as f64
+        } else {
+            // the top 64 bits and a sticky bit for everything below them round exactly like the
+            // full magnitude, a double only has 53 bits of precision
+            let shift = bits - 64;
+            let sticky = self.digits[..shift / 32].iter().any(|d| *d != 0)
+                || self.digits[shift / 32] & ((1 << (shift % 32)) - 1) != 0;
+            let top = (self.bits_at(shift) | sticky as u64) as f64;
+            match shift {
+                // 2^shift built from its exponent bits
+                ..=1023 => top * f64::from_bits(((shift + 1023) as u64) << 52),
+                _ => f64::INFINITY,
+            }
+        };
+        if self.negative { -magnitude } else { magnitude }
+    }
+
+    /// exact order of self and `rhs`, None if rhs is NaN
+    pub fn cmp_f64(&self, rhs: f64) -> Option<Ordering> {
+        if rhs.is_nan() {
+            return None;
+        }
+        if let Ok(int) = i64::try_from(self) {
+            return int::cmp_f64(int, rhs);
+        }
+        if rhs.is_infinite() {
+            return Some(if rhs > 0.0 {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            });
+        }
+        // self is outside of i64 range, so it is ordered against smaller doubles by its sign,
+        // larger ones are integral and convert exactly
+        if rhs.abs() < 9223372036854775808.0 {
+            return Some(if self.negative {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            });
+        }
+        Some(self.cmp(&BigInt::from_integral_f64(rhs)))
+    }
+
+    /// `value`, which has to be integral and finite, at least 2^53 in magnitude
+    fn from_integral_f64(value: f64) -> Self {
+        let bits = value.to_bits();
+        let mantissa = bits & ((1 << 52) - 1) | 1 << 52;
+        let exponent = ((bits >> 52) & 0x7ff) as usize - 1075;
+        let mut pow2 = vec![0; exponent / 32 + 1];
+        pow2[exponent / 32] = 1 << (exponent % 32);
+        let magnitude = &BigInt::from(mantissa as i64) * &BigInt::new(false, pow2);
+        BigInt::new(value < 0.0, magnitude.digits)
+    }
+
+    /// number of significant bits of the magnitude
+    fn bits(&self) -> usize {
+        self.digits.last().map_or(0, |top| {
+            self.digits.len() * 32 - top.leading_zeros() as usize
+        })
+    }
+
+    /// the 64 bits of the magnitude starting at bit `offset`
+    fn bits_at(&self, offset: usize) -> u64 {
+        (0..64).fold(0, |acc, i| {
+            let bit = offset + i;
+            let digit = self.digits.get(bit / 32).map_or(0, |d| d >> (bit % 32) & 1);
+            acc | (digit as u64) << i
+        })
+    }
+
+    /// Truncating division, the quotient rounds towards zero and the remainder has the sign of
+    /// self, like i64 / and %. None for a zero divisor.
+    pub fn div_rem(&self, rhs: &BigInt) -> Option<(BigInt, BigInt)> {
+        if rhs.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem_digits(&self.digits, &rhs.digits);
+        Some((
+            BigInt::new(self.negative != rhs.negative, quotient),
+            BigInt::new(self.negative, remainder),
+        ))
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let magnitude = value.unsigned_abs();
+        BigInt::new(value < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+}
+
+impl TryFrom<&BigInt> for i64 {
+    type Error = ();
+
+    fn try_from(value: &BigInt) -> Result<Self, Self::Error> {
+        if value.digits.len() > 2 {
+            return Err(());
+        }
+        let magnitude = value
+            .digits
+            .iter()
+            .rev()
+            .fold(0u64, |acc, d| acc << 32 | *d as u64);
+        match (value.negative, magnitude) {
+            (false, m) if m <= i64::MAX as u64 => Ok(m as i64),
+            // i64::MIN has no positive counterpart, wrapping negation maps 2^63 onto it
+            (true, m) if m <= i64::MIN.unsigned_abs() => Ok((m as i64).wrapping_neg()),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_digits(&self.digits, &other.digits),
+            (true, true) => cmp_digits(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, rhs: &BigInt) -> BigInt {
+        if self.negative == rhs.negative {
+            return BigInt::new(self.negative, add_digits(&self.digits, &rhs.digits));
+        }
+        // the sign of the operand with the larger magnitude wins
+        match cmp_digits(&self.digits, &rhs.digits) {
+            Ordering::Less => BigInt::new(rhs.negative, sub_digits(&rhs.digits, &self.digits)),
+            _ => BigInt::new(self.negative, sub_digits(&self.digits, &rhs.digits)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, rhs: &BigInt) -> BigInt {
+        self + &-rhs
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, rhs: &BigInt) -> BigInt {
+        let mut digits = vec![0u32; self.digits.len() + rhs.digits.len()];
+        for (i, &l) in self.digits.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &r) in rhs.digits.iter().enumerate() {
+                let product = l as u64 * r as u64 + digits[i + j] as u64 + carry;
+                digits[i + j] = product as u32;
+                carry = product >> 32;
+            }
+            digits[i + rhs.digits.len()] = carry as u32;
+        }
+        BigInt::new(self.negative != rhs.negative, digits)
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.digits.clone())
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+        // base 10^9 digits, least significant first
+        let mut chunks = vec![];
+        let mut rest = self.digits.clone();
+        while !rest.is_empty() {
+            let (quotient, remainder) = div_rem_small(&rest, 1_000_000_000);
+            chunks.push(remainder);
+            rest = quotient;
+        }
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap_or(&0))?;
+        chunks.try_for_each(|chunk| write!(f, "{chunk:09}"))
+    }
+}
+
+fn trim(mut digits: Vec<u32>) -> Vec<u32> {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    digits
+}
+
+fn cmp_digits(lhs: &[u32], rhs: &[u32]) -> Ordering {
+    lhs.len()
+        .cmp(&rhs.len())
+        .then_with(|| lhs.iter().rev().cmp(rhs.iter().rev()))
+}
+
+fn add_digits(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let (long, short) = if lhs.len() >= rhs.len() {
+        (lhs, rhs)
+    } else {
+        (rhs, lhs)
+    };
+    let mut digits = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, &d) in long.iter().enumerate() {
+        let sum = d as u64 + *short.get(i).unwrap_or(&0) as u64 + carry;
+        digits.push(sum as u32);
+        carry = sum >> 32;
+    }
+    digits.push(carry as u32);
+    trim(digits)
+}
+
+/// `lhs - rhs` for `lhs >= rhs`
+fn sub_digits(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let mut digits = Vec::with_capacity(lhs.len());
+    let mut borrow = 0i64;
+    for (i, &d) in lhs.iter().enumerate() {
+        let mut diff = d as i64 - *rhs.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = (diff < 0) as i64;
+        diff += borrow << 32;
+        digits.push(diff as u32);
+    }
+    trim(digits)
+}
+
+/// `digits * m + add` in place
+fn mul_add_small(digits: &mut Vec<u32>, m: u32, add: u32) {
+    let mut carry = add as u64;
+    for d in digits.iter_mut() {
+        let product = *d as u64 * m as u64 + carry;
+        *d = product as u32;
+        carry = product >> 32;
+    }
+    if carry != 0 {
+        digits.push(carry as u32);
+    }
+}
+
+fn div_rem_small(lhs: &[u32], rhs: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0; lhs.len()];
+    let mut remainder = 0u64;
+    for (i, &d) in lhs.iter().enumerate().rev() {
+        let current = remainder << 32 | d as u64;
+        quotient[i] = (current / rhs as u64) as u32;
+        remainder = current % rhs as u64;
+    }
+    (trim(quotient), remainder as u32)
+}
+
+/// Magnitude division for a non zero `rhs`, binary long division unless rhs is a single digit
+fn div_rem_digits(lhs: &[u32], rhs: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if let [rhs] = rhs {
+        let (quotient, remainder) = div_rem_small(lhs, *rhs);
+        return (quotient, trim(vec![remainder]));
+    }
+    let mut quotient = vec![0u32; lhs.len()];
+    let mut remainder: Vec<u32> = vec![];
+    for bit in (0..lhs.len() * 32).rev() {
+        // remainder = remainder << 1 | the next bit of lhs
+        let mut carry = lhs[bit / 32] >> (bit % 32) & 1;
+        for d in remainder.iter_mut() {
+            let next = *d >> 31;
+            *d = *d << 1 | carry;
+            carry = next;
+        }
+        if carry != 0 {
+            remainder.push(carry);
+        }
+        if cmp_digits(&remainder, rhs) != Ordering::Less {
+            remainder = sub_digits(&remainder, rhs);
+            quotient[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+    (trim(quotient), remainder)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering::{Equal, Greater, Less};
+
+    use crate::vm::bigint::BigInt;
+
+    fn big(s: &str) -> BigInt {
+        BigInt::parse(s).expect("Failed to parse")
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for s in [
+            "0",
+            "1",
+            "-1",
+            "4294967296",
+            "9223372036854775808",
+            "-9223372036854775809",
+            "123456789012345678901234567890123456789",
+            "-1000000000000000000000000000000",
+        ] {
+            assert_eq!(big(s).to_string(), s);
+        }
+        assert_eq!(big("-0"), BigInt::default());
+        assert_eq!(big("000123").to_string(), "123");
+        for invalid in ["", "-", "1.5", "12a", "--1", "+1"] {
+            assert_eq!(BigInt::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn i64_conversions() {
+        for i in [
+            0,
+            1,
+            -1,
+            i64::MAX,
+            i64::MIN,
+            i64::MIN + 1,
+            1 << 32,
+            -(1 << 32),
+        ] {
+            assert_eq!(BigInt::from(i).to_string(), i.to_string());
+            assert_eq!(i64::try_from(&BigInt::from(i)), Ok(i));
+        }
+        assert_eq!(i64::try_from(&big("9223372036854775808")), Err(()));
+        assert_eq!(i64::try_from(&big("-9223372036854775809")), Err(()));
+        assert_eq!(i64::try_from(&big("18446744073709551616")), Err(()));
+    }
+
+    /// every operation against i128 arithmetic
+    #[test]
+    fn arithmetic() {
+        let values: [i128; 12] = [
+            0,
+            1,
+            -1,
+            7,
+            -7,
+            u32::MAX as i128,
+            i64::MAX as i128,
+            i64::MIN as i128,
+            i64::MAX as i128 * 3,
+            -(i64::MAX as i128) * 5 - 11,
+            1 << 100,
+            -(1 << 90) + 12345,
+        ];
+        let big = |i: i128| big(&i.to_string());
+        for l in values {
+            for r in values {
+                let (bl, br) = (big(l), big(r));
+                assert_eq!(&bl + &br, big(l + r), "{l} + {r}");
+                assert_eq!(&bl - &br, big(l - r), "{l} - {r}");
+                if let Some(product) = l.checked_mul(r) {
+                    assert_eq!(&bl * &br, big(product), "{l} * {r}");
+                }
+                assert_eq!(bl.cmp(&br), l.cmp(&r), "{l} cmp {r}");
+                if r == 0 {
+                    assert_eq!(bl.div_rem(&br), None);
+                } else {
+                    assert_eq!(bl.div_rem(&br), Some((big(l / r), big(l % r))), "{l} / {r}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn doubles() {
+        for i in [0, 1, -1, i64::MAX, i64::MIN, 1 << 53, (1 << 53) + 1] {
+            assert_eq!(BigInt::from(i).to_f64(), i as f64, "{i}");
+        }
+        assert_eq!(big("100000000000000000000").to_f64(), 1e20);
+        assert_eq!(
+            big("-18446744073709551616").to_f64(),
+            -18446744073709551616.0
+        );
+        // 2^64 + 2^11 + 1 is just above the midpoint between two doubles and rounds up
+        assert_eq!(big("18446744073709553665").to_f64(), 18446744073709555712.0);
+        assert_eq!(big(&"9".repeat(400)).to_f64(), f64::INFINITY);
+        assert_eq!(
+            big(&format!("-{}", "9".repeat(400))).to_f64(),
+            f64::NEG_INFINITY
+        );
+
+        let two_63 = 9223372036854775808.0;
+        assert_eq!(big("9223372036854775808").cmp_f64(two_63), Some(Equal));
+        assert_eq!(big("9223372036854775809").cmp_f64(two_63), Some(Greater));
+        assert_eq!(big("-9223372036854775809").cmp_f64(-two_63), Some(Less));
+        assert_eq!(big("100000000000000000000").cmp_f64(1e20), Some(Equal));
+        assert_eq!(big("100000000000000000001").cmp_f64(1e20), Some(Greater));
+        assert_eq!(big("99999999999999999999").cmp_f64(1e20), Some(Less));
+        assert_eq!(big("100000000000000000000").cmp_f64(1.5), Some(Greater));
+        assert_eq!(big("-100000000000000000000").cmp_f64(1.5), Some(Less));
+        assert_eq!(
+            big("-100000000000000000000").cmp_f64(f64::NEG_INFINITY),
+            Some(Greater)
+        );
+        assert_eq!(big(&"9".repeat(400)).cmp_f64(f64::INFINITY), Some(Less));
+        assert_eq!(big(&"9".repeat(400)).cmp_f64(f64::MAX), Some(Greater));
+        assert_eq!(BigInt::from(3).cmp_f64(2.5), Some(Greater));
+        assert_eq!(big("100000000000000000000").cmp_f64(f64::NAN), None);
+    }
+
+    #[test]
+    fn large_products() {
+        let factorial = (1..=30).fold(BigInt::from(1), |acc, i| &acc * &BigInt::from(i));
+        assert_eq!(factorial.to_string(), "265252859812191058636308480000000");
+        let (quotient, remainder) = factorial
+            .div_rem(&big("8841761993739701954543616000000"))
+            .unwrap();
+        assert_eq!(quotient, BigInt::from(30));
+        assert!(remainder.is_zero());
+    }
+}
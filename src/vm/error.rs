@@ -0,0 +1,102 @@
+use std::fmt;
+
+use crate::err::{PgError, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// Vm::verify rejected the bytecode before it was run
+    Verify(String),
+    DivisionByZero,
+    /// the right operand of a shift was negative
+    NegativeShift(i64),
+    /// an operation applied to operands of types it is not defined for
+    Type {
+        op: &'static str,
+        lhs: &'static str,
+        rhs: Option<&'static str>,
+    },
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    /// name of the variable if known, its hash otherwise
+    UndefinedVariable(String),
+    UndefinedFunction(usize),
+    /// a builtin was called with the wrong number of arguments
+    Arity {
+        name: &'static str,
+        expected: usize,
+        got: usize,
+    },
+    /// a builtin failed to read or write a file
+    Io(String),
+    /// the call stack reached Vm::max_depth frames
+    StackOverflow {
+        depth: usize,
+    },
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Verify(msg) => write!(f, "invalid bytecode: {msg}"),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::NegativeShift(amount) => write!(f, "shift by negative amount {amount}"),
+            ErrorKind::Type {
+                op,
+                lhs,
+                rhs: Some(rhs),
+            } => write!(f, "`{op}` is not defined for {lhs} and {rhs}"),
+            ErrorKind::Type { op, lhs, rhs: None } => write!(f, "`{op}` is not defined for {lhs}"),
+            ErrorKind::IndexOutOfBounds { index, len } => {
+                write!(f, "index {index} is out of bounds for length {len}")
+            }
+            ErrorKind::UndefinedVariable(name) => write!(f, "undefined variable `{name}`"),
+            ErrorKind::UndefinedFunction(func) => write!(f, "undefined function {func}"),
+            ErrorKind::Arity {
+                name,
+                expected,
+                got,
+            } => write!(f, "`{name}` takes {expected} arguments, got {got}"),
+            ErrorKind::Io(msg) => write!(f, "io error: {msg}"),
+            ErrorKind::StackOverflow { depth } => {
+                write!(f, "stack overflow, calls nested deeper than {depth} frames")
+            }
+        }
+    }
+}
+
+/// A single purple garden call frame of a runtime error
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// name of the function, `<main>` for the top level
+    pub name: String,
+    /// location of the failing instruction in the innermost frame and of the call in all others
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    /// word offset of the failing instruction
+    pub pc: usize,
+    /// innermost frame first
+    pub trace: Vec<TraceFrame>,
+}
+
+impl From<RuntimeError> for PgError {
+    fn from(value: RuntimeError) -> Self {
+        let span = value
+            .trace
+            .first()
+            .and_then(|frame| frame.span)
+            .unwrap_or_default();
+        PgError::with_msg(value.kind.to_string(), span).with_trace(
+            value
+                .trace
+                .into_iter()
+                .map(|frame| (frame.name, frame.span))
+                .collect(),
+        )
+    }
+}
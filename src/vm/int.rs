@@ -0,0 +1,154 @@
+//! Integer semantics shared by Vm::run and the constant folding of Cc, so folded expressions
+//! evaluate to exactly what the vm would have computed.
+//!
+//! Ints are i64 as long as results fit. The operations below return None for results that do
+//! not fit and for a zero divisor, Vm::run then redoes them on BigInt, which promotes overflowing
+//! results and raises ErrorKind::DivisionByZero, and Cc leaves them to the runtime. Shifts are
+//! bit operations on i64 only and drop the bits shifted out instead of promoting.
+//!
+//! Mixed with doubles, ints are numbers like any other:
+//!
+//! - arithmetic with a double operand is double arithmetic, the int is rounded to the nearest
+//!   double first, so `1 / 0.0` is infinity and `0 / 0.0` NaN
+//! - comparisons and equality are exact, ints are never rounded: `1 == 1.0`, and
+//!   `9007199254740993 > 9007199254740992.0` although the int rounds to exactly that double
+//! - NaN is unordered, every comparison with it is false and it is unequal to everything,
+//!   itself included
+
+use std::cmp::Ordering;
+
+use crate::vm::ErrorKind;
+
+/// 2^63 as a double, the first double above i64::MAX
+const I64_END: f64 = 9223372036854775808.0;
+
+/// exact order of `lhs` and `rhs`, None if rhs is NaN
+pub fn cmp_f64(lhs: i64, rhs: f64) -> Option<Ordering> {
+    if rhs.is_nan() {
+        return None;
+    }
+    if rhs >= I64_END {
+        return Some(Ordering::Less);
+    }
+    if rhs < -I64_END {
+        return Some(Ordering::Greater);
+    }
+    // rhs is in i64 range, its integral part converts exactly, its fractional part decides ties
+    let integral = rhs.trunc();
+    Some(
+        lhs.cmp(&(integral as i64))
+            .then_with(|| 0.0.partial_cmp(&(rhs - integral)).unwrap()),
+    )
+}
+
+pub fn add(lhs: i64, rhs: i64) -> Option<i64> {
+    lhs.checked_add(rhs)
+}
+
+pub fn sub(lhs: i64, rhs: i64) -> Option<i64> {
+    lhs.checked_sub(rhs)
+}
+
+pub fn mul(lhs: i64, rhs: i64) -> Option<i64> {
+    lhs.checked_mul(rhs)
+}
+
+/// `lhs / rhs`, rounding towards zero
+pub fn div(lhs: i64, rhs: i64) -> Option<i64> {
+    lhs.checked_div(rhs)
+}
+
+/// `lhs % rhs`, the result has the sign of lhs
+pub fn rem(lhs: i64, rhs: i64) -> Option<i64> {
+    // i64::MIN % -1 only overflows in the division, its remainder is exactly 0
+    match rhs {
+        -1 => Some(0),
+        _ => lhs.checked_rem(rhs),
+    }
+}
+
+/// `-rhs`
+pub fn neg(rhs: i64) -> Option<i64> {
+    rhs.checked_neg()
+}
+
+/// the shift amount `rhs`, amounts above 64 are clamped to it
+fn amount(rhs: i64) -> Result<u32, ErrorKind> {
+    if rhs < 0 {
+        return Err(ErrorKind::NegativeShift(rhs));
+    }
+    Ok(rhs.min(64) as u32)
+}
+
+/// `lhs << rhs`, shifting by 64 or more bits shifts every bit out and yields 0
+pub fn shl(lhs: i64, rhs: i64) -> Result<i64, ErrorKind> {
+    Ok(lhs.checked_shl(amount(rhs)?).unwrap_or(0))
+}
+
+/// `lhs >> rhs`, arithmetic: the sign bit is shifted in, so shifting by 64 or more bits yields 0
+/// for positive and -1 for negative lhs
+pub fn shr(lhs: i64, rhs: i64) -> Result<i64, ErrorKind> {
+    Ok(lhs >> amount(rhs)?.min(63))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        ErrorKind,
+        int::{add, cmp_f64, div, mul, neg, rem, shl, shr, sub},
+    };
+    use std::cmp::Ordering::{Equal, Greater, Less};
+
+    #[test]
+    fn double_comparisons() {
+        assert_eq!(cmp_f64(1, 1.0), Some(Equal));
+        assert_eq!(cmp_f64(1, 1.5), Some(Less));
+        assert_eq!(cmp_f64(2, 1.5), Some(Greater));
+        assert_eq!(cmp_f64(-1, -1.5), Some(Greater));
+        assert_eq!(cmp_f64(-2, -1.5), Some(Less));
+        assert_eq!(cmp_f64(0, -0.0), Some(Equal));
+        // both round to 2^53 as doubles
+        assert_eq!(cmp_f64(9007199254740993, 9007199254740992.0), Some(Greater));
+        assert_eq!(cmp_f64(i64::MAX, 9223372036854775808.0), Some(Less));
+        assert_eq!(cmp_f64(i64::MIN, -9223372036854775808.0), Some(Equal));
+        assert_eq!(cmp_f64(i64::MIN, -9223372036854777856.0), Some(Greater));
+        assert_eq!(cmp_f64(i64::MAX, f64::INFINITY), Some(Less));
+        assert_eq!(cmp_f64(i64::MIN, f64::NEG_INFINITY), Some(Greater));
+        assert_eq!(cmp_f64(0, f64::NAN), None);
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(add(i64::MAX - 1, 1), Some(i64::MAX));
+        assert_eq!(add(i64::MAX, 1), None);
+        assert_eq!(add(i64::MIN, -1), None);
+        assert_eq!(sub(i64::MIN + 1, 1), Some(i64::MIN));
+        assert_eq!(sub(i64::MIN, 1), None);
+        assert_eq!(sub(0, i64::MIN), None);
+        assert_eq!(mul(i64::MAX, -1), Some(-i64::MAX));
+        assert_eq!(mul(i64::MIN, -1), None);
+        assert_eq!(mul(i64::MAX, 2), None);
+        assert_eq!(div(i64::MIN, 1), Some(i64::MIN));
+        assert_eq!(div(i64::MIN, -1), None);
+        assert_eq!(div(1, 0), None);
+        assert_eq!(rem(i64::MIN, -1), Some(0));
+        assert_eq!(rem(-7, 3), Some(-1));
+        assert_eq!(rem(1, 0), None);
+        assert_eq!(neg(i64::MAX), Some(-i64::MAX));
+        assert_eq!(neg(i64::MIN), None);
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(shl(1, 3), Ok(8));
+        assert_eq!(shl(1, 63), Ok(i64::MIN));
+        assert_eq!(shl(-1, 64), Ok(0));
+        assert_eq!(shl(1, i64::MAX), Ok(0));
+        assert_eq!(shr(-16, 2), Ok(-4));
+        assert_eq!(shr(i64::MAX, 63), Ok(0));
+        assert_eq!(shr(5, 64), Ok(0));
+        assert_eq!(shr(-5, 1000), Ok(-1));
+        assert_eq!(shl(1, -1), Err(ErrorKind::NegativeShift(-1)));
+        assert_eq!(shr(1, i64::MIN), Err(ErrorKind::NegativeShift(i64::MIN)));
+    }
+}
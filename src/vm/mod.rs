@@ -1,26 +1,1639 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, cmp::Ordering, collections::HashMap, io};
 
+mod bigint;
+mod error;
+pub mod int;
 mod value;
+mod verify;
 
+/// registers addressable by a single frame, the size of its register window
 pub const REGISTER_COUNT: usize = 32;
+// register indexes are masked with REGISTER_COUNT - 1, see Vm::reg
+const _: () = assert!(REGISTER_COUNT.is_power_of_two());
 
-use crate::op::Op;
-pub use crate::vm::value::Value;
+pub use crate::vm::{
+    bigint::BigInt,
+    error::{ErrorKind, RuntimeError, TraceFrame},
+    value::Value,
+};
+use crate::{
+    bc::{self, Bytecode, LineTable, opcode},
+    err::Span,
+    gc::{Array, Gc, Heap, Managed, Map, Report},
+    op::Op,
+};
 
-#[derive(Default, Debug)]
-pub struct Frame<'frame> {
-    variables: HashMap<&'frame str, Value<'frame>>,
-    return_to: usize,
-    prev: Option<Box<Frame<'frame>>>,
+/// Default for Vm::max_depth
+pub const MAX_DEPTH: usize = 1024;
+
+/// Instructions Vm::run executes between two slices of a running incremental collection, so the
+/// cycle finishes even if the program stops allocating
+pub const SLICE_INTERVAL: usize = 1024;
+
+/// A call frame record, all frames live contiguously in Vm::frames, the outermost first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// word offset to continue at once the frame returns
+    return_to: u32,
+    /// first register of the frames register window in Vm::registers
+    base: u32,
+    /// start of the frames variable slots in Vm::locals
+    locals: u32,
+    /// index into Vm::functions, Frame::TOP_LEVEL for the top level
+    func: u32,
+}
+
+impl Frame {
+    const TOP_LEVEL: u32 = u32::MAX;
+
+    fn top_level() -> Self {
+        Frame {
+            return_to: 0,
+            base: 0,
+            locals: 0,
+            func: Self::TOP_LEVEL,
+        }
+    }
+}
+
+/// A compiled purple garden function, Op::Call::func indexes into Vm::functions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function<'f> {
+    pub name: &'f str,
+    /// index of the first op of the function in the builder form, translated into a word offset
+    /// into Vm::bytecode by Vm::new
+    pub pc: usize,
+    pub args: u8,
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct Vm<'vm> {
-    pub registers: [Option<Value<'vm>>; REGISTER_COUNT],
+    /// the register stack, each frame sees the REGISTER_COUNT registers starting at its
+    /// Frame::base as r0..r{REGISTER_COUNT-1}. A call slides the window up to the first argument,
+    /// so the callee finds its arguments in r0.. without copying and the registers of the caller
+    /// below the arguments stay untouched. The callee returns its result in its r0, which is the
+    /// register the caller passed its first argument in. Every other register of the caller from
+    /// the first argument up belongs to the callee's window and is overwritten, callers therefore
+    /// pass arguments above every register they still need and Vm::verify rejects reads of those
+    /// registers after the call.
+    pub registers: Vec<Option<Value<'vm>>>,
+    /// Frame::base of the current frame
+    base: usize,
     pub pc: usize,
-    pub frame: Frame<'vm>,
-    pub bytecode: Vec<Op<'vm>>,
+    /// the call stack, never empty, the last frame is the currently executing one
+    pub frames: Vec<Frame>,
+    /// variables of all frames, keyed by the hash Op::Let and Op::LoadV carry, each frame owns the
+    /// slots from its Frame::locals to the next frames Frame::locals
+    pub locals: Vec<(u64, Value<'vm>)>,
+    /// calls nesting deeper than this fail with ErrorKind::StackOverflow
+    pub max_depth: usize,
+    pub bytecode: Bytecode<'vm>,
     pub globals: Vec<Value<'vm>>,
+    pub functions: Vec<Function<'vm>>,
+    /// identifier names by their hash, for error messages
+    pub symbols: HashMap<u64, &'vm str>,
+    /// arrays, objects and runtime strings, see Vm::collect for its roots
+    pub heap: Heap<'vm>,
+    /// set by Vm::run once Vm::verify accepted the bytecode
+    verified: bool,
+}
+
+/// A host function Op::Sys calls with the values of its argument registers, the result is written
+/// to the first argument register, like the result of Op::Call
+pub type BuiltinFn<'vm> = fn(&mut Vm<'vm>, &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind>;
+
+impl Default for Vm<'_> {
+    fn default() -> Self {
+        Vm {
+            registers: vec![None; REGISTER_COUNT],
+            base: 0,
+            pc: 0,
+            frames: vec![Frame::top_level()],
+            locals: vec![],
+            max_depth: MAX_DEPTH,
+            bytecode: Bytecode::default(),
+            globals: vec![],
+            functions: vec![],
+            symbols: HashMap::new(),
+            heap: Heap::default(),
+            verified: false,
+        }
+    }
 }
 
-pub type BuiltinFn<'vm> = fn(&mut Vm<'vm>, &[Value]);
+impl<'vm> Vm<'vm> {
+    /// Packs `ops` and translates the op index entries of `functions` and `lines` into word
+    /// offsets
+    pub fn new(
+        ops: &[Op<'vm>],
+        globals: Vec<Value<'vm>>,
+        mut functions: Vec<Function<'vm>>,
+        mut lines: LineTable,
+    ) -> Self {
+        let (mut bytecode, offsets) = bc::encode(ops);
+        for f in &mut functions {
+            f.pc = offsets[f.pc];
+        }
+        lines.translate(&offsets);
+        bytecode.lines = lines;
+        Vm {
+            bytecode,
+            globals,
+            functions,
+            ..Default::default()
+        }
+    }
+
+    /// Source location the instruction at `pc` was compiled from
+    pub fn span(&self, pc: usize) -> Option<Span> {
+        self.bytecode.lines.lookup(pc)
+    }
+
+    /// Allocates `value` on the heap, then runs the due collection once the heap grew past its
+    /// thresholds. The new object is a root of that collection, so the handles it holds, weak
+    /// targets included, are kept valid like any other reference. Handles only held by the host
+    /// and not by Vm::roots or `value` do not survive this.
+    pub fn alloc<T: Managed<'vm>>(&mut self, value: T) -> Gc<T> {
+        let gc = self.heap.alloc(value);
+        if !self.heap.should_collect() {
+            return gc;
+        }
+        let mut new = T::into_value(gc);
+        let (heap, roots) = self.roots();
+        heap.collect(roots.chain(std::iter::once(&mut new)));
+        T::from_value(new)
+    }
+
+    /// Runs a full collection, see Vm::alloc, returns the number of freed objects
+    pub fn collect(&mut self) -> usize {
+        let before = self.heap.len();
+        let (heap, roots) = self.roots();
+        heap.collect_full(roots);
+        before - self.heap.len()
+    }
+
+    pub fn gc_report(&self) -> Report {
+        self.heap.report()
+    }
+
+    /// see Heap::set_threshold
+    pub fn set_gc_threshold(&mut self, bytes: usize) {
+        self.heap.set_threshold(bytes);
+    }
+
+    /// The heap and the roots of its collections: the register windows of all frames, the
+    /// variables of all frames and the globals
+    fn roots(&mut self) -> (&mut Heap<'vm>, impl Iterator<Item = &mut Value<'vm>>) {
+        // registers above the current window were cleared when their frames returned
+        let live = (self.base + REGISTER_COUNT).min(self.registers.len());
+        let roots = self.registers[..live]
+            .iter_mut()
+            .flatten()
+            .chain(self.locals.iter_mut().map(|(_, value)| value))
+            .chain(self.globals.iter_mut());
+        (&mut self.heap, roots)
+    }
+
+    /// Writes a JSON snapshot of the heap, see the gc::snapshot module, roots are named by their
+    /// frame and register, their variable or their global
+    pub fn heap_snapshot(&self, out: &mut impl io::Write) -> io::Result<()> {
+        let mut roots: Vec<(String, &Value<'vm>)> = vec![];
+        let name = |func: u32| match func {
+            Frame::TOP_LEVEL => "<main>",
+            func => self.functions[func as usize].name,
+        };
+        for (i, frame) in self.frames.iter().enumerate() {
+            let next = self.frames.get(i + 1);
+            // a callees window starts at the callers argument registers, these belong to it
+            let registers = frame.base as usize
+                ..next.map_or(self.base + REGISTER_COUNT, |next| next.base as usize);
+            for (r, value) in self.registers[registers].iter().enumerate() {
+                if let Some(value) = value {
+                    roots.push((format!("{} r{r}", name(frame.func)), value));
+                }
+            }
+            let locals =
+                frame.locals as usize..next.map_or(self.locals.len(), |next| next.locals as usize);
+            for (hash, value) in &self.locals[locals] {
+                let var = match self.symbols.get(hash) {
+                    Some(var) => var.to_string(),
+                    None => format!("{hash:#x}"),
+                };
+                roots.push((format!("{} {var}", name(frame.func)), value));
+            }
+        }
+        for (i, value) in self.globals.iter().enumerate() {
+            roots.push((format!("g{i}"), value));
+        }
+        self.heap.snapshot(roots.into_iter(), out)
+    }
+
+    /// contents of Str and String values
+    pub fn str(&self, value: &Value<'vm>) -> Option<&str> {
+        match value {
+            Value::Str(s) => Some(s),
+            Value::String(s) => Some(self.heap.get(*s).as_str()),
+            _ => None,
+        }
+    }
+
+    // Vm::verify rejects registers at or above REGISTER_COUNT, masking is therefore a no-op on
+    // verified bytecode and keeps every access inside the current window, which Vm::call makes
+    // sure is backed by Vm::registers
+    #[inline(always)]
+    fn reg(&self, r: u8) -> &Value<'vm> {
+        self.registers[self.base + (r as usize & (REGISTER_COUNT - 1))]
+            .as_ref()
+            .unwrap_or_else(|| panic!("Vm: read of uninitialised register r{r}"))
+    }
+
+    #[inline(always)]
+    fn set(&mut self, r: u8, v: Value<'vm>) {
+        self.registers[self.base + (r as usize & (REGISTER_COUNT - 1))] = Some(v);
+    }
+
+    /// extension word `n` of the instruction at `pc`
+    #[inline(always)]
+    fn ext(&self, pc: usize, n: usize) -> u32 {
+        self.bytecode.code[pc + n]
+    }
+
+    #[inline(always)]
+    fn ext64(&self, pc: usize) -> u64 {
+        self.ext(pc, 1) as u64 | (self.ext(pc, 2) as u64) << 32
+    }
+
+    /// Applies `int` to int operands, redoing it with `big` if the result does not fit into an
+    /// i64 or an operand is a big int, and `double` once an operand is a double, see vm::int
+    fn arith(
+        &mut self,
+        w: u32,
+        op: &'static str,
+        int: fn(i64, i64) -> Option<i64>,
+        big: fn(&BigInt, &BigInt) -> Result<BigInt, ErrorKind>,
+        double: fn(f64, f64) -> f64,
+    ) -> Result<(), ErrorKind> {
+        let (lhs, rhs) = (*self.reg(bc::b(w)), *self.reg(bc::c(w)));
+        let result = match (lhs, rhs) {
+            (Value::Int(l), Value::Int(r)) if let Some(result) = int(l, r) => Value::Int(result),
+            (Value::Double(_), _) | (_, Value::Double(_)) => {
+                match (self.double(&lhs), self.double(&rhs)) {
+                    (Some(lhs), Some(rhs)) => Value::Double(double(lhs, rhs)),
+                    _ => return Err(type_error(op, &lhs, Some(&rhs))),
+                }
+            }
+            _ => match (self.big(&lhs), self.big(&rhs)) {
+                (Some(lhs), Some(rhs)) => {
+                    let result = big(&lhs, &rhs)?;
+                    self.int_value(result)
+                }
+                _ => return Err(type_error(op, &lhs, Some(&rhs))),
+            },
+        };
+        self.set(bc::a(w), result);
+        Ok(())
+    }
+
+    /// the value of a number as a double, ints are rounded to the nearest double
+    fn double(&self, value: &Value<'vm>) -> Option<f64> {
+        match value {
+            Value::Int(i) => Some(*i as f64),
+            Value::BigInt(gc) => Some(self.heap.get(*gc).to_f64()),
+            Value::Double(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    /// the value of an Int or BigInt as a big int
+    fn big(&self, value: &Value<'vm>) -> Option<Cow<'_, BigInt>> {
+        match value {
+            Value::Int(i) => Some(Cow::Owned(BigInt::from(*i))),
+            Value::BigInt(gc) => Some(Cow::Borrowed(self.heap.get(*gc))),
+            _ => None,
+        }
+    }
+
+    /// `int` as an Int if it fits, as a newly allocated BigInt otherwise
+    fn int_value(&mut self, int: BigInt) -> Value<'vm> {
+        match i64::try_from(&int) {
+            Ok(i) => Value::Int(i),
+            Err(()) => Value::BigInt(self.alloc(int)),
+        }
+    }
+
+    /// operations only defined for ints
+    fn int(
+        &mut self,
+        w: u32,
+        op: &'static str,
+        int: fn(i64, i64) -> Result<i64, ErrorKind>,
+    ) -> Result<(), ErrorKind> {
+        let result = match (self.reg(bc::b(w)), self.reg(bc::c(w))) {
+            (Value::Int(lhs), Value::Int(rhs)) => int(*lhs, *rhs)?,
+            (lhs, rhs) => return Err(type_error(op, lhs, Some(rhs))),
+        };
+        self.set(bc::a(w), Value::Int(result));
+        Ok(())
+    }
+
+    /// `+` of two strings into a new heap string, false without writing a result if either
+    /// operand is not a string
+    fn concat(&mut self, w: u32) -> bool {
+        let (Some(lhs), Some(rhs)) = (self.str(self.reg(bc::b(w))), self.str(self.reg(bc::c(w))))
+        else {
+            return false;
+        };
+        let mut concatenated = String::with_capacity(lhs.len() + rhs.len());
+        concatenated.push_str(lhs);
+        concatenated.push_str(rhs);
+        let result = Value::String(self.alloc(concatenated));
+        self.set(bc::a(w), result);
+        true
+    }
+
+    /// Orders the operands and applies `ord`, numbers by value, see Vm::cmp_numbers, strings
+    /// lexicographically. Comparisons with NaN are always false.
+    fn cmp(
+        &mut self,
+        w: u32,
+        op: &'static str,
+        ord: fn(Ordering) -> bool,
+    ) -> Result<(), ErrorKind> {
+        let (lhs, rhs) = (self.reg(bc::b(w)), self.reg(bc::c(w)));
+        let ordering = match (self.str(lhs), self.str(rhs)) {
+            (Some(lhs), Some(rhs)) => Some(lhs.cmp(rhs)),
+            _ => self
+                .cmp_numbers(lhs, rhs)
+                .ok_or_else(|| type_error(op, lhs, Some(rhs)))?,
+        };
+        self.set(bc::a(w), bool(ordering.is_some_and(ord)));
+        Ok(())
+    }
+
+    /// Exact order of two numbers, ints are never rounded to doubles. Some(None) if an operand is
+    /// NaN, None if an operand is not a number.
+    fn cmp_numbers(&self, lhs: &Value<'vm>, rhs: &Value<'vm>) -> Option<Option<Ordering>> {
+        Some(match (lhs, rhs) {
+            (Value::Int(lhs), Value::Int(rhs)) => Some(lhs.cmp(rhs)),
+            (Value::Double(lhs), Value::Double(rhs)) => lhs.partial_cmp(rhs),
+            (Value::Int(lhs), Value::Double(rhs)) => int::cmp_f64(*lhs, *rhs),
+            (Value::Double(lhs), Value::Int(rhs)) => {
+                int::cmp_f64(*rhs, *lhs).map(Ordering::reverse)
+            }
+            (Value::BigInt(lhs), Value::Double(rhs)) => self.heap.get(*lhs).cmp_f64(*rhs),
+            (Value::Double(lhs), Value::BigInt(rhs)) => {
+                self.heap.get(*rhs).cmp_f64(*lhs).map(Ordering::reverse)
+            }
+            _ => Some(self.big(lhs)?.cmp(&self.big(rhs)?)),
+        })
+    }
+
+    /// Whether the operands of the comparison `w` are equal: numbers by their exact value,
+    /// strings by content whether they are borrowed Str or owned String values, everything else
+    /// by identity. Value::eq is structural and does not follow these rules.
+    fn equal(&self, w: u32) -> bool {
+        let (lhs, rhs) = (self.reg(bc::b(w)), self.reg(bc::c(w)));
+        if let Some(ordering) = self.cmp_numbers(lhs, rhs) {
+            return ordering == Some(Ordering::Equal);
+        }
+        match (self.str(lhs), self.str(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs == rhs,
+            _ => lhs == rhs,
+        }
+    }
+
+    fn call(&mut self, func: usize, args_start: u8, return_to: usize) -> Result<(), ErrorKind> {
+        let entry = self
+            .functions
+            .get(func)
+            .ok_or(ErrorKind::UndefinedFunction(func))?
+            .pc;
+        if self.frames.len() >= self.max_depth {
+            return Err(ErrorKind::StackOverflow {
+                depth: self.frames.len(),
+            });
+        }
+        // slide the window, the arguments starting at r{args_start} become the callees r0..
+        self.base += args_start as usize;
+        if self.registers.len() < self.base + REGISTER_COUNT {
+            self.registers.resize(self.base + REGISTER_COUNT, None);
+        }
+        self.frames.push(Frame {
+            return_to: return_to as u32,
+            base: self.base as u32,
+            locals: self.locals.len() as u32,
+            func: func as u32,
+        });
+        self.pc = entry;
+        Ok(())
+    }
+
+    fn sys(&mut self, builtin: usize, args_start: u8, args_len: u8) -> Result<(), ErrorKind> {
+        let args: Vec<Value<'vm>> = (args_start..args_start + args_len)
+            .map(|r| *self.reg(r))
+            .collect();
+        let result = (self.bytecode.builtins[builtin])(self, &args)?;
+        self.set(args_start, result);
+        Ok(())
+    }
+
+    /// Op::Concat, strings are copied, ints in decimal, doubles in the shortest form that parses
+    /// back to them with at least one fractional digit, bools and none by their literal
+    fn interpolate(&mut self, start: u8, len: u8) -> Result<(), ErrorKind> {
+        use std::fmt::Write;
+        let mut s = String::new();
+        for r in start..start + len {
+            let value = self.reg(r);
+            match value {
+                Value::True => s.push_str("true"),
+                Value::False => s.push_str("false"),
+                Value::None => s.push_str("none"),
+                Value::Int(i) => write!(s, "{i}").unwrap(),
+                Value::BigInt(big) => write!(s, "{}", self.heap.get(*big)).unwrap(),
+                Value::Double(d) => write!(s, "{d:?}").unwrap(),
+                Value::Str(_) | Value::String(_) => s.push_str(self.str(value).unwrap()),
+                other => return Err(type_error("{}", other, None)),
+            }
+        }
+        let result = Value::String(self.alloc(s));
+        self.set(start, result);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn frame(&self) -> &Frame {
+        // the top level frame is never popped
+        self.frames.last().unwrap()
+    }
+
+    /// variable slots of the current frame
+    #[inline(always)]
+    fn frame_locals(&self) -> &[(u64, Value<'vm>)] {
+        &self.locals[self.frame().locals as usize..]
+    }
+
+    /// Builds the purple garden stack trace for a failure at `pc` by walking the call stack from
+    /// the innermost frame outwards
+    fn trace(&self, pc: usize) -> Vec<TraceFrame> {
+        let name = |func: u32| match func {
+            Frame::TOP_LEVEL => "<main>".to_string(),
+            func => self.functions[func as usize].name.to_string(),
+        };
+        let mut trace = Vec::with_capacity(self.frames.len());
+        let mut at = pc;
+        for frame in self.frames.iter().rev() {
+            trace.push(TraceFrame {
+                name: name(frame.func),
+                span: self.span(at),
+            });
+            // return_to is the word after the call, its last word still maps to the call
+            at = (frame.return_to as usize).saturating_sub(1);
+        }
+        trace
+    }
+
+    fn error(&self, pc: usize, kind: ErrorKind) -> RuntimeError {
+        RuntimeError {
+            kind,
+            pc,
+            trace: self.trace(pc),
+        }
+    }
+
+    /// Interprets the packed bytecode starting at Vm::pc, until either the end of the bytecode or
+    /// a Ret in the outermost frame is reached. The bytecode is verified on the first run.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        if !self.verified {
+            self.verify()
+                .map_err(|e| self.error(e.pc, ErrorKind::Verify(e.msg)))?;
+            self.verified = true;
+        }
+
+        let mut until_slice = SLICE_INTERVAL;
+        while self.pc < self.bytecode.code.len() {
+            let pc = self.pc;
+            let w = self.bytecode.code[pc];
+            self.pc += Bytecode::width(w);
+
+            until_slice -= 1;
+            if until_slice == 0 {
+                until_slice = SLICE_INTERVAL;
+                if self.heap.cycle_running() {
+                    let (heap, roots) = self.roots();
+                    heap.collect(roots);
+                }
+            }
+
+            #[cfg(feature = "trace")]
+            println!("Vm::run({pc:04}: {w:#010x})");
+
+            if let Err(kind) = self.step(pc, w) {
+                return Err(self.error(pc, kind));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Executes the instruction `w` at `pc`, Vm::pc already points to the next instruction
+    #[inline(always)]
+    fn step(&mut self, pc: usize, w: u32) -> Result<(), ErrorKind> {
+        use opcode::*;
+
+        match bc::op(w) {
+            ADD if self.concat(w) => {}
+            ADD => self.arith(w, "+", int::add, |l, r| Ok(l + r), |l, r| l + r)?,
+            SUB => self.arith(w, "-", int::sub, |l, r| Ok(l - r), |l, r| l - r)?,
+            MUL => self.arith(w, "*", int::mul, |l, r| Ok(l * r), |l, r| l * r)?,
+            DIV => self.arith(w, "/", int::div, big_div, |l, r| l / r)?,
+            MOD => self.arith(w, "%", int::rem, big_rem, |l, r| l % r)?,
+            EQ => self.set(bc::a(w), bool(self.equal(w))),
+            NE => self.set(bc::a(w), bool(!self.equal(w))),
+            LT => self.cmp(w, "<", Ordering::is_lt)?,
+            GT => self.cmp(w, ">", Ordering::is_gt)?,
+            LE => self.cmp(w, "<=", Ordering::is_le)?,
+            GE => self.cmp(w, ">=", Ordering::is_ge)?,
+            BITAND => self.int(w, "&", |l, r| Ok(l & r))?,
+            BITOR => self.int(w, "|", |l, r| Ok(l | r))?,
+            BITXOR => self.int(w, "^", |l, r| Ok(l ^ r))?,
+            SHL => self.int(w, "<<", int::shl)?,
+            SHR => self.int(w, ">>", int::shr)?,
+            BITNOT => match self.reg(bc::b(w)) {
+                Value::Int(i) => self.set(bc::a(w), Value::Int(!i)),
+                other => return Err(type_error("~", other, None)),
+            },
+            NEG => {
+                let negated = match *self.reg(bc::b(w)) {
+                    Value::Int(i) if let Some(negated) = int::neg(i) => Value::Int(negated),
+                    Value::Double(d) => Value::Double(-d),
+                    other => match self.big(&other) {
+                        Some(big) => {
+                            let negated = -big.as_ref();
+                            self.int_value(negated)
+                        }
+                        None => return Err(type_error("-", &other, None)),
+                    },
+                };
+                self.set(bc::a(w), negated)
+            }
+            NOT => {
+                let falsy = matches!(self.reg(bc::b(w)), Value::False | Value::None);
+                self.set(bc::a(w), bool(falsy))
+            }
+            MOV => self.set(bc::a(w), *self.reg(bc::b(w))),
+            LOADI => self.set(bc::a(w), Value::Int(bc::bc(w) as i16 as i64)),
+            LOADI_W => self.set(bc::a(w), Value::Int(self.ext64(pc) as i64)),
+            LOADG => self.set(bc::a(w), self.globals[bc::bc(w) as usize]),
+            LOADG_W => self.set(bc::a(w), self.globals[self.ext(pc, 1) as usize]),
+            LET => {
+                let hash = self.ext64(pc);
+                let value = *self.reg(bc::a(w));
+                let start = self.frame().locals as usize;
+                match self.locals[start..].iter_mut().find(|(h, _)| *h == hash) {
+                    Some((_, slot)) => *slot = value,
+                    None => self.locals.push((hash, value)),
+                }
+            }
+            LOADV => {
+                let hash = self.ext64(pc);
+                let Some((_, value)) = self.frame_locals().iter().rev().find(|(h, _)| *h == hash)
+                else {
+                    return Err(ErrorKind::UndefinedVariable(
+                        match self.symbols.get(&hash) {
+                            Some(name) => name.to_string(),
+                            None => format!("{hash:#x}"),
+                        },
+                    ));
+                };
+                self.set(bc::a(w), *value);
+            }
+            LEN => {
+                let len = match self.reg(bc::b(w)) {
+                    Value::Str(s) => s.chars().count(),
+                    Value::String(s) => self.heap.get(*s).chars().count(),
+                    Value::Arr(arr) => self.heap.get(*arr).len(),
+                    Value::Obj(obj) => self.heap.get(*obj).len(),
+                    other => return Err(type_error("len", other, None)),
+                };
+                self.set(bc::a(w), Value::Int(len as i64));
+            }
+            NEW => {
+                let size = bc::b(w) as usize;
+                let container = match bc::c(w) {
+                    0 => Value::Obj(self.alloc(Map::with_capacity(size))),
+                    _ => Value::Arr(self.alloc(Array::with_capacity(size))),
+                };
+                self.set(bc::a(w), container);
+            }
+            SIZE | SIZE_W => {
+                let additional = if bc::op(w) == SIZE {
+                    bc::bc(w) as usize
+                } else {
+                    self.ext(pc, 1) as usize
+                };
+                match *self.reg(bc::a(w)) {
+                    Value::Arr(gc) => {
+                        let arr = self.heap.get_mut(gc);
+                        let before = arr.capacity();
+                        arr.reserve(additional);
+                        let grew = (arr.capacity() - before) * size_of::<Value>();
+                        self.heap.grew(gc, grew);
+                    }
+                    Value::Obj(gc) => {
+                        let obj = self.heap.get_mut(gc);
+                        let before = obj.capacity();
+                        obj.reserve(additional);
+                        let grew = (obj.capacity() - before) * size_of::<(String, Value)>();
+                        self.heap.grew(gc, grew);
+                    }
+                    other => return Err(type_error("size", &other, None)),
+                }
+            }
+            APPEND => {
+                let value = *self.reg(bc::b(w));
+                let Value::Arr(gc) = *self.reg(bc::a(w)) else {
+                    return Err(type_error(
+                        "append",
+                        self.reg(bc::a(w)),
+                        Some(self.reg(bc::b(w))),
+                    ));
+                };
+                let arr = self.heap.get_mut(gc);
+                let before = arr.capacity();
+                arr.push(value);
+                let grew = (arr.capacity() - before) * size_of::<Value>();
+                self.heap.grew(gc, grew);
+                self.heap.barrier(gc, &value);
+            }
+            IDX => {
+                let value = match (self.reg(bc::b(w)), self.reg(bc::c(w))) {
+                    // a view into the same input, no allocation
+                    (&Value::Str(s), &Value::Int(index)) => {
+                        let (start, c) = char_at(s, index)?;
+                        Value::Str(&s[start..start + c.len_utf8()])
+                    }
+                    (&Value::String(s), &Value::Int(index)) => {
+                        let (_, c) = char_at(self.heap.get(s).as_str(), index)?;
+                        Value::String(self.alloc(c.to_string()))
+                    }
+                    (Value::Arr(arr), Value::Int(index)) => {
+                        let arr = self.heap.get(*arr);
+                        *usize::try_from(*index)
+                            .ok()
+                            .and_then(|i| arr.get(i))
+                            .ok_or(ErrorKind::IndexOutOfBounds {
+                                index: *index,
+                                len: arr.len(),
+                            })?
+                    }
+                    (Value::Obj(obj), key @ (Value::Str(_) | Value::String(_))) => {
+                        let key = self.str(key).unwrap();
+                        self.heap.get(*obj).get(key).copied().unwrap_or(Value::None)
+                    }
+                    (container, index) => return Err(type_error("[]", container, Some(index))),
+                };
+                self.set(bc::a(w), value);
+            }
+            SET => {
+                let value = *self.reg(bc::c(w));
+                match (*self.reg(bc::a(w)), *self.reg(bc::b(w))) {
+                    (Value::Arr(gc), Value::Int(index)) => {
+                        let arr = self.heap.get_mut(gc);
+                        let len = arr.len();
+                        *usize::try_from(index)
+                            .ok()
+                            .and_then(|i| arr.get_mut(i))
+                            .ok_or(ErrorKind::IndexOutOfBounds { index, len })? = value;
+                        self.heap.barrier(gc, &value);
+                    }
+                    (Value::Obj(gc), key @ (Value::Str(_) | Value::String(_))) => {
+                        let key = self.str(&key).unwrap().to_string();
+                        let grew = key.len() + size_of::<(String, Value)>();
+                        if self.heap.get_mut(gc).insert(key, value).is_none() {
+                            self.heap.grew(gc, grew);
+                        }
+                        self.heap.barrier(gc, &value);
+                    }
+                    (container, key) => return Err(type_error("[]=", &container, Some(&key))),
+                }
+            }
+            JMP => self.pc = (pc as i64 + bc::abc(w) as i64) as usize,
+            JMP_W => self.pc = (pc as i64 + self.ext(pc, 1) as i32 as i64) as usize,
+            JMPF | JMPF_W => {
+                if let Value::False | Value::None = self.reg(bc::a(w)) {
+                    let rel = if bc::op(w) == JMPF {
+                        bc::bc(w) as i16 as i64
+                    } else {
+                        self.ext(pc, 1) as i32 as i64
+                    };
+                    self.pc = (pc as i64 + rel) as usize;
+                }
+            }
+            CALL => self.call(bc::c(w) as usize, bc::a(w), self.pc)?,
+            CALL_W => self.call(self.ext(pc, 1) as usize, bc::a(w), self.pc)?,
+            RET => {
+                for _ in 0..bc::a(w) {
+                    if self.frames.len() == 1 {
+                        // returning from the outermost frame halts the vm
+                        self.pc = self.bytecode.code.len();
+                        break;
+                    }
+                    let frame = self.frames.pop().unwrap();
+                    // everything but the result is dead, handles left in the window would turn
+                    // into roots again once a later call reaches this far up
+                    let base = frame.base as usize;
+                    self.registers[base + 1..base + REGISTER_COUNT].fill(None);
+                    self.locals.truncate(frame.locals as usize);
+                    self.pc = frame.return_to as usize;
+                    self.base = self.frame().base as usize;
+                }
+            }
+            SYS => self.sys(bc::c(w) as usize, bc::a(w), bc::b(w))?,
+            SYS_W => self.sys(self.ext(pc, 1) as usize, bc::a(w), bc::b(w))?,
+            CONCAT => self.interpolate(bc::a(w), bc::b(w))?,
+            unknown => unreachable!("Vm::run: unknown opcode {unknown:#04x}"),
+        }
+
+        Ok(())
+    }
+}
+
+fn bool<'v>(b: bool) -> Value<'v> {
+    if b { Value::True } else { Value::False }
+}
+
+fn big_div(lhs: &BigInt, rhs: &BigInt) -> Result<BigInt, ErrorKind> {
+    let (quotient, _) = lhs.div_rem(rhs).ok_or(ErrorKind::DivisionByZero)?;
+    Ok(quotient)
+}
+
+fn big_rem(lhs: &BigInt, rhs: &BigInt) -> Result<BigInt, ErrorKind> {
+    let (_, remainder) = lhs.div_rem(rhs).ok_or(ErrorKind::DivisionByZero)?;
+    Ok(remainder)
+}
+
+/// the byte offset and the char at the char index `index` of `s`
+fn char_at(s: &str, index: i64) -> Result<(usize, char), ErrorKind> {
+    usize::try_from(index)
+        .ok()
+        .and_then(|i| s.char_indices().nth(i))
+        .ok_or_else(|| ErrorKind::IndexOutOfBounds {
+            index,
+            len: s.chars().count(),
+        })
+}
+
+fn type_error(op: &'static str, lhs: &Value, rhs: Option<&Value>) -> ErrorKind {
+    ErrorKind::Type {
+        op,
+        lhs: lhs.type_name(),
+        rhs: rhs.map(Value::type_name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asm,
+        bc::LineTable,
+        err::{PgError, Span},
+        gc::Collector,
+        op::Op,
+        vm::{BigInt, ErrorKind, Function, MAX_DEPTH, REGISTER_COUNT, RuntimeError, Value, Vm},
+    };
+
+    fn vm(ops: Vec<Op<'static>>) -> Vm<'static> {
+        Vm::new(&ops, vec![], vec![], LineTable::default())
+    }
+
+    /// operands of the coercion table, allocated into the heap of the vm under test
+    #[derive(Debug, Clone, Copy)]
+    enum Operand {
+        Int(i64),
+        Big(&'static str),
+        Double(f64),
+        Str(&'static str),
+        /// a heap String
+        Owned(&'static str),
+        None,
+    }
+
+    /// runs `lhs op rhs` and renders the result, big ints prefixed with `big`
+    fn binary(lhs: Operand, op: &str, rhs: Operand) -> Result<String, ErrorKind> {
+        let (dst, l, r) = (2, 0, 1);
+        let op = match op {
+            "+" => Op::Add {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "-" => Op::Sub {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "*" => Op::Mul {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "/" => Op::Div {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "%" => Op::Mod {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "==" => Op::Eq {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "!=" => Op::Ne {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "<" => Op::Lt {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            ">" => Op::Gt {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "<=" => Op::Le {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            ">=" => Op::Ge {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            "&" => Op::BitAnd {
+                dst,
+                lhs: l,
+                rhs: r,
+            },
+            _ => unreachable!("no op for {op}"),
+        };
+        let mut vm = vm(vec![
+            Op::LoadG { dst: l, idx: 0 },
+            Op::LoadG { dst: r, idx: 1 },
+            op,
+        ]);
+        vm.globals = [lhs, rhs]
+            .into_iter()
+            .map(|operand| match operand {
+                Operand::Int(i) => Value::Int(i),
+                Operand::Big(digits) => {
+                    Value::BigInt(vm.heap.alloc(BigInt::parse(digits).unwrap()))
+                }
+                Operand::Double(d) => Value::Double(d),
+                Operand::Str(s) => Value::Str(s),
+                Operand::Owned(s) => Value::String(vm.heap.alloc(s.to_string())),
+                Operand::None => Value::None,
+            })
+            .collect();
+        vm.run().map_err(|e| e.kind)?;
+        Ok(match vm.registers[dst as usize].unwrap() {
+            Value::True => "true".to_string(),
+            Value::False => "false".to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::BigInt(big) => format!("big {}", vm.heap.get(big)),
+            Value::Double(d) => format!("{d:?}"),
+            Value::Str(s) => format!("{s:?}"),
+            Value::String(s) => format!("owned {:?}", vm.heap.get(s)),
+            other => panic!("unexpected result {other:?}"),
+        })
+    }
+
+    /// numeric promotion, NaN and string equality, see vm::int and Vm::equal
+    #[test]
+    fn coercions() {
+        use Operand::*;
+        let nan = f64::NAN;
+        let two_53 = 9007199254740992.0;
+        let two_63 = 9223372036854775808.0;
+        let type_error = |op, lhs, rhs| {
+            Err(ErrorKind::Type {
+                op,
+                lhs,
+                rhs: Some(rhs),
+            })
+        };
+        let tests = [
+            // an int and a double are added as doubles
+            (Int(1), "+", Double(2.5), Ok("3.5")),
+            (Double(2.5), "+", Int(1), Ok("3.5")),
+            (Int(1), "-", Double(0.5), Ok("0.5")),
+            (Int(3), "*", Double(0.5), Ok("1.5")),
+            (Int(2), "*", Double(-0.0), Ok("-0.0")),
+            (Int(7), "/", Int(2), Ok("3")),
+            (Int(7), "/", Double(2.0), Ok("3.5")),
+            (Int(7), "%", Double(2.5), Ok("2.0")),
+            (Double(-7.5), "%", Int(2), Ok("-1.5")),
+            (Int(i64::MAX), "+", Double(1.0), Ok("9.223372036854776e18")),
+            (Big("100000000000000000000"), "*", Double(0.5), Ok("5e19")),
+            (Double(0.5), "-", Big("-100000000000000000000"), Ok("1e20")),
+            // doubles follow IEEE 754, there is no division by zero error
+            (Int(1), "/", Double(0.0), Ok("inf")),
+            (Int(-1), "/", Double(0.0), Ok("-inf")),
+            (Int(0), "/", Double(0.0), Ok("NaN")),
+            (Int(1), "%", Double(0.0), Ok("NaN")),
+            (Int(1), "/", Int(0), Err(ErrorKind::DivisionByZero)),
+            (Int(1), "+", Double(nan), Ok("NaN")),
+            // comparisons are exact, ints are not rounded to doubles
+            (Int(1), "==", Double(1.0), Ok("true")),
+            (Double(1.0), "==", Int(1), Ok("true")),
+            (Int(1), "!=", Double(1.0), Ok("false")),
+            (Int(1), "==", Double(1.5), Ok("false")),
+            (Int(1), "<", Double(1.5), Ok("true")),
+            (Double(1.5), ">", Int(1), Ok("true")),
+            (Int(-2), ">=", Double(-1.5), Ok("false")),
+            (Int(0), "==", Double(-0.0), Ok("true")),
+            (Double(0.0), "==", Double(-0.0), Ok("true")),
+            (Int(9007199254740993), "==", Double(two_53), Ok("false")),
+            (Int(9007199254740993), ">", Double(two_53), Ok("true")),
+            (Int(i64::MAX), "<", Double(two_63), Ok("true")),
+            (Int(i64::MIN), "==", Double(-two_63), Ok("true")),
+            (Big("9223372036854775808"), "==", Double(two_63), Ok("true")),
+            (Big("9223372036854775809"), ">", Double(two_63), Ok("true")),
+            (Double(two_63), "<", Big("9223372036854775809"), Ok("true")),
+            (
+                Big("-100000000000000000000"),
+                "<",
+                Double(-1e19),
+                Ok("true"),
+            ),
+            (
+                Big("100000000000000000000"),
+                "<=",
+                Double(f64::INFINITY),
+                Ok("true"),
+            ),
+            (Big("100000000000000000000"), "==", Int(1), Ok("false")),
+            (Big("100000000000000000000"), ">", Int(i64::MAX), Ok("true")),
+            // NaN is unordered and unequal to everything
+            (Double(nan), "==", Double(nan), Ok("false")),
+            (Double(nan), "!=", Double(nan), Ok("true")),
+            (Double(nan), "<=", Double(nan), Ok("false")),
+            (Int(1), "<", Double(nan), Ok("false")),
+            (Int(1), ">=", Double(nan), Ok("false")),
+            (Double(nan), ">", Int(1), Ok("false")),
+            (Big("100000000000000000000"), "<", Double(nan), Ok("false")),
+            (Int(1), "==", Double(nan), Ok("false")),
+            // strings are equal by content, borrowed or owned
+            (Str("a"), "==", Owned("a"), Ok("true")),
+            (Owned("a"), "==", Str("a"), Ok("true")),
+            (Owned("a"), "==", Owned("a"), Ok("true")),
+            (Str("a"), "==", Str("a"), Ok("true")),
+            (Str("a"), "!=", Owned("b"), Ok("true")),
+            (Owned("a"), "!=", Owned("a"), Ok("false")),
+            (Str("1"), "==", Int(1), Ok("false")),
+            (None, "==", None, Ok("true")),
+            (None, "==", Int(0), Ok("false")),
+            (Int(0), "==", Str(""), Ok("false")),
+            // no coercion between strings and numbers, bitwise operators take ints only
+            (Str("1"), "+", Int(1), type_error("+", "str", "int")),
+            (Int(1), "<", Owned("a"), type_error("<", "int", "str")),
+            (None, "<", Double(1.0), type_error("<", "none", "double")),
+            (Double(1.5), "&", Int(1), type_error("&", "double", "int")),
+        ];
+        for (lhs, op, rhs, expected) in tests {
+            assert_eq!(
+                binary(lhs, op, rhs),
+                expected.map(String::from),
+                "{lhs:?} {op} {rhs:?}"
+            );
+        }
+    }
+
+    /// concatenation, ordering, length and indexing, see the unicode policy on Value
+    #[test]
+    fn strings() {
+        use Operand::*;
+        let tests = [
+            (Str("ab"), "+", Str("c"), Ok("owned \"abc\"")),
+            (Str("ab"), "+", Owned("c"), Ok("owned \"abc\"")),
+            (Owned("ab"), "+", Owned(""), Ok("owned \"ab\"")),
+            (Str("e"), "+", Str("\u{301}"), Ok(r#"owned "e\u{301}""#)),
+            (Str("ab"), "<", Str("b"), Ok("true")),
+            (Str("ab"), "<", Str("abc"), Ok("true")),
+            (Str(""), "<", Owned("a"), Ok("true")),
+            (Owned("Z"), "<", Str("a"), Ok("true")),
+            (Str("é"), ">", Str("z"), Ok("true")),
+            (Str("b"), "<=", Owned("b"), Ok("true")),
+            (Owned("a"), ">=", Owned("b"), Ok("false")),
+            (
+                Str("a"),
+                "+",
+                Int(1),
+                Err(ErrorKind::Type {
+                    op: "+",
+                    lhs: "str",
+                    rhs: Some("int"),
+                }),
+            ),
+            (
+                Str("a"),
+                "<",
+                None,
+                Err(ErrorKind::Type {
+                    op: "<",
+                    lhs: "str",
+                    rhs: Some("none"),
+                }),
+            ),
+        ];
+        for (lhs, op, rhs, expected) in tests {
+            assert_eq!(
+                binary(lhs, op, rhs),
+                expected.map(String::from),
+                "{lhs:?} {op} {rhs:?}"
+            );
+        }
+
+        let mut vm = asm::assemble(
+            r#".globals
+    "héllo"
+.code
+    loadg r0, g0
+    len r1, r0
+    loadi r2, 1
+    idx r3, r0, r2
+    loadi r2, 4
+    idx r4, r0, r2
+    add r5, r0, r0
+    len r6, r5
+    loadi r2, 6
+    idx r7, r5, r2
+"#,
+            &[],
+        )
+        .expect("Failed to assemble")
+        .into_vm();
+        vm.run().expect("Failed to run");
+        assert_eq!(vm.registers[1], Some(Value::Int(5)));
+        assert_eq!(vm.registers[3], Some(Value::Str("é")));
+        assert_eq!(vm.registers[4], Some(Value::Str("o")));
+        assert_eq!(vm.registers[6], Some(Value::Int(10)));
+        let Some(Value::String(s)) = vm.registers[7] else {
+            panic!("indexing an owned string should allocate");
+        };
+        assert_eq!(vm.heap.get(s), "é");
+
+        let err = |index| {
+            asm::assemble(
+                &format!(".globals\n    \"héllo\"\n.code\n    loadg r0, g0\n    loadi r1, {index}\n    idx r2, r0, r1"),
+                &[],
+            )
+            .expect("Failed to assemble")
+            .into_vm()
+            .run()
+            .expect_err("should fail")
+            .kind
+        };
+        assert_eq!(err(5), ErrorKind::IndexOutOfBounds { index: 5, len: 5 });
+        assert_eq!(err(-1), ErrorKind::IndexOutOfBounds { index: -1, len: 5 });
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut vm = vm(vec![
+            Op::LoadI { dst: 0, value: 10 },
+            Op::LoadI {
+                dst: 1,
+                value: 1 << 40,
+            },
+            Op::Add {
+                dst: 2,
+                lhs: 0,
+                rhs: 1,
+            },
+            Op::Mul {
+                dst: 2,
+                lhs: 2,
+                rhs: 0,
+            },
+        ]);
+        vm.run().expect("Failed to run");
+        assert_eq!(vm.registers[2], Some(Value::Int(((1 << 40) + 10) * 10)));
+    }
+
+    #[test]
+    fn variables() {
+        let mut vm = vm(vec![
+            Op::LoadI { dst: 0, value: 42 },
+            Op::Let {
+                hash: 0x123,
+                src: 0,
+            },
+            Op::LoadV {
+                hash: 0x123,
+                dst: 1,
+            },
+        ]);
+        vm.run().expect("Failed to run");
+        assert_eq!(vm.registers[1], Some(Value::Int(42)));
+    }
+
+    #[test]
+    fn loop_with_jumps() {
+        // r0 = 0; while r0 < 5 { r0 = r0 + 1 }
+        let mut vm = vm(vec![
+            Op::LoadI { dst: 0, value: 0 },
+            Op::LoadI { dst: 1, value: 5 },
+            Op::LoadI { dst: 2, value: 1 },
+            Op::Lt {
+                dst: 3,
+                lhs: 0,
+                rhs: 1,
+            },
+            Op::JmpF { cond: 3, target: 7 },
+            Op::Add {
+                dst: 0,
+                lhs: 0,
+                rhs: 2,
+            },
+            Op::Jmp { target: 3 },
+        ]);
+        vm.run().expect("Failed to run");
+        assert_eq!(vm.registers[0], Some(Value::Int(5)));
+    }
+
+    #[test]
+    fn call_and_ret() {
+        let ops = vec![
+            Op::LoadI { dst: 1, value: 7 },
+            Op::Call {
+                func: 0,
+                args_start: 1,
+                args_len: 1,
+            },
+            Op::Ret { times: 1 },
+            // fn square(a) { a * a }
+            Op::Mul {
+                dst: 0,
+                lhs: 0,
+                rhs: 0,
+            },
+            Op::Ret { times: 1 },
+        ];
+        let mut vm = Vm::new(
+            &ops,
+            vec![],
+            vec![Function {
+                name: "square",
+                pc: 3,
+                args: 1,
+            }],
+            LineTable::default(),
+        );
+        vm.run().expect("Failed to run");
+        // the result replaces the first argument
+        assert_eq!(vm.registers[1], Some(Value::Int(49)));
+        assert_eq!(vm.frames.len(), 1);
+    }
+
+    #[test]
+    fn caller_registers_survive_calls() {
+        let mut vm = asm::assemble(
+            ".functions
+    f 1
+.code
+    loadi r0, 1
+    loadi r1, 2
+    loadi r2, 3
+    loadi r5, 7
+    call f, r5, 1
+    ret 1
+f:
+    loadi r1, 10
+    loadi r2, 20
+    loadi r26, 30
+    add r0, r0, r1
+    ret 1
+",
+            &[],
+        )
+        .expect("Failed to assemble")
+        .into_vm();
+        vm.run().expect("Failed to run");
+        assert_eq!(
+            vm.registers[..3],
+            [
+                Some(Value::Int(1)),
+                Some(Value::Int(2)),
+                Some(Value::Int(3))
+            ]
+        );
+        assert_eq!(vm.registers[5], Some(Value::Int(17)));
+    }
+
+    #[test]
+    fn sys() {
+        fn store<'vm>(vm: &mut Vm<'vm>, args: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+            let Value::Int(i) = args[0] else {
+                unreachable!()
+            };
+            vm.registers[5] = Some(Value::Int(i * 2));
+            Ok(Value::Int(i + 1))
+        }
+        let mut vm = vm(vec![
+            Op::LoadI { dst: 3, value: 21 },
+            Op::Sys {
+                ptr: store,
+                args_start: 3,
+                args_len: 1,
+            },
+        ]);
+        vm.run().expect("Failed to run");
+        assert_eq!(vm.registers[5], Some(Value::Int(42)));
+        assert_eq!(vm.registers[3], Some(Value::Int(22)));
+    }
+
+    #[test]
+    fn concat() {
+        let mut vm = asm::assemble(
+            r#".globals
+    "n = " ; g0
+    2.5 ; g1
+    -100000000000000000000 ; g2
+.code
+    loadg r0, g0
+    loadi r1, 1
+    loadg r2, g1
+    loadg r3, g2
+    concat r0, 4
+"#,
+            &[],
+        )
+        .expect("Failed to assemble")
+        .into_vm();
+        vm.run().expect("Failed to run");
+        let Some(Value::String(s)) = vm.registers[0] else {
+            panic!("r0 should hold the joined string");
+        };
+        assert_eq!(vm.heap.get(s), "n = 12.5-100000000000000000000");
+
+        let err = asm::assemble(
+            ".code\n    loadi r0, 1\n    new r1, 0, array\n    concat r0, 2",
+            &[],
+        )
+        .expect("Failed to assemble")
+        .into_vm()
+        .run()
+        .expect_err("should fail");
+        assert_eq!(
+            err.kind,
+            ErrorKind::Type {
+                op: "{}",
+                lhs: "array",
+                rhs: None
+            }
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let vm = |src| {
+            let mut vm = asm::assemble(src, &[])
+                .expect("Failed to assemble")
+                .into_vm();
+            vm.run().expect_err("should fail").kind
+        };
+        assert_eq!(
+            vm(".code\n    loadi r0, 1\n    loadi r1, 0\n    div r0, r0, r1"),
+            ErrorKind::DivisionByZero
+        );
+        assert_eq!(
+            vm(
+                ".globals\n    \"str\"\n.code\n    loadg r0, g0\n    loadi r1, 1\n    add r0, r0, r1"
+            ),
+            ErrorKind::Type {
+                op: "+",
+                lhs: "str",
+                rhs: Some("int")
+            }
+        );
+        assert_eq!(
+            vm(".code\n    loadv r0, missing"),
+            ErrorKind::UndefinedVariable("missing".into())
+        );
+        assert_eq!(
+            vm(".code\n    call f0, r0, 0"),
+            ErrorKind::Verify("function 0 does not exist, there are 0".into())
+        );
+    }
+
+    #[test]
+    fn stack_trace() {
+        let ops = [
+            Op::LoadI { dst: 1, value: 0 },
+            Op::Call {
+                func: 0,
+                args_start: 1,
+                args_len: 1,
+            },
+            Op::Ret { times: 1 },
+            // fn outer(a) { inner(a) }
+            Op::Call {
+                func: 1,
+                args_start: 0,
+                args_len: 1,
+            },
+            Op::Ret { times: 1 },
+            // fn inner(a) { a / a }
+            Op::Div {
+                dst: 0,
+                lhs: 0,
+                rhs: 0,
+            },
+            Op::Ret { times: 1 },
+        ];
+        let mut lines = LineTable::default();
+        for (pc, line) in [(0, 10), (3, 2), (5, 6)] {
+            lines.push(
+                pc,
+                Span {
+                    line,
+                    start: 4,
+                    end: 8,
+                },
+            );
+        }
+        let mut vm = Vm::new(
+            &ops,
+            vec![],
+            vec![
+                Function {
+                    name: "outer",
+                    pc: 3,
+                    args: 1,
+                },
+                Function {
+                    name: "inner",
+                    pc: 5,
+                    args: 1,
+                },
+            ],
+            lines,
+        );
+        let err = vm.run().expect_err("should fail");
+        let names: Vec<_> = err.trace.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["inner", "outer", "<main>"]);
+        let lines: Vec<_> = err.trace.iter().map(|f| f.span.unwrap().line).collect();
+        assert_eq!(lines, vec![6, 2, 10]);
+        assert_eq!(
+            PgError::from(err).to_string(),
+            "err: division by zero at l:6:4-8
+    at inner (l:6:4-8)
+    at outer (l:2:4-8)
+    at <main> (l:10:4-8)"
+        );
+    }
+
+    const FIB: &str = ".functions
+    fib 1
+.code
+    loadi r1, 20
+    call fib, r1, 1
+    ret 1
+fib:
+    let n, r0
+    loadi r1, 2
+    lt r2, r0, r1
+    jmpf r2, recurse
+    ret 1
+recurse:
+    loadi r1, 1
+    sub r0, r0, r1
+    call fib, r0, 1
+    let a, r0
+    loadv r0, n
+    loadi r1, 2
+    sub r0, r0, r1
+    call fib, r0, 1
+    loadv r1, a
+    add r0, r0, r1
+    ret 1
+";
+
+    fn fib(max_depth: usize) -> Result<Vm<'static>, RuntimeError> {
+        let mut vm = asm::assemble(FIB, &[])
+            .expect("Failed to assemble")
+            .into_vm();
+        vm.max_depth = max_depth;
+        vm.run().map(|_| vm)
+    }
+
+    #[test]
+    fn recursion() {
+        let vm = fib(MAX_DEPTH).expect("Failed to run");
+        assert_eq!(vm.registers[1], Some(Value::Int(6765)));
+        assert_eq!(vm.frames.len(), 1);
+        assert!(vm.locals.is_empty());
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let err = fib(10).expect_err("should overflow");
+        assert_eq!(err.kind, ErrorKind::StackOverflow { depth: 10 });
+        assert_eq!(err.trace.len(), 10);
+    }
+
+    #[test]
+    fn register_windows() {
+        let program = asm::assemble(
+            ".functions
+    clobber 1
+.code
+    loadi r0, 1
+    loadi r1, 2
+    loadi r4, 3
+    call clobber, r4, 1
+    ret 1
+clobber:
+    loadi r1, 40
+    add r0, r0, r1
+    loadi r2, 0
+    loadi r3, 0
+    ret 1
+",
+            &[],
+        )
+        .expect("Failed to assemble");
+        let mut vm = program.into_vm();
+        vm.run().expect("Failed to run");
+        // the callees r1..r3 are the callers r5..r7, cleared on return, r0 and r1 of the caller
+        // are untouched
+        assert_eq!(vm.registers[0], Some(Value::Int(1)));
+        assert_eq!(vm.registers[1], Some(Value::Int(2)));
+        assert_eq!(vm.registers[4], Some(Value::Int(43)));
+        assert_eq!(vm.registers[5], None);
+        assert_eq!(vm.registers.len(), 4 + REGISTER_COUNT);
+    }
+
+    #[test]
+    fn containers() {
+        let mut vm = asm::assemble(
+            r#".globals
+    "key"
+    "missing"
+.code
+    new r0, 0, array
+    loadi r1, 3
+    append r0, r1
+    loadi r1, 4
+    append r0, r1
+    loadi r2, 0
+    loadi r3, 5
+    set r0, r2, r3
+    idx r4, r0, r2
+    len r5, r0
+    new r6, 1, object
+    loadg r7, g0
+    set r6, r7, r0
+    idx r8, r6, r7
+    len r9, r6
+    loadg r10, g1
+    idx r10, r6, r10
+"#,
+            &[],
+        )
+        .expect("Failed to assemble")
+        .into_vm();
+        vm.run().expect("Failed to run");
+        assert_eq!(vm.registers[4], Some(Value::Int(5)));
+        assert_eq!(vm.registers[5], Some(Value::Int(2)));
+        assert_eq!(vm.registers[8], vm.registers[0]);
+        assert_eq!(vm.registers[9], Some(Value::Int(1)));
+        assert_eq!(vm.registers[10], Some(Value::None));
+        let Some(Value::Arr(arr)) = vm.registers[0] else {
+            panic!("r0 should hold an array");
+        };
+        assert_eq!(vm.heap.get(arr), &vec![Value::Int(5), Value::Int(4)]);
+    }
+
+    #[test]
+    fn container_errors() {
+        let err = |src| {
+            asm::assemble(src, &[])
+                .expect("Failed to assemble")
+                .into_vm()
+                .run()
+                .expect_err("should fail")
+                .kind
+        };
+        assert_eq!(
+            err(".code\n    new r0, 0, array\n    loadi r1, 0\n    idx r2, r0, r1"),
+            ErrorKind::IndexOutOfBounds { index: 0, len: 0 }
+        );
+        assert_eq!(
+            err(".code\n    loadi r0, 1\n    append r0, r0"),
+            ErrorKind::Type {
+                op: "append",
+                lhs: "int",
+                rhs: Some("int")
+            }
+        );
+    }
+
+    /// every iteration allocates a garbage array of about 6KiB, r0 keeps a live one
+    const ALLOCATING_LOOP: &str = ".code
+    new r0, 0, array
+    loadi r1, 0
+    loadi r2, 1
+    loadi r3, 1000
+loop:
+    lt r4, r1, r3
+    jmpf r4, end
+    new r5, 255, array
+    append r0, r1
+    add r1, r1, r2
+    jmp loop
+end:
+";
+
+    fn allocating_loop(collector: Collector) -> Vm<'static> {
+        let mut vm = asm::assemble(ALLOCATING_LOOP, &[])
+            .expect("Failed to assemble")
+            .into_vm();
+        vm.heap.collector = collector;
+        vm.run().expect("Failed to run");
+        let Some(Value::Arr(arr)) = vm.registers[0] else {
+            panic!("r0 should hold an array");
+        };
+        assert_eq!(vm.heap.get(arr).len(), 1000);
+        assert_eq!(vm.heap.get(arr)[999], Value::Int(999));
+        vm
+    }
+
+    #[test]
+    fn collection_during_run() {
+        let vm = allocating_loop(Collector::MarkSweep);
+        // collections kick in about every INITIAL_THRESHOLD bytes
+        assert!(vm.heap.len() < 250, "{} objects survived", vm.heap.len());
+        assert!(vm.heap.stats.major > 0);
+        assert_eq!(vm.heap.stats.minor, 0);
+    }
+
+    #[test]
+    #[cfg_attr(
+        feature = "gc-stress",
+        ignore = "gc-stress collects on every allocation"
+    )]
+    fn generational_collection_during_run() {
+        let mut vm = allocating_loop(Collector::Generational);
+        assert!(vm.heap.stats.minor > 0);
+        // the garbage dies young, only the live array is ever promoted
+        assert_eq!(vm.heap.stats.major, 0);
+        vm.collect();
+        // the live array and the last garbage array, still in r5
+        assert_eq!(vm.heap.len(), 2);
+    }
+
+    #[test]
+    fn incremental_collection_during_run() {
+        let vm = allocating_loop(Collector::Incremental);
+        assert!(vm.heap.stats.major > 0);
+        assert!(vm.heap.stats.slices > vm.heap.stats.major);
+        assert!(vm.heap.stats.max_pause <= vm.heap.stats.total_pause);
+        assert!(vm.heap.len() < 500, "{} objects survived", vm.heap.len());
+    }
+
+    #[test]
+    fn incremental_cycle_finishes_without_allocations() {
+        // the only allocation starts a cycle, the loop after it doesn't allocate
+        let mut vm = asm::assemble(
+            ".code
+    new r0, 0, array
+    loadi r1, 0
+    loadi r2, 1
+    loadi r3, 10000
+loop:
+    lt r4, r1, r3
+    jmpf r4, end
+    add r1, r1, r2
+    jmp loop
+end:
+",
+            &[],
+        )
+        .expect("Failed to assemble")
+        .into_vm();
+        vm.heap.collector = Collector::Incremental;
+        vm.heap.threshold = 0;
+        vm.run().expect("Failed to run");
+        assert!(!vm.heap.cycle_running());
+        assert!(vm.heap.stats.major > 0);
+    }
+}
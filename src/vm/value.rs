@@ -1,26 +1,87 @@
-use crate::{Todo, cc::Const, gc::Gc};
+use std::borrow::Cow;
 
-#[derive(Debug, PartialEq)]
+use crate::{
+    cc::Const,
+    gc::{Array, Gc, Heap, Host, Map, Weak},
+    vm::BigInt,
+};
+
+/// PartialEq is structural, heap values compare by handle and Str never equals String, the `==`
+/// of the language is Vm::equal
+///
+/// Strings are UTF-8 and every string op works on unicode scalar values, Rust chars, never on
+/// bytes: Op::Len counts chars and Op::Idx yields the char at a char index as a one char string,
+/// both therefore take time linear in the length. `<` and `>` order strings lexicographically by
+/// char, which is the byte order of UTF-8. There is no normalization and no notion of grapheme
+/// clusters, `"e\u{301}"` is two chars long and not equal to `"\u{e9}"`.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value<'v> {
     True,
     False,
+    /// the absent value, equal only to itself and falsy in conditions
+    None,
     Int(i64),
+    /// an int outside of i64 range, see vm::bigint
+    BigInt(Gc<BigInt>),
     Double(f64),
     /// a view into the bytes of the interpreters input, compile time strings
     Str(&'v str),
-    String(String),
-    Arr(Gc<[Value<'v>]>),
-    Obj(Gc<Todo>),
+    /// a string created at runtime, managed by the gc
+    String(Gc<String>),
+    Arr(Gc<Array<'v>>),
+    Obj(Gc<Map<'v>>),
+    /// a reference that does not keep its target alive
+    Weak(Gc<Weak<'v>>),
+    /// a resource of the embedding program
+    Host(Gc<Host>),
 }
 
-impl<'c> From<Const<'c>> for Value<'c> {
-    fn from(value: Const<'c>) -> Self {
-        match value {
+impl<'c> Value<'c> {
+    /// the runtime value of `constant`, big ints and strings that do not borrow the input are
+    /// allocated on `heap`
+    pub fn from_const(constant: Const<'c>, heap: &mut Heap<'c>) -> Self {
+        match constant {
             Const::False => Value::False,
             Const::True => Value::True,
+            Const::None => Value::None,
             Const::Int(i) => Value::Int(i),
+            Const::BigInt(digits) => Value::BigInt(
+                heap.alloc(BigInt::parse(digits).expect("Const::BigInt holds decimal digits")),
+            ),
             Const::Double(bits) => Value::Double(f64::from_bits(bits)),
-            Const::Str(str) => Value::Str(str),
+            Const::Str(Cow::Borrowed(str)) => Value::Str(str),
+            Const::Str(Cow::Owned(string)) => Value::String(heap.alloc(string)),
+        }
+    }
+}
+
+impl Value<'_> {
+    /// name of the values type, as used in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::True | Value::False => "bool",
+            Value::None => "none",
+            Value::Int(_) => "int",
+            Value::BigInt(_) => "bigint",
+            Value::Double(_) => "double",
+            Value::Str(_) | Value::String(_) => "str",
+            Value::Arr(_) => "array",
+            Value::Obj(_) => "object",
+            Value::Weak(_) => "weak",
+            Value::Host(_) => "host",
+        }
+    }
+
+    /// slot index of the heap object the value refers to, if any
+    pub fn heap_ref(&self) -> Option<u32> {
+        match self {
+            Value::BigInt(gc) => Some(gc.idx()),
+            Value::String(gc) => Some(gc.idx()),
+            Value::Arr(gc) => Some(gc.idx()),
+            Value::Obj(gc) => Some(gc.idx()),
+            Value::Weak(gc) => Some(gc.idx()),
+            Value::Host(gc) => Some(gc.idx()),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,478 @@
+//! Checks the invariants Vm::run relies on before it starts interpreting: every instruction is
+//! complete and uses a known opcode, registers are below REGISTER_COUNT, jumps land on
+//! instruction boundaries, LoadG, Call and Sys refer to existing globals, functions and
+//! builtins, Call passes as many arguments as its function takes, and no register is read before
+//! it was written on every path leading to the read. A call counts as overwriting every register
+//! from its first argument up, the callee's window starts there.
+
+use std::fmt;
+
+use crate::{
+    bc::{self, Bytecode, opcode},
+    vm::{REGISTER_COUNT, Vm},
+};
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    /// word offset of the offending instruction
+    pub pc: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid bytecode at {:04}: {}", self.pc, self.msg)
+    }
+}
+
+/// Registers as a bit set, bit n is r{n}
+type Registers = u32;
+const _: () = assert!(REGISTER_COUNT <= Registers::BITS as usize);
+
+fn bit(r: u8) -> Registers {
+    1 << r
+}
+
+fn range(start: u8, len: u8) -> Registers {
+    (start..start.saturating_add(len)).fold(0, |set, r| set | bit(r))
+}
+
+/// Registers an instruction reads, writes and may overwrite with values that must not be read
+struct Access {
+    reads: Registers,
+    writes: Registers,
+    clobbers: Registers,
+}
+
+fn access(w: u32) -> Access {
+    use opcode::*;
+    let (a, b, c) = (bc::a(w), bc::b(w), bc::c(w));
+    let mut clobbers = 0;
+    let (reads, writes) = match bc::op(w) {
+        ADD | SUB | MUL | DIV | MOD | EQ | NE | LT | GT | LE | GE | BITAND | BITOR | BITXOR
+        | SHL | SHR => (bit(b) | bit(c), bit(a)),
+        MOV | LEN | NEG | NOT | BITNOT => (bit(b), bit(a)),
+        IDX => (bit(b) | bit(c), bit(a)),
+        SET => (bit(a) | bit(b) | bit(c), 0),
+        LOADI | LOADI_W | LOADG | LOADG_W | LOADV | NEW => (0, bit(a)),
+        SIZE | SIZE_W => (bit(a), 0),
+        LET => (bit(a), 0),
+        APPEND => (bit(a) | bit(b), 0),
+        JMPF | JMPF_W => (bit(a), 0),
+        // functions return their result in their r0, which is the callers r{args_start}, the rest
+        // of the callee's window overlaps the callers registers from r{args_start} up
+        CALL | CALL_W => {
+            clobbers = !0 << a;
+            (range(a, b), bit(a))
+        }
+        SYS | SYS_W => (range(a, b), bit(a)),
+        CONCAT => (range(a, b), bit(a)),
+        _ => (0, 0),
+    };
+    Access {
+        reads,
+        writes,
+        clobbers,
+    }
+}
+
+/// Registers an instruction names, for the REGISTER_COUNT check
+fn registers(w: u32) -> Vec<(u8, &'static str)> {
+    use opcode::*;
+    let (a, b, c) = (bc::a(w), bc::b(w), bc::c(w));
+    match bc::op(w) {
+        ADD | SUB | MUL | DIV | MOD | EQ | NE | LT | GT | LE | GE | BITAND | BITOR | BITXOR
+        | SHL | SHR | IDX | SET => vec![(a, "a"), (b, "b"), (c, "c")],
+        MOV | LEN | APPEND | NEG | NOT | BITNOT => vec![(a, "a"), (b, "b")],
+        LOADI | LOADI_W | LOADG | LOADG_W | LOADV | NEW | SIZE | SIZE_W | LET | JMPF | JMPF_W
+        | CALL | CALL_W | SYS | SYS_W | CONCAT => vec![(a, "a")],
+        _ => vec![],
+    }
+}
+
+impl<'vm> Vm<'vm> {
+    fn jump_target(&self, pc: usize, w: u32) -> Option<i64> {
+        use opcode::*;
+        let code = &self.bytecode.code;
+        let rel = match bc::op(w) {
+            JMP => bc::abc(w) as i64,
+            JMP_W => code[pc + 1] as i32 as i64,
+            JMPF => bc::bc(w) as i16 as i64,
+            JMPF_W => code[pc + 1] as i32 as i64,
+            _ => return None,
+        };
+        Some(pc as i64 + rel)
+    }
+
+    /// Verifies the bytecode, see the module documentation for the checked invariants.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        use opcode::*;
+        let code = &self.bytecode.code;
+        let err = |pc: usize, msg: String| Err(VerifyError { pc, msg });
+
+        // instruction boundaries and per instruction operand checks
+        let mut heads = vec![false; code.len() + 1];
+        let mut pc = 0;
+        while pc < code.len() {
+            let w = code[pc];
+            if bc::op(w) > CONCAT {
+                return err(pc, format!("unknown opcode {:#04x}", bc::op(w)));
+            }
+            let width = Bytecode::width(w);
+            if pc + width > code.len() {
+                return err(
+                    pc,
+                    "instruction truncated by the end of the bytecode".into(),
+                );
+            }
+            heads[pc] = true;
+
+            for (r, field) in registers(w) {
+                if r as usize >= REGISTER_COUNT {
+                    return err(
+                        pc,
+                        format!("register r{r} in operand {field} is out of range"),
+                    );
+                }
+            }
+
+            match bc::op(w) {
+                LOADG | LOADG_W => {
+                    let idx = if bc::op(w) == LOADG {
+                        bc::bc(w) as usize
+                    } else {
+                        code[pc + 1] as usize
+                    };
+                    if idx >= self.globals.len() {
+                        return err(
+                            pc,
+                            format!(
+                                "global g{idx} does not exist, there are {}",
+                                self.globals.len()
+                            ),
+                        );
+                    }
+                }
+                CALL | CALL_W | SYS | SYS_W => {
+                    if bc::a(w) as usize + bc::b(w) as usize > REGISTER_COUNT {
+                        return err(
+                            pc,
+                            format!(
+                                "arguments r{}..r{} are out of range",
+                                bc::a(w),
+                                bc::a(w) as usize + bc::b(w) as usize
+                            ),
+                        );
+                    }
+                    let (idx, len, what) = match bc::op(w) {
+                        CALL => (bc::c(w) as usize, self.functions.len(), "function"),
+                        CALL_W => (code[pc + 1] as usize, self.functions.len(), "function"),
+                        SYS => (bc::c(w) as usize, self.bytecode.builtins.len(), "builtin"),
+                        _ => (
+                            code[pc + 1] as usize,
+                            self.bytecode.builtins.len(),
+                            "builtin",
+                        ),
+                    };
+                    if idx >= len {
+                        return err(pc, format!("{what} {idx} does not exist, there are {len}"));
+                    }
+                    if matches!(bc::op(w), CALL | CALL_W)
+                        && let f = &self.functions[idx]
+                        && f.args != bc::b(w)
+                    {
+                        return err(
+                            pc,
+                            format!(
+                                "function `{}` takes {} arguments, got {}",
+                                f.name,
+                                f.args,
+                                bc::b(w)
+                            ),
+                        );
+                    }
+                }
+                CONCAT if bc::a(w) as usize + bc::b(w) as usize > REGISTER_COUNT => {
+                    return err(
+                        pc,
+                        format!(
+                            "operands r{}..r{} are out of range",
+                            bc::a(w),
+                            bc::a(w) as usize + bc::b(w) as usize
+                        ),
+                    );
+                }
+                NEW if bc::c(w) > 1 => {
+                    return err(pc, format!("unknown container type {}", bc::c(w)));
+                }
+                _ => {}
+            }
+
+            pc += width;
+        }
+        heads[code.len()] = true;
+
+        let mut pc = 0;
+        while pc < code.len() {
+            let w = code[pc];
+            if let Some(target) = self.jump_target(pc, w)
+                && (target < 0 || target as usize > code.len() || !heads[target as usize])
+            {
+                return err(
+                    pc,
+                    format!("jump target {target} is not the start of an instruction"),
+                );
+            }
+            pc += Bytecode::width(w);
+        }
+
+        for f in &self.functions {
+            if f.pc >= code.len() || !heads[f.pc] {
+                return err(
+                    f.pc,
+                    format!(
+                        "entry of function `{}` is not the start of an instruction",
+                        f.name
+                    ),
+                );
+            }
+            if f.args as usize > REGISTER_COUNT {
+                return err(
+                    f.pc,
+                    format!("function `{}` takes more arguments than registers", f.name),
+                );
+            }
+        }
+
+        // register initialisation: forward must-analysis over the control flow, the state at
+        // each instruction is the set of registers written on every path reaching it, merged by
+        // intersection, the top level starts with none and functions with their arguments
+        let mut state: Vec<Option<Registers>> = vec![None; code.len() + 1];
+        let mut work: Vec<usize> = vec![];
+        let flow = |state: &mut Vec<Option<Registers>>,
+                    work: &mut Vec<usize>,
+                    pc: usize,
+                    set: Registers| {
+            let merged = match state[pc] {
+                Some(existing) => existing & set,
+                None => set,
+            };
+            if state[pc] != Some(merged) {
+                state[pc] = Some(merged);
+                work.push(pc);
+            }
+        };
+        if !code.is_empty() {
+            flow(&mut state, &mut work, 0, 0);
+        }
+        for f in &self.functions {
+            flow(&mut state, &mut work, f.pc, range(0, f.args));
+        }
+
+        while let Some(pc) = work.pop() {
+            if pc == code.len() {
+                continue;
+            }
+            let w = code[pc];
+            let init = state[pc].unwrap_or_default();
+            let Access {
+                reads,
+                writes,
+                clobbers,
+            } = access(w);
+            let uninit = reads & !init;
+            if uninit != 0 {
+                return Err(VerifyError {
+                    pc,
+                    msg: format!(
+                        "register r{} may be read before it was written",
+                        uninit.trailing_zeros()
+                    ),
+                });
+            }
+
+            let out = (init & !clobbers) | writes;
+            let next = pc + Bytecode::width(w);
+            match bc::op(w) {
+                RET => {}
+                JMP | JMP_W => {
+                    let target = self.jump_target(pc, w).unwrap() as usize;
+                    flow(&mut state, &mut work, target, out)
+                }
+                JMPF | JMPF_W => {
+                    let target = self.jump_target(pc, w).unwrap() as usize;
+                    flow(&mut state, &mut work, target, out);
+                    flow(&mut state, &mut work, next, out);
+                }
+                _ => flow(&mut state, &mut work, next, out),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asm,
+        bc::LineTable,
+        vm::{ErrorKind, Value, Vm},
+    };
+
+    fn verify(src: &str) -> Result<(), String> {
+        fn noop<'vm>(_: &mut Vm<'vm>, _: &[Value<'vm>]) -> Result<Value<'vm>, ErrorKind> {
+            Ok(Value::False)
+        }
+        let builtins: &[(&str, crate::vm::BuiltinFn)] = &[("noop", noop)];
+        asm::assemble(src, builtins)
+            .expect("Failed to assemble")
+            .into_vm()
+            .verify()
+            .map_err(|e| e.msg)
+    }
+
+    #[test]
+    fn valid() {
+        verify(
+            ".globals
+    true
+.functions
+    square 1
+.code
+    loadi r1, 7
+    loadg r2, g0
+    jmpf r2, skip
+    call square, r1, 1
+    mov r3, r1
+skip:
+    sys noop, r1, 1
+    ret 1
+square:
+    mul r0, r0, r0
+    ret 1
+",
+        )
+        .expect("should verify");
+    }
+
+    #[test]
+    fn register_out_of_range() {
+        assert_eq!(
+            verify(".code\n    loadi r32, 1"),
+            Err("register r32 in operand a is out of range".into())
+        );
+        assert!(verify(".code\n    loadi r0, 1\n    sys noop, r30, 3").is_err());
+    }
+
+    #[test]
+    fn unknown_references() {
+        assert_eq!(
+            verify(".code\n    loadg r0, g2"),
+            Err("global g2 does not exist, there are 0".into())
+        );
+        assert_eq!(
+            verify(".code\n    call f3, r0, 0"),
+            Err("function 3 does not exist, there are 0".into())
+        );
+    }
+
+    #[test]
+    fn call_arguments() {
+        assert_eq!(
+            verify(
+                ".functions
+    f 2
+.code
+    loadi r0, 1
+    call f, r0, 1
+    ret 1
+f:
+    add r0, r0, r1
+    ret 1
+"
+            ),
+            Err("function `f` takes 2 arguments, got 1".into())
+        );
+    }
+
+    #[test]
+    fn calls_clobber_the_callee_window() {
+        let src = |read: u8| {
+            format!(
+                ".functions
+    f 1
+.code
+    loadi r1, 1
+    loadi r2, 2
+    loadi r5, 42
+    call f, r2, 1
+    mov r6, r{read}
+    ret 1
+f:
+    loadi r3, 7
+    ret 1
+"
+            )
+        };
+        // below the arguments and the result survive
+        verify(&src(1)).expect("should verify");
+        verify(&src(2)).expect("should verify");
+        // r5 is the callees r3
+        assert_eq!(
+            verify(&src(5)),
+            Err("register r5 may be read before it was written".into())
+        );
+    }
+
+    #[test]
+    fn jump_targets() {
+        let mut vm = Vm::new(&[], vec![], vec![], LineTable::default());
+        // jmp +5 into nowhere
+        vm.bytecode.code = vec![crate::bc::opcode::JMP as u32 | 5 << 8];
+        assert!(vm.verify().is_err());
+        // jmp +1 into the middle of a let
+        vm.bytecode.code = vec![
+            crate::bc::opcode::JMP as u32 | 2 << 8,
+            crate::bc::opcode::LET as u32,
+            0,
+            0,
+        ];
+        assert!(vm.verify().is_err());
+        // truncated let
+        vm.bytecode.code = vec![crate::bc::opcode::LET as u32, 0];
+        assert!(vm.verify().is_err());
+    }
+
+    #[test]
+    fn uninitialised_registers() {
+        assert_eq!(
+            verify(".code\n    add r0, r1, r2"),
+            Err("register r1 may be read before it was written".into())
+        );
+        // only written on one of two paths
+        assert_eq!(
+            verify(
+                ".code
+    loadi r0, 0
+    jmpf r0, join
+    loadi r1, 1
+join:
+    mov r2, r1
+"
+            ),
+            Err("register r1 may be read before it was written".into())
+        );
+        // loops converge
+        verify(
+            ".code
+    loadi r0, 0
+    loadi r1, 1
+head:
+    add r0, r0, r1
+    jmp head
+",
+        )
+        .expect("should verify");
+    }
+}